@@ -0,0 +1,31 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use hack_assembler::*;
+use std::fs;
+
+const PONG: &str = "../06-assembler/pong/Pong.asm";
+
+fn bench_pong(c: &mut Criterion) {
+    let input = fs::read_to_string(PONG).unwrap();
+    let mut group = c.benchmark_group("pong");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_function("run", |b| {
+        b.iter(|| Assembler::run(black_box(&input)).unwrap())
+    });
+
+    let words = Assembler::run_with_options(&input, None, &AssembleOptions::default())
+        .unwrap()
+        .words;
+    let mut buf = Vec::new();
+    group.bench_function("write_hack", |b| {
+        b.iter(|| {
+            buf.clear();
+            OutputFormat::Hack
+                .write_to(black_box(&words), &mut buf)
+                .unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_pong);
+criterion_main!(benches);
@@ -0,0 +1,481 @@
+use crate::parser::command::*;
+use crate::parser::common::*;
+use crate::sysmbol_table::{is_local, qualify, scopes, ROM_SIZE};
+
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// グローバルラベルから次のグローバルラベルまでのROMの区間
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    /// 最初のグローバルラベルより前は空文字列
+    pub label: String,
+    pub start: usize,
+    pub size: usize,
+}
+
+/// 基本ブロック [start, end)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    /// 先頭に置かれたラベル (ローカルラベルは修飾した名前)
+    pub labels: Vec<String>,
+    /// 後続のブロックの番号
+    pub succs: Vec<usize>,
+    /// ジャンプ先が静的に分からない (`A=M; 0;JMP`など)
+    pub indirect: bool,
+    /// ループの一部か
+    pub in_loop: bool,
+}
+
+/// ループのない経路の終わり方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionExit {
+    /// プログラムの終わり (ROMの外へのジャンプを含む)
+    End,
+    /// ループに入る (ブロックの番号)
+    Loop(usize),
+    /// ジャンプ先が分からない
+    Indirect,
+}
+
+/// ラベルから始まる、ループのない経路の最悪の命令数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub label: String,
+    pub start: usize,
+    pub worst_case: usize,
+    pub exit: RegionExit,
+}
+
+/// ROMの使用量、ラベルごとの大きさ、制御フローグラフと最悪の命令数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    pub rom_words: usize,
+    pub sections: Vec<Section>,
+    pub blocks: Vec<BasicBlock>,
+    pub regions: Vec<Region>,
+}
+
+/// 構文解析したコマンド列を解析する
+/// ROMに収まらないプログラムも、アドレスを数え続けて解析する
+pub fn analyze(commands: &[Command]) -> Analysis {
+    let scopes = scopes(commands);
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    let mut label_at: HashMap<usize, Vec<String>> = HashMap::new();
+    let mut sections: Vec<Section> = vec![];
+    // A/C命令とそのスコープ
+    let mut instructions = vec![];
+    for (cmd, scope) in commands.iter().zip(&scopes) {
+        match &cmd.value {
+            CommandKind::L(LabelCommand { label }) => {
+                let address = instructions.len();
+                let name = qualify(label, scope);
+                labels.entry(name.clone()).or_insert(address);
+                label_at.entry(address).or_default().push(name);
+                if !is_local(label) {
+                    sections.push(Section {
+                        label: label.clone(),
+                        start: address,
+                        size: 0,
+                    });
+                }
+            }
+            CommandKind::A(_) | CommandKind::C(_) => instructions.push((&cmd.value, *scope)),
+            CommandKind::D(_) => (),
+        }
+    }
+    let rom_words = instructions.len();
+
+    if sections.first().is_none_or(|s| s.start > 0) && rom_words > 0 {
+        let top = Section {
+            label: String::new(),
+            start: 0,
+            size: 0,
+        };
+        sections.insert(0, top);
+    }
+    for i in 0..sections.len() {
+        let end = sections.get(i + 1).map_or(rom_words, |s| s.start);
+        sections[i].size = end - sections[i].start;
+    }
+
+    // 各ジャンプ命令のジャンプ先 (直前の`@`から分かるもの)
+    let mut targets: HashMap<usize, Option<usize>> = HashMap::new();
+    for (i, (cmd, _)) in instructions.iter().enumerate() {
+        if let CommandKind::C(CompCommand { jump: Some(_), .. }) = cmd {
+            let target = match i.checked_sub(1).map(|j| &instructions[j]) {
+                Some((CommandKind::A(AddrCommand { value }), scope)) => {
+                    eval_target(value, scope, &labels)
+                }
+                _ => None,
+            };
+            targets.insert(i, target);
+        }
+    }
+
+    let mut leaders: BTreeSet<usize> = BTreeSet::new();
+    if rom_words > 0 {
+        leaders.insert(0);
+    }
+    leaders.extend(label_at.keys().copied().filter(|a| *a < rom_words));
+    for (i, target) in targets.iter() {
+        leaders.insert(i + 1);
+        leaders.extend(target.filter(|t| *t < rom_words));
+    }
+    let starts: Vec<usize> = leaders.into_iter().filter(|a| *a < rom_words).collect();
+    let block_of = |address: usize| starts.binary_search(&address).ok();
+
+    let mut blocks: Vec<BasicBlock> = starts
+        .iter()
+        .enumerate()
+        .map(|(b, &start)| {
+            let end = starts.get(b + 1).copied().unwrap_or(rom_words);
+            let last = end - 1;
+            let mut succs = vec![];
+            let mut indirect = false;
+            let mut fallthrough = true;
+            if let Some(target) = targets.get(&last) {
+                // ジャンプ先の`@`が前のブロックにあれば分からないものとする
+                match target.filter(|_| last > start) {
+                    Some(t) => succs.extend(block_of(t)),
+                    None => indirect = true,
+                }
+                if let (CommandKind::C(CompCommand { jump, .. }), _) = instructions[last] {
+                    fallthrough = *jump != Some(JumpKind::Jmp);
+                }
+            }
+            if fallthrough && end < rom_words && !succs.contains(&(b + 1)) {
+                succs.push(b + 1);
+            }
+            BasicBlock {
+                start,
+                end,
+                labels: label_at.get(&start).cloned().unwrap_or_default(),
+                succs,
+                indirect,
+                in_loop: false,
+            }
+        })
+        .collect();
+
+    let components = strongly_connected(&blocks);
+    for component in components.iter() {
+        let cyclic = component.len() > 1 || blocks[component[0]].succs.contains(&component[0]);
+        for &b in component.iter() {
+            blocks[b].in_loop = cyclic;
+        }
+    }
+
+    // 後続から順に、ループに入るまでの最悪の命令数を求める
+    let mut costs: Vec<(usize, RegionExit)> = vec![(0, RegionExit::End); blocks.len()];
+    for component in components.iter().rev() {
+        for &b in component.iter() {
+            let block = &blocks[b];
+            if block.in_loop {
+                costs[b] = (0, RegionExit::Loop(b));
+                continue;
+            }
+            let init = if block.indirect {
+                (0, RegionExit::Indirect)
+            } else {
+                (0, RegionExit::End)
+            };
+            let (cost, exit) = block
+                .succs
+                .iter()
+                .map(|&s| costs[s])
+                .fold(None, |max: Option<(usize, RegionExit)>, c| match max {
+                    Some(max) if max.0 >= c.0 => Some(max),
+                    _ => Some(c),
+                })
+                .unwrap_or(init);
+            costs[b] = (cost + block.end - block.start, exit);
+        }
+    }
+
+    let regions = sections
+        .iter()
+        .filter_map(|s| {
+            let (worst_case, exit) = costs[block_of(s.start)?];
+            Some(Region {
+                label: s.label.clone(),
+                start: s.start,
+                worst_case,
+                exit,
+            })
+        })
+        .collect();
+
+    Analysis {
+        rom_words,
+        sections,
+        blocks,
+        regions,
+    }
+}
+
+/// ジャンプ先のROMアドレス (ラベル、数値、ラベル±数値)
+fn eval_target(expr: &Expr, scope: &str, labels: &HashMap<String, usize>) -> Option<usize> {
+    match expr {
+        Expr::Num(n) => usize::try_from(*n).ok(),
+        Expr::Symbol(s) => labels.get(&qualify(s, scope)).copied(),
+        Expr::BinOp { op, l, r } => match (op, r.as_ref()) {
+            (ExprOpKind::Add, Expr::Num(n)) => {
+                eval_target(l, scope, labels)?.checked_add(usize::try_from(*n).ok()?)
+            }
+            (ExprOpKind::Sub, Expr::Num(n)) => {
+                eval_target(l, scope, labels)?.checked_sub(usize::try_from(*n).ok()?)
+            }
+            _ => None,
+        },
+    }
+}
+
+/// 強連結成分 (Kosarajuの方法)
+/// 成分は、元のグラフでの位相順 (先行するものが先) に並ぶ
+fn strongly_connected(blocks: &[BasicBlock]) -> Vec<Vec<usize>> {
+    let n = blocks.len();
+    let mut visited = vec![false; n];
+    let mut order = Vec::with_capacity(n);
+    for root in 0..n {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        let mut stack = vec![(root, 0)];
+        while let Some((v, i)) = stack.pop() {
+            match blocks[v].succs.get(i) {
+                Some(&w) => {
+                    stack.push((v, i + 1));
+                    if !visited[w] {
+                        visited[w] = true;
+                        stack.push((w, 0));
+                    }
+                }
+                None => order.push(v),
+            }
+        }
+    }
+
+    let mut preds = vec![vec![]; n];
+    for (v, block) in blocks.iter().enumerate() {
+        for &w in block.succs.iter() {
+            preds[w].push(v);
+        }
+    }
+    let mut assigned = vec![false; n];
+    let mut components = vec![];
+    for &root in order.iter().rev() {
+        if assigned[root] {
+            continue;
+        }
+        assigned[root] = true;
+        let mut component = vec![];
+        let mut stack = vec![root];
+        while let Some(v) = stack.pop() {
+            component.push(v);
+            for &w in preds[v].iter() {
+                if !assigned[w] {
+                    assigned[w] = true;
+                    stack.push(w);
+                }
+            }
+        }
+        components.push(component);
+    }
+    components
+}
+
+fn label_name(label: &str) -> &str {
+    if label.is_empty() {
+        "(top)"
+    } else {
+        label
+    }
+}
+
+impl Analysis {
+    /// 基本ブロックと後続を書き出す
+    pub fn render_cfg(&self) -> String {
+        let mut buf = String::from("Basic blocks:\n");
+        for block in self.blocks.iter() {
+            let succs: Vec<String> = block
+                .succs
+                .iter()
+                .map(|&s| format!("{:04x}", self.blocks[s].start))
+                .collect();
+            let succs = match (succs.is_empty(), block.indirect) {
+                (true, true) => "indirect".to_owned(),
+                (true, false) => "end".to_owned(),
+                (false, true) => format!("{}, indirect", succs.join(", ")),
+                (false, false) => succs.join(", "),
+            };
+            buf.push_str(&format!(
+                "  {:04x}..{:04x}  {:5}  -> {}{}{}\n",
+                block.start,
+                block.end,
+                block.end - block.start,
+                succs,
+                if block.in_loop { "  [loop]" } else { "" },
+                if block.labels.is_empty() {
+                    String::new()
+                } else {
+                    format!("  ({})", block.labels.join(", "))
+                },
+            ));
+        }
+        buf
+    }
+
+    fn exit_name(&self, exit: RegionExit) -> String {
+        match exit {
+            RegionExit::End => "end of program".to_owned(),
+            RegionExit::Indirect => "indirect jump".to_owned(),
+            RegionExit::Loop(b) => {
+                let block = &self.blocks[b];
+                match block.labels.first() {
+                    Some(label) => format!("loop at {:04x} ({})", block.start, label),
+                    None => format!("loop at {:04x}", block.start),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ROM: {} / {} words ({:.1}%)",
+            self.rom_words,
+            ROM_SIZE,
+            self.rom_words as f64 * 100.0 / ROM_SIZE as f64
+        )?;
+        if self.rom_words > ROM_SIZE {
+            write!(f, ", exceeds ROM by {} words", self.rom_words - ROM_SIZE)?;
+        }
+        writeln!(f)?;
+
+        writeln!(f, "\nSections:\nADDR   SIZE  LABEL")?;
+        for s in self.sections.iter() {
+            writeln!(f, "{:04x}  {:5}  {}", s.start, s.size, label_name(&s.label))?;
+        }
+
+        writeln!(
+            f,
+            "\nWorst-case instructions (loop-free paths):\nADDR  COUNT  LABEL"
+        )?;
+        for r in self.regions.iter() {
+            writeln!(
+                f,
+                "{:04x}  {:5}  {} -> {}",
+                r.start,
+                r.worst_case,
+                label_name(&r.label),
+                self.exit_name(r.exit)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{lexer, parse_all};
+
+    fn analyze_str(input: &str) -> Analysis {
+        let (tokens, _) = lexer::lex_all(input);
+        let (commands, errors) = parse_all(tokens, input);
+        assert!(errors.is_empty());
+        analyze(&commands)
+    }
+
+    #[test]
+    fn test_analyze() {
+        let input = r###"
+   @Sys.init
+   0;JMP
+(Sys.init)
+   @i
+   M=1
+   @.skip
+   D;JEQ
+   @i
+   M=M+1
+(.skip)
+   @Main.main
+   0;JMP
+(Main.main)
+(.loop)
+   @i
+   MD=M-1
+   @.loop
+   D;JGT
+   @R14
+   A=M
+   0;JMP
+"###;
+        let actual = analyze_str(input);
+        assert_eq!(actual.rom_words, 17);
+        let sections: Vec<(&str, usize, usize)> = actual
+            .sections
+            .iter()
+            .map(|s| (s.label.as_str(), s.start, s.size))
+            .collect();
+        assert_eq!(
+            sections,
+            vec![("", 0, 2), ("Sys.init", 2, 8), ("Main.main", 10, 7)]
+        );
+
+        let blocks: Vec<(usize, usize, &[usize], bool)> = actual
+            .blocks
+            .iter()
+            .map(|b| (b.start, b.end, b.succs.as_slice(), b.in_loop))
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                (0, 2, &[1][..], false),
+                (2, 6, &[3, 2][..], false),
+                (6, 8, &[3][..], false),
+                (8, 10, &[4][..], false),
+                (10, 14, &[4, 5][..], true),
+                (14, 17, &[][..], false),
+            ]
+        );
+        assert!(actual.blocks[5].indirect);
+
+        let regions: Vec<(&str, usize, RegionExit)> = actual
+            .regions
+            .iter()
+            .map(|r| (r.label.as_str(), r.worst_case, r.exit))
+            .collect();
+        assert_eq!(
+            regions,
+            vec![
+                ("", 10, RegionExit::Loop(4)),
+                ("Sys.init", 8, RegionExit::Loop(4)),
+                ("Main.main", 0, RegionExit::Loop(4)),
+            ]
+        );
+        assert_eq!(
+            actual.to_string().lines().last(),
+            Some("000a      0  Main.main -> loop at 000a (Main.main)")
+        );
+    }
+
+    #[test]
+    fn test_analyze_rom_overflow() {
+        let input = "@0\n".repeat(ROM_SIZE + 2);
+        let actual = analyze_str(&input);
+        assert_eq!(actual.rom_words, ROM_SIZE + 2);
+        assert_eq!(actual.regions[0].worst_case, ROM_SIZE + 2);
+        assert!(actual
+            .to_string()
+            .starts_with("ROM: 32770 / 32768 words (100.0%), exceeds ROM by 2 words\n"));
+    }
+}
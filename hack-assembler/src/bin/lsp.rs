@@ -0,0 +1,359 @@
+use anyhow::Result;
+use hack_assembler::*;
+// lsp_typesの`SymbolKind`ではなく、こちらを使う
+use hack_assembler::SymbolKind;
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::Notification as _;
+use lsp_types::request::Request as _;
+use lsp_types::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// 標準入出力で話すHackアセンブリのLanguage Server
+fn main() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        rename_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec!["@".to_owned(), "=".to_owned(), ";".to_owned()]),
+            ..CompletionOptions::default()
+        }),
+        ..ServerCapabilities::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+    Server::default().run(&connection)?;
+    // 送信側を閉じないと書き込みスレッドが終わらない
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct Server {
+    /// 開いているファイルの内容
+    docs: HashMap<Url, String>,
+}
+
+impl Server {
+    fn run(&mut self, connection: &Connection) -> Result<()> {
+        for msg in &connection.receiver {
+            match msg {
+                Message::Request(req) => {
+                    if connection.handle_shutdown(&req)? {
+                        return Ok(());
+                    }
+                    let resp = self.request(req);
+                    connection.sender.send(Message::Response(resp))?;
+                }
+                Message::Notification(not) => {
+                    if let Some(uri) = self.notification(not)? {
+                        let params = PublishDiagnosticsParams {
+                            diagnostics: self.diagnostics(&uri),
+                            uri,
+                            version: None,
+                        };
+                        let not = Notification::new(
+                            notification::PublishDiagnostics::METHOD.to_owned(),
+                            params,
+                        );
+                        connection.sender.send(Message::Notification(not))?;
+                    }
+                }
+                Message::Response(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// 内容が変わったファイル
+    fn notification(&mut self, not: Notification) -> Result<Option<Url>> {
+        match not.method.as_str() {
+            notification::DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams = serde_json::from_value(not.params)?;
+                let uri = params.text_document.uri;
+                self.docs.insert(uri.clone(), params.text_document.text);
+                Ok(Some(uri))
+            }
+            notification::DidChangeTextDocument::METHOD => {
+                let params: DidChangeTextDocumentParams = serde_json::from_value(not.params)?;
+                let uri = params.text_document.uri;
+                // 全体を送ってもらう (TextDocumentSyncKind::FULL)
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.docs.insert(uri.clone(), change.text);
+                }
+                Ok(Some(uri))
+            }
+            notification::DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams = serde_json::from_value(not.params)?;
+                self.docs.remove(&params.text_document.uri);
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn request(&self, req: Request) -> Response {
+        let id = req.id.clone();
+        let result = match req.method.as_str() {
+            request::GotoDefinition::METHOD => self.dispatch(req, Self::definition),
+            request::References::METHOD => self.dispatch(req, Self::references),
+            request::Rename::METHOD => self.dispatch(req, Self::rename),
+            request::HoverRequest::METHOD => self.dispatch(req, Self::hover),
+            request::Completion::METHOD => self.dispatch(req, Self::completion),
+            _ => {
+                let message = format!("unsupported request `{}`", req.method);
+                return Response::new_err(id, ErrorCode::MethodNotFound as i32, message);
+            }
+        };
+        match result {
+            Ok(value) => Response {
+                id,
+                result: Some(value),
+                error: None,
+            },
+            Err(e) => Response::new_err(id, ErrorCode::RequestFailed as i32, e.to_string()),
+        }
+    }
+
+    fn dispatch<P, R>(
+        &self,
+        req: Request,
+        f: impl Fn(&Self, P) -> Result<R>,
+    ) -> Result<serde_json::Value>
+    where
+        P: serde::de::DeserializeOwned,
+        R: serde::Serialize,
+    {
+        let params = serde_json::from_value(req.params)?;
+        Ok(serde_json::to_value(f(self, params)?)?)
+    }
+
+    /// ファイルの内容と索引
+    fn open(&self, uri: &Url) -> Option<(&str, SourceIndex)> {
+        let text = self.docs.get(uri)?;
+        let path = uri.to_file_path().ok();
+        Some((text, SourceIndex::new(text, path.as_deref())))
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Result<Option<GotoDefinitionResponse>> {
+        let TextDocumentPositionParams {
+            text_document,
+            position,
+        } = params.text_document_position_params;
+        let uri = text_document.uri;
+        let (text, index) = match self.open(&uri) {
+            Some(doc) => doc,
+            None => return Ok(None),
+        };
+        let loc = index
+            .occurrence_at(offset(text, position))
+            .and_then(|o| index.definition(&o.name));
+        Ok(loc.map(|loc| {
+            GotoDefinitionResponse::Scalar(Location::new(
+                uri.clone(),
+                range(text, loc.start(), loc.end()),
+            ))
+        }))
+    }
+
+    fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        let TextDocumentPositionParams {
+            text_document,
+            position,
+        } = params.text_document_position;
+        let uri = text_document.uri;
+        let (text, index) = match self.open(&uri) {
+            Some(doc) => doc,
+            None => return Ok(None),
+        };
+        let occ = match index.occurrence_at(offset(text, position)) {
+            Some(occ) => occ,
+            None => return Ok(None),
+        };
+        let locations = index
+            .references(&occ.name, params.context.include_declaration)
+            .into_iter()
+            .map(|loc| Location::new(uri.clone(), range(text, loc.start(), loc.end())))
+            .collect();
+        Ok(Some(locations))
+    }
+
+    fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        let RenameParams {
+            text_document_position:
+                TextDocumentPositionParams {
+                    text_document,
+                    position,
+                },
+            new_name,
+            ..
+        } = params;
+        let uri = text_document.uri;
+        let (text, index) = match self.open(&uri) {
+            Some(doc) => doc,
+            None => return Ok(None),
+        };
+        let edits = index
+            .rename(offset(text, position), &new_name)?
+            .into_iter()
+            .map(|loc| TextEdit::new(range(text, loc.start(), loc.end()), new_name.clone()))
+            .collect();
+        let mut changes = HashMap::new();
+        changes.insert(uri, edits);
+        Ok(Some(WorkspaceEdit::new(changes)))
+    }
+
+    fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let TextDocumentPositionParams {
+            text_document,
+            position,
+        } = params.text_document_position_params;
+        let (text, index) = match self.open(&text_document.uri) {
+            Some(doc) => doc,
+            None => return Ok(None),
+        };
+        let occ = match index.occurrence_at(offset(text, position)) {
+            Some(occ) => occ,
+            None => return Ok(None),
+        };
+        Ok(index.hover(&occ.name).map(|value| Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(range(text, occ.loc.start(), occ.loc.end())),
+        }))
+    }
+
+    fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let TextDocumentPositionParams {
+            text_document,
+            position,
+        } = params.text_document_position;
+        let (text, index) = match self.open(&text_document.uri) {
+            Some(doc) => doc,
+            None => return Ok(None),
+        };
+        let items = index
+            .completions(offset(text, position))
+            .into_iter()
+            .map(|c| CompletionItem {
+                kind: Some(match c.kind {
+                    Some(SymbolKind::Label) => CompletionItemKind::REFERENCE,
+                    Some(SymbolKind::Variable) => CompletionItemKind::VARIABLE,
+                    Some(SymbolKind::Constant) | Some(SymbolKind::Predefined) => {
+                        CompletionItemKind::CONSTANT
+                    }
+                    Some(SymbolKind::Macro) => CompletionItemKind::FUNCTION,
+                    None => CompletionItemKind::KEYWORD,
+                }),
+                detail: Some(c.detail),
+                label: c.label,
+                ..CompletionItem::default()
+            })
+            .collect();
+        Ok(Some(CompletionResponse::Array(items)))
+    }
+
+    /// アセンブルのエラーとlintの警告
+    fn diagnostics(&self, uri: &Url) -> Vec<Diagnostic> {
+        let text = match self.docs.get(uri) {
+            Some(text) => text,
+            None => return vec![],
+        };
+        let path = uri.to_file_path().ok();
+        let opts = AssembleOptions {
+            max_errors: 0,
+            ..AssembleOptions::default()
+        };
+
+        let mut diagnostics = vec![];
+        if let Err(errors) = Assembler::run_with_options(text, path.as_deref(), &opts) {
+            for e in errors.errors.iter() {
+                let message = e.kind.message();
+                diagnostics.push(diagnostic(&e.snippet, &e.path, &path, message));
+            }
+        }
+        if let Ok(reports) = Assembler::lint(text, path.as_deref(), &opts, &LintOptions::default())
+        {
+            for r in reports.iter() {
+                let mut d = diagnostic(&r.snippet, &r.path, &path, r.warning.to_string());
+                d.severity = Some(DiagnosticSeverity::WARNING);
+                d.code = Some(NumberOrString::String(r.warning.check().to_string()));
+                diagnostics.push(d);
+            }
+        }
+        diagnostics
+    }
+}
+
+/// エラーの抜粋から作る
+/// `.include`した別のファイルのエラーは先頭行に付ける
+fn diagnostic(
+    snippet: &Snippet,
+    path: &Option<PathBuf>,
+    doc: &Option<PathBuf>,
+    message: String,
+) -> Diagnostic {
+    let (range, message) = if path == doc || path.is_none() {
+        let line = (snippet.pos.line - 1) as u32;
+        let start = utf16_len(snippet.line_text.chars().take(snippet.pos.col - 1));
+        let end = start
+            + utf16_len(
+                snippet
+                    .line_text
+                    .chars()
+                    .skip(snippet.pos.col - 1)
+                    .take(snippet.width),
+            );
+        let range = Range::new(Position::new(line, start), Position::new(line, end));
+        (range, message)
+    } else {
+        let path = path.as_ref().unwrap();
+        let message = format!("{}:{}: {}", path.display(), snippet.pos, message);
+        (Range::default(), message)
+    };
+    Diagnostic {
+        range,
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some("hackasm".to_owned()),
+        message,
+        ..Diagnostic::default()
+    }
+}
+
+fn utf16_len(chars: impl Iterator<Item = char>) -> u32 {
+    chars.map(|c| c.len_utf16() as u32).sum()
+}
+
+/// LSPの位置 (行とUTF-16での列) をバイト位置に直す
+fn offset(text: &str, pos: Position) -> usize {
+    let line_start = text
+        .split_inclusive('\n')
+        .take(pos.line as usize)
+        .map(str::len)
+        .sum::<usize>();
+    let mut col = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if col >= pos.character || c == '\n' {
+            return line_start + i;
+        }
+        col += c.len_utf16() as u32;
+    }
+    text.len()
+}
+
+fn position(text: &str, offset: usize) -> Position {
+    let before = &text[..offset];
+    let line = before.matches('\n').count() as u32;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Position::new(line, utf16_len(text[line_start..offset].chars()))
+}
+
+fn range(text: &str, start: usize, end: usize) -> Range {
+    Range::new(position(text, start), position(text, end))
+}
@@ -2,42 +2,589 @@ use anyhow::{anyhow, Context, Result};
 use clap::Clap;
 use hack_assembler::*;
 use std::fs::{self, File};
-use std::io::{BufWriter, Write};
+use std::io::{self, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 
 #[derive(Clap, Debug)]
 #[clap(name = env!("CARGO_BIN_NAME"), version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"))]
 struct Opts {
+    #[clap(subcommand)]
+    subcmd: Option<SubCommand>,
+    /// .asmファイル、ディレクトリ (その下の.asmをすべて)、または`-` (標準入力)
+    #[clap(name = ".asm FILE")]
+    asm_path: Option<PathBuf>,
+    /// 出力先 (`-`なら標準出力、ディレクトリを渡したときは出力先のディレクトリ)
+    #[clap(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+    /// 書き出さずに、アセンブルできるかだけを確かめる
+    #[clap(long = "check", conflicts_with_all = &["output", "listing", "map", "expect", "compile"])]
+    check: bool,
+    /// 書き出さずに、出力を.hackファイルと比べる
+    #[clap(long = "expect", conflicts_with_all = &["output", "listing", "map", "compile"])]
+    expect: Option<PathBuf>,
+    /// 表示するエラーの上限 (0なら無制限)
+    #[clap(long = "max-errors", default_value = "20")]
+    max_errors: usize,
+    /// リスティング (.lst) も出力する (`-O`とは併用できない)
+    #[clap(short = 'l', long = "listing", conflicts_with = "optimize")]
+    listing: bool,
+    /// 変数のメモリマップ (.map) も出力する
+    #[clap(long = "map")]
+    map: bool,
+    /// 出力形式 (hack, bin, hex, ihex, logisim)
+    #[clap(short = 'f', long = "format", default_value = "hack")]
+    format: OutputFormat,
+    /// 冗長な命令を削ってからアセンブルする
+    #[clap(short = 'O', long = "optimize")]
+    optimize: bool,
+    /// 再配置可能なオブジェクト (.hobj) を出力する
+    #[clap(short = 'c', long = "compile", conflicts_with_all = &["listing", "map"])]
+    compile: bool,
+    /// シフト命令 (`D<<`, `A>>`, `M<<` など) を持つ拡張命令セットでアセンブルする
+    #[clap(long = "extended-isa")]
+    extended_isa: bool,
+    #[clap(flatten)]
+    layout: LayoutOpts,
+}
+
+/// 変数のRAM配置 (コマンドラインの指定はレイアウトファイルに追加・上書きする)
+#[derive(Clap, Debug)]
+struct LayoutOpts {
+    /// 変数の配置を書いたファイル (`base`, `reserve`, `fixed`の行)
+    #[clap(long = "layout")]
+    layout: Option<PathBuf>,
+    /// 変数の割り当て開始アドレス (既定値は16)
+    #[clap(long = "var-base", parse(try_from_str = parse_address))]
+    var_base: Option<u16>,
+    /// 変数を置かない区間 (`START..END`または`START..=END`)
+    #[clap(long = "reserve", number_of_values = 1)]
+    reserve: Vec<RamRange>,
+    /// アドレスを固定するシンボル (`NAME=ADDRESS`)
+    #[clap(long = "fixed", number_of_values = 1)]
+    fixed: Vec<FixedSymbol>,
+}
+
+impl LayoutOpts {
+    fn layout(&self) -> Result<RamLayout> {
+        let mut layout = match &self.layout {
+            Some(path) => fs::read_to_string(path)?
+                .parse::<RamLayout>()
+                .with_context(|| format!("failed to read layout {:?}", path))?,
+            None => RamLayout::default(),
+        };
+        if let Some(base) = self.var_base {
+            layout.variable_base = base;
+        }
+        layout.reserved.extend(self.reserve.iter().copied());
+        layout.fixed.extend(self.fixed.iter().cloned());
+        layout.validate().map_err(|errors| {
+            let messages: Vec<String> = errors
+                .iter()
+                .map(|e| format!("LayoutError: {}", e))
+                .collect();
+            anyhow!("{}", messages.join("\n"))
+        })?;
+        Ok(layout)
+    }
+}
+
+#[derive(Clap, Debug)]
+enum SubCommand {
+    /// .hackファイルを.asmに逆アセンブルする
+    Disasm(DisasmOpts),
+    /// オブジェクト (.hobj) をリンクする
+    Link(LinkOpts),
+    /// アセンブラが受け付ける誤りやすい書き方を警告する
+    Lint(LintOpts),
+    /// .asmファイルを一定の書式に整形する
+    Fmt(FmtOpts),
+    /// ROMの使用量、ラベルごとの大きさ、ループのない経路の最悪の命令数を表示する
+    Analyze(AnalyzeOpts),
+}
+
+#[derive(Clap, Debug)]
+struct AnalyzeOpts {
+    #[clap(name = ".asm FILE")]
+    asm_path: PathBuf,
+    /// 基本ブロックと後続 (制御フローグラフ) も表示する
+    #[clap(long = "cfg")]
+    cfg: bool,
+    /// 冗長な命令を削ってから解析する
+    #[clap(short = 'O', long = "optimize")]
+    optimize: bool,
+    /// シフト命令を持つ拡張命令セットを受け付ける
+    #[clap(long = "extended-isa")]
+    extended_isa: bool,
+}
+
+#[derive(Clap, Debug)]
+struct FmtOpts {
+    #[clap(name = ".asm FILE", required = true)]
+    asm_paths: Vec<PathBuf>,
+    /// 書き換えずに、整形済みでなければ失敗する
+    #[clap(long = "check")]
+    check: bool,
+    /// シフト命令を持つ拡張命令セットを受け付ける
+    #[clap(long = "extended-isa")]
+    extended_isa: bool,
+}
+
+#[derive(Clap, Debug)]
+struct LintOpts {
     #[clap(name = ".asm FILE")]
     asm_path: PathBuf,
+    /// 行う検査 (省略時はすべて)
+    #[clap(short = 'W', long = "warn", number_of_values = 1)]
+    warn: Vec<LintCheck>,
+    /// 行わない検査
+    #[clap(short = 'A', long = "allow", number_of_values = 1)]
+    allow: Vec<LintCheck>,
+    /// シフト命令を持つ拡張命令セットを受け付ける
+    #[clap(long = "extended-isa")]
+    extended_isa: bool,
 }
 
-fn ensure_asm_file(path: &Path) -> Result<()> {
+#[derive(Clap, Debug)]
+struct DisasmOpts {
+    #[clap(name = ".hack FILE")]
+    hack_path: PathBuf,
+    /// 出力先 (省略時は標準出力)
+    #[clap(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Clap, Debug)]
+struct LinkOpts {
+    /// この順にROMに並べる
+    #[clap(name = ".hobj FILE", required = true)]
+    objects: Vec<PathBuf>,
+    /// 出力先 (省略時は最初のオブジェクトの拡張子を置換)
+    #[clap(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+    /// 出力形式 (hack, bin, hex, ihex, logisim)
+    #[clap(short = 'f', long = "format", default_value = "hack")]
+    format: OutputFormat,
+    #[clap(flatten)]
+    layout: LayoutOpts,
+}
+
+/// オブジェクトファイルの拡張子
+const OBJECT_EXT: &str = "hobj";
+
+fn ensure_ext(path: &Path, expect: &str) -> Result<()> {
     let ext = path
         .extension()
         .with_context(|| format!("failed to get file extention\nfile path: {:?}", path))?;
-    if ext == "asm" {
+    if ext == expect {
         Ok(())
     } else {
-        Err(anyhow!("{:?} is not .asm file", path))
+        Err(anyhow!("{:?} is not .{} file", path, expect))
     }
 }
 
-fn main() -> Result<()> {
-    let opts = Opts::parse();
-    let asm_path = opts.asm_path;
-    let code = fs::read_to_string(&asm_path)?;
+/// `-`は標準入力・標準出力
+const STDIO: &str = "-";
+
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new(STDIO)
+}
+
+/// 出力しない場合の扱い
+#[derive(Debug)]
+enum Mode {
+    Write,
+    Check,
+    Expect(PathBuf),
+}
+
+fn read_source(asm_path: &Path) -> Result<String> {
+    if is_stdio(asm_path) {
+        let mut code = String::new();
+        io::stdin().read_to_string(&mut code)?;
+        return Ok(code);
+    }
+    ensure_ext(asm_path, "asm")?;
+    Ok(fs::read_to_string(asm_path)?)
+}
+
+/// 出力全体を文字列にせず、`write`で直接書き出す
+fn write_output(
+    out_path: &Path,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> Result<()> {
+    if is_stdio(out_path) {
+        let stdout = io::stdout();
+        let mut writer = BufWriter::new(stdout.lock());
+        write(&mut writer)?;
+        writer.flush()?;
+    } else {
+        let mut writer = BufWriter::new(File::create(out_path)?);
+        write(&mut writer)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// 出力先 (省略時は入力の拡張子を置換、標準入力なら標準出力)
+fn output_path(asm_path: &Path, output: Option<&Path>, ext: &str) -> PathBuf {
+    match output {
+        Some(path) => path.to_path_buf(),
+        None if is_stdio(asm_path) => PathBuf::from(STDIO),
+        None => asm_path.with_extension(ext),
+    }
+}
+
+/// 標準出力に機械語を書くときは、メッセージを標準エラー出力に回す
+fn status(to_stdout: bool, message: String) {
+    if to_stdout {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+fn assemble(
+    asm_path: &Path,
+    output: Option<&Path>,
+    opts: &AssembleOptions,
+    format: OutputFormat,
+    mode: &Mode,
+) -> Result<()> {
+    let code = read_source(asm_path)?;
+    let src_path = Some(asm_path).filter(|p| !is_stdio(p));
+    let out_path = output_path(asm_path, output, format.extension());
+    if opts.listing && is_stdio(&out_path) {
+        return Err(anyhow!("--listing needs an output file (not stdout)"));
+    }
+    if opts.memory_map && is_stdio(&out_path) {
+        return Err(anyhow!("--map needs an output file (not stdout)"));
+    }
+
+    let output = Assembler::run_with_options(&code, src_path, opts)?;
+    match mode {
+        Mode::Write => (),
+        Mode::Check => {
+            println!("OK: {:?} ({} words)", asm_path, output.words.len());
+            return Ok(());
+        }
+        Mode::Expect(hack_path) => return expect(&output.words, hack_path),
+    }
+
+    write_output(&out_path, |w| format.write_to(&output.words, w))?;
+    let to_stdout = is_stdio(&out_path);
+    status(
+        to_stdout,
+        format!("Success: assembled {:?} to {:?}", &asm_path, &out_path),
+    );
+    if output.optimize_skipped {
+        status(
+            to_stdout,
+            "Optimized: skipped because the program jumps to numeric or computed ROM addresses"
+                .to_string(),
+        );
+    } else if opts.optimize {
+        status(
+            to_stdout,
+            format!(
+                "Optimized: removed {} instruction(s) ({} words)",
+                output.saved,
+                output.words.len()
+            ),
+        );
+    }
 
-    let _ = ensure_asm_file(&asm_path)?;
-    // 拡張子を置換
-    let hack_path = asm_path.with_extension("hack");
+    if let Some(listing) = output.listing {
+        let lst_path = out_path.with_extension("lst");
+        fs::write(&lst_path, listing)?;
+        println!("Success: wrote listing to {:?}", &lst_path);
+    }
+    if let Some(map) = output.memory_map {
+        let map_path = out_path.with_extension("map");
+        fs::write(&map_path, map)?;
+        println!("Success: wrote memory map to {:?}", &map_path);
+    }
+    Ok(())
+}
+
+/// 機械語列を.hackファイルと比べ、最初に食い違うROMアドレスを示す
+fn expect(words: &[u16], hack_path: &Path) -> Result<()> {
+    let text = fs::read_to_string(hack_path)?;
+    let expected = Disassembler::read_words(&text)
+        .with_context(|| format!("failed to read {:?}", hack_path))?;
+
+    let show = |w: Option<&u16>| match w {
+        Some(w) => {
+            let asm = Instruction::decode(*w).map_or_else(|_| "?".to_owned(), |i| i.to_string());
+            format!("{:016b}  {}", w, asm)
+        }
+        None => "(end of program)".to_owned(),
+    };
+    let differ = (0..words.len().max(expected.len())).find(|&i| words.get(i) != expected.get(i));
+    match differ {
+        None => {
+            println!("OK: matches {:?} ({} words)", hack_path, words.len());
+            Ok(())
+        }
+        Some(address) => Err(anyhow!(
+            "output differs from {:?} at ROM[{}]\n  expected: {}\n    actual: {}\n({} words, expected {})",
+            hack_path,
+            address,
+            show(expected.get(address)),
+            show(words.get(address)),
+            words.len(),
+            expected.len()
+        )),
+    }
+}
+
+fn compile(asm_path: &Path, output: Option<&Path>, opts: &AssembleOptions) -> Result<()> {
+    let code = read_source(asm_path)?;
+    let src_path = Some(asm_path).filter(|p| !is_stdio(p));
+    let obj_path = output_path(asm_path, output, OBJECT_EXT);
+
+    let obj = Assembler::run_object(&code, src_path, opts)?;
+    write_output(&obj_path, |w| write!(w, "{}", obj))?;
+    status(
+        is_stdio(&obj_path),
+        format!("Success: compiled {:?} to {:?}", &asm_path, &obj_path),
+    );
+    Ok(())
+}
+
+/// ディレクトリの下の.asmファイル (順序は`read_dir`のまま)
+fn collect_asm_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_asm_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "asm") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// ディレクトリの下の.asmファイルをそれぞれアセンブルする
+/// 失敗したファイルがあっても続け、最後にまとめて報告する
+fn assemble_dir(dir: &Path, opts: &Opts, asm_opts: &AssembleOptions, mode: &Mode) -> Result<()> {
+    if let Mode::Expect(_) = mode {
+        return Err(anyhow!(
+            "--expect needs a single .asm FILE, not a directory"
+        ));
+    }
+    let mut files = vec![];
+    collect_asm_files(dir, &mut files)?;
+    files.sort();
+    if let Some(out_dir) = &opts.output {
+        fs::create_dir_all(out_dir)?;
+    }
+
+    let mut failed = 0;
+    for asm_path in files.iter() {
+        let ext = if opts.compile {
+            OBJECT_EXT
+        } else {
+            opts.format.extension()
+        };
+        // 出力先のディレクトリには、入力ディレクトリからの相対パスを引き継ぐ (同じ名前のファイルを上書きしない)
+        let out_path = match &opts.output {
+            Some(out_dir) => {
+                let relative = asm_path.strip_prefix(dir).unwrap_or(asm_path);
+                let out_path = out_dir.join(relative).with_extension(ext);
+                if let Some(parent) = out_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                Some(out_path)
+            }
+            None => None,
+        };
+        let result = if opts.compile {
+            compile(asm_path, out_path.as_deref(), asm_opts)
+        } else {
+            assemble(asm_path, out_path.as_deref(), asm_opts, opts.format, mode)
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {:?}\n{}\n", asm_path, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} of {} file(s) failed", failed, files.len()));
+    }
+    println!("{} file(s) OK", files.len());
+    Ok(())
+}
+
+fn link(opts: &LinkOpts) -> Result<()> {
+    let mut objects = vec![];
+    for path in opts.objects.iter() {
+        ensure_ext(path, OBJECT_EXT)?;
+        let text = fs::read_to_string(path)?;
+        let obj = text
+            .parse::<Object>()
+            .with_context(|| format!("failed to read object {:?}", path))?;
+        objects.push((path.display().to_string(), obj));
+    }
+
+    let layout = opts.layout.layout()?;
+    let words = hack_assembler::link_with_layout(&objects, &layout).map_err(|errors| {
+        let messages: Vec<String> = errors.iter().map(|e| format!("LinkError: {}", e)).collect();
+        anyhow!(
+            "{}\naborting due to {} error(s)",
+            messages.join("\n"),
+            errors.len()
+        )
+    })?;
+
+    let out_path = match &opts.output {
+        Some(path) => path.clone(),
+        None => opts.objects[0].with_extension(opts.format.extension()),
+    };
+    fs::write(&out_path, opts.format.encode(&words))?;
+    println!(
+        "Success: linked {} object(s) to {:?}",
+        objects.len(),
+        &out_path
+    );
+    Ok(())
+}
+
+/// `--extended-isa`か`extended-isa`フィーチャーで拡張命令セットを有効にする
+fn extended_options(extended_isa: bool) -> AssembleOptions {
+    AssembleOptions {
+        extended: extended_isa || cfg!(feature = "extended-isa"),
+        ..AssembleOptions::default()
+    }
+}
+
+fn lint(opts: &LintOpts) -> Result<()> {
+    ensure_ext(&opts.asm_path, "asm")?;
+    let code = fs::read_to_string(&opts.asm_path)?;
+
+    let mut lint_opts = LintOptions::default();
+    if !opts.warn.is_empty() {
+        lint_opts.checks = opts.warn.iter().copied().collect();
+    }
+    for check in opts.allow.iter() {
+        lint_opts.checks.remove(check);
+    }
+    let reports = Assembler::lint(
+        &code,
+        Some(&opts.asm_path),
+        &extended_options(opts.extended_isa),
+        &lint_opts,
+    )?;
+    for report in reports.iter() {
+        println!("{}\n", report);
+    }
+    println!("{} warning(s)", reports.len());
+    Ok(())
+}
+
+fn format(opts: &FmtOpts) -> Result<()> {
+    let asm_opts = extended_options(opts.extended_isa);
+    let mut unformatted = 0;
+    for path in opts.asm_paths.iter() {
+        ensure_ext(path, "asm")?;
+        let code = fs::read_to_string(path)?;
+        let formatted = Assembler::format(&code, Some(path), &asm_opts)?;
+        if formatted == code {
+            continue;
+        }
+        if opts.check {
+            // 最初に食い違う行を示す
+            let line = code
+                .lines()
+                .zip(formatted.lines())
+                .position(|(a, b)| a != b)
+                .unwrap_or_else(|| code.lines().count().min(formatted.lines().count()));
+            println!("{}:{}: not formatted", path.display(), line + 1);
+            unformatted += 1;
+        } else {
+            fs::write(path, formatted)?;
+            println!("Formatted {:?}", path);
+        }
+    }
+    if unformatted > 0 {
+        return Err(anyhow!("{} file(s) not formatted", unformatted));
+    }
+    Ok(())
+}
+
+fn analyze(opts: &AnalyzeOpts) -> Result<()> {
+    ensure_ext(&opts.asm_path, "asm")?;
+    let code = fs::read_to_string(&opts.asm_path)?;
+    let asm_opts = AssembleOptions {
+        optimize: opts.optimize,
+        ..extended_options(opts.extended_isa)
+    };
+    let analysis = Assembler::analyze(&code, Some(&opts.asm_path), &asm_opts)?;
+    print!("{}", analysis);
+    if opts.cfg {
+        print!("\n{}", analysis.render_cfg());
+    }
+    if analysis.rom_words > ROM_SIZE {
+        return Err(anyhow!(
+            "program does not fit in ROM ({} of {} words)",
+            analysis.rom_words,
+            ROM_SIZE
+        ));
+    }
+    Ok(())
+}
 
-    // 出力先のファイルは上書き
-    let mut writer = BufWriter::new(File::create(&hack_path)?);
-    let code = Assembler::run(&code)?;
-    writer.write_all(&code.as_bytes())?;
+fn disassemble(opts: &DisasmOpts) -> Result<()> {
+    ensure_ext(&opts.hack_path, "hack")?;
+    let code = fs::read_to_string(&opts.hack_path)?;
+    let asm = Disassembler::run(&code)?;
 
-    let hack_path: PathBuf = hack_path.into();
-    println!("Success: assembled {:?} to {:?}", &asm_path, &hack_path);
+    match &opts.output {
+        Some(path) => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            writer.write_all(asm.as_bytes())?;
+            eprintln!("Success: disassembled {:?} to {:?}", &opts.hack_path, path);
+        }
+        None => print!("{}", asm),
+    }
     Ok(())
 }
+
+fn main() -> Result<()> {
+    let opts = Opts::parse();
+    match (&opts.subcmd, &opts.asm_path) {
+        (Some(SubCommand::Disasm(disasm_opts)), _) => disassemble(disasm_opts),
+        (Some(SubCommand::Link(link_opts)), _) => link(link_opts),
+        (Some(SubCommand::Lint(lint_opts)), _) => lint(lint_opts),
+        (Some(SubCommand::Fmt(fmt_opts)), _) => format(fmt_opts),
+        (Some(SubCommand::Analyze(analyze_opts)), _) => analyze(analyze_opts),
+        (None, Some(asm_path)) => {
+            let asm_opts = AssembleOptions {
+                max_errors: opts.max_errors,
+                listing: opts.listing,
+                optimize: opts.optimize,
+                layout: opts.layout.layout()?,
+                memory_map: opts.map,
+                ..extended_options(opts.extended_isa)
+            };
+            let mode = match (&opts.expect, opts.check) {
+                (Some(hack_path), _) => Mode::Expect(hack_path.clone()),
+                (None, true) => Mode::Check,
+                (None, false) => Mode::Write,
+            };
+            if asm_path.is_dir() {
+                assemble_dir(asm_path, &opts, &asm_opts, &mode)
+            } else if opts.compile {
+                compile(asm_path, opts.output.as_deref(), &asm_opts)
+            } else {
+                assemble(
+                    asm_path,
+                    opts.output.as_deref(),
+                    &asm_opts,
+                    opts.format,
+                    &mode,
+                )
+            }
+        }
+        (None, None) => Err(anyhow!("no .asm FILE given (see --help)")),
+    }
+}
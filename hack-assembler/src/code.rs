@@ -1,245 +1,947 @@
-use crate::parser::lexer::{self, LexError};
-use crate::parser::{self, command::*, common::*};
+use crate::analysis::{self, Analysis};
+use crate::diagnostic::Snippet;
+use crate::format::OutputFormat;
+use crate::formatter;
+use crate::instruction::{self, InstructionError, Statement};
+use crate::layout::RamLayout;
+use crate::lint::{self, LintOptions, LintWarningKind};
+use crate::listing;
+use crate::memory_map;
+use crate::object::{self, Object, ObjectError};
+use crate::optimize;
+use crate::parser::lexer::LexError;
+use crate::parser::macros::MacroError;
+use crate::parser::{command::*, common::*};
 
 use crate::parser::ParseError;
+use crate::source::{IncludeError, SourceMap};
 use crate::sysmbol_table::*;
+use crate::types::Address;
 
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CodeErrorKind {
+    #[error("comp `{0}` cannot be encoded")]
+    InvalidComp(String),
+    #[error("`{0}` is a shift instruction of the extended ISA (enable it with `--extended-isa` or the `extended-isa` feature)")]
+    ExtendedOnly(String),
+    #[error("`@{0}` does not fit in 15 bits (0..=32767)")]
+    AddressOverflow(u64),
+    #[error("`@{expr}` evaluates to {value}, which does not fit in 15 bits (0..=32767)")]
+    ExprOutOfRange { expr: String, value: i64 },
+    #[error("`@{0}` overflows or divides by zero during evaluation")]
+    ExprOverflow(String),
+}
+
+pub type CodeError = Annot<CodeErrorKind>;
+
+impl fmt::Display for CodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.loc, self.value)
+    }
+}
+
+impl CodeError {
+    fn invalid_comp(comp: &CompKind, loc: Loc) -> Self {
+        CodeError::new(CodeErrorKind::InvalidComp(comp.to_string()), loc)
+    }
+    fn extended_only(comp: &CompKind, loc: Loc) -> Self {
+        CodeError::new(CodeErrorKind::ExtendedOnly(comp.to_string()), loc)
+    }
+    fn address_overflow(n: u64, loc: Loc) -> Self {
+        CodeError::new(CodeErrorKind::AddressOverflow(n), loc)
+    }
+    fn expr_out_of_range(expr: &Expr, value: i64, loc: Loc) -> Self {
+        let kind = CodeErrorKind::ExprOutOfRange {
+            expr: expr.to_string(),
+            value,
+        };
+        CodeError::new(kind, loc)
+    }
+    fn expr_overflow(expr: &Expr, loc: Loc) -> Self {
+        CodeError::new(CodeErrorKind::ExprOverflow(expr.to_string()), loc)
+    }
+}
+
 /// 各エラーの統合型
 #[derive(Error, Debug)]
-pub enum AssembleError {
+pub enum AssembleErrorKind {
     #[error("SymTableError:\n {0}")]
     SymTable(SymTableError),
     #[error("LexError:\n {0}")]
     Lex(LexError),
     #[error("ParseError:\n {0}")]
     Parse(ParseError),
+    #[error("CodeError:\n {0}")]
+    Code(CodeError),
+    #[error("IncludeError:\n {0}")]
+    Include(IncludeError),
+    #[error("MacroError:\n {0}")]
+    Macro(MacroError),
+    #[error("ObjectError:\n {0}")]
+    Object(ObjectError),
+}
+
+impl AssembleErrorKind {
+    pub fn loc(&self) -> &Loc {
+        use self::AssembleErrorKind::*;
+        match self {
+            SymTable(e) => &e.loc,
+            Lex(e) => &e.loc,
+            Parse(e) => &e.loc,
+            Code(e) => &e.loc,
+            Include(e) => &e.loc,
+            Macro(e) => &e.loc,
+            Object(e) => &e.loc,
+        }
+    }
+    /// 位置情報を除いたメッセージ
+    pub fn message(&self) -> String {
+        use self::AssembleErrorKind::*;
+        match self {
+            SymTable(e) => e.value.to_string(),
+            Lex(e) => e.value.to_string(),
+            Parse(e) => e.value.to_string(),
+            Code(e) => e.value.to_string(),
+            Include(e) => e.value.to_string(),
+            Macro(e) => e.value.to_string(),
+            Object(e) => e.value.to_string(),
+        }
+    }
+    fn name(&self) -> &'static str {
+        use self::AssembleErrorKind::*;
+        match self {
+            SymTable(_) => "SymTableError",
+            Lex(_) => "LexError",
+            Parse(_) => "ParseError",
+            Code(_) => "CodeError",
+            Include(_) => "IncludeError",
+            Macro(_) => "MacroError",
+            Object(_) => "ObjectError",
+        }
+    }
 }
 
-impl From<SymTableError> for AssembleError {
+impl From<SymTableError> for AssembleErrorKind {
     fn from(err: SymTableError) -> Self {
         Self::SymTable(err)
     }
 }
 
-impl From<LexError> for AssembleError {
+impl From<LexError> for AssembleErrorKind {
     fn from(err: LexError) -> Self {
         Self::Lex(err)
     }
 }
 
-impl From<ParseError> for AssembleError {
+impl From<ParseError> for AssembleErrorKind {
     fn from(err: ParseError) -> Self {
         Self::Parse(err)
     }
 }
 
+impl From<CodeError> for AssembleErrorKind {
+    fn from(err: CodeError) -> Self {
+        Self::Code(err)
+    }
+}
+
+impl From<IncludeError> for AssembleErrorKind {
+    fn from(err: IncludeError) -> Self {
+        Self::Include(err)
+    }
+}
+
+impl From<MacroError> for AssembleErrorKind {
+    fn from(err: MacroError) -> Self {
+        Self::Macro(err)
+    }
+}
+
+impl From<ObjectError> for AssembleErrorKind {
+    fn from(err: ObjectError) -> Self {
+        Self::Object(err)
+    }
+}
+
+/// エラーに添える補足 (マクロの呼び出し箇所など)
+#[derive(Debug)]
+pub struct Note {
+    pub message: String,
+    pub path: Option<PathBuf>,
+    pub snippet: Snippet,
+}
+
+/// ファイルパスと行・列の情報を持つエラー
+#[derive(Debug)]
+pub struct AssembleError {
+    pub kind: Box<AssembleErrorKind>,
+    pub path: Option<PathBuf>,
+    pub snippet: Snippet,
+    pub notes: Vec<Note>,
+}
+
+impl AssembleError {
+    pub fn new(kind: AssembleErrorKind, src: &str, path: Option<&Path>) -> Self {
+        let loc = kind.loc();
+        let snippet = Snippet::new(src, loc.start(), loc.end());
+        Self {
+            kind: Box::new(kind),
+            path: path.map(|p| p.to_path_buf()),
+            snippet,
+            notes: vec![],
+        }
+    }
+
+    /// `.include`されたファイルのエラーは、そのファイルの位置で報告する
+    /// マクロを展開した行のエラーは、マクロの定義の位置で報告し、呼び出し箇所を添える
+    fn located(kind: AssembleErrorKind, sources: &SourceMap) -> Self {
+        let (path, snippet, notes) = trace(kind.loc(), sources);
+        Self {
+            kind: Box::new(kind),
+            path,
+            snippet,
+            notes,
+        }
+    }
+}
+
+/// 元のソース上の位置と、マクロの展開をたどる補足
+fn trace(loc: &Loc, sources: &SourceMap) -> (Option<PathBuf>, Snippet, Vec<Note>) {
+    let (loc, expansion) = sources.original(loc);
+    let (path, snippet) = sources.snippet(&loc);
+
+    let mut notes = vec![];
+    let mut next = expansion;
+    while let Some(expansion) = next {
+        let (path, snippet) = sources.snippet(&expansion.call);
+        let message = format!("in expansion of macro `{}`", expansion.name);
+        notes.push(Note {
+            message,
+            path,
+            snippet,
+        });
+        next = sources.parent(expansion);
+    }
+    if let Some(expansion) = expansion {
+        let (path, snippet) = sources.snippet(&expansion.def);
+        let message = format!("macro `{}` defined here", expansion.name);
+        notes.push(Note {
+            message,
+            path,
+            snippet,
+        });
+    }
+    (path, snippet, notes)
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", self.kind.name(), self.kind.message())?;
+        self.snippet.render(f, self.path.as_deref())?;
+        render_notes(f, &self.notes)
+    }
+}
+
+fn render_notes(f: &mut fmt::Formatter, notes: &[Note]) -> fmt::Result {
+    for note in notes.iter() {
+        write!(f, "\n = note: {}\n", note.message)?;
+        note.snippet.render(f, note.path.as_deref())?;
+    }
+    Ok(())
+}
+
+/// ファイルパスと行・列の情報を持つ`lint`の警告
+#[derive(Debug)]
+pub struct LintReport {
+    pub warning: LintWarningKind,
+    pub path: Option<PathBuf>,
+    pub snippet: Snippet,
+    pub notes: Vec<Note>,
+}
+
+impl fmt::Display for LintReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "warning[{}]: {}", self.warning.check(), self.warning)?;
+        self.snippet.render(f, self.path.as_deref())?;
+        render_notes(f, &self.notes)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// 1回のアセンブルで見つかったすべてのエラー (位置順)
+#[derive(Debug)]
+pub struct AssembleErrors {
+    pub errors: Vec<AssembleError>,
+    /// 上限で打ち切る前のエラー数
+    pub total: usize,
+}
+
+impl fmt::Display for AssembleErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for e in self.errors.iter() {
+            writeln!(f, "{}\n", e)?;
+        }
+        if self.total > self.errors.len() {
+            writeln!(
+                f,
+                "... {} more errors omitted",
+                self.total - self.errors.len()
+            )?;
+        }
+        write!(f, "aborting due to {} error(s)", self.total)
+    }
+}
+
+impl std::error::Error for AssembleErrors {}
+
+/// アセンブル時の設定
+#[derive(Debug, Clone)]
+pub struct AssembleOptions {
+    /// 報告するエラーの上限 (0なら無制限)
+    pub max_errors: usize,
+    /// リスティングを出力するか
+    pub listing: bool,
+    /// アセンブルの前に覗き穴最適化を行うか
+    /// 最適化した命令列はソースと対応しないので、`listing`があれば行わない
+    pub optimize: bool,
+    /// 変数のRAM配置
+    pub layout: RamLayout,
+    /// 変数のメモリマップを出力するか
+    pub memory_map: bool,
+    /// シフト命令 (`101`で始まるC命令) を持つ拡張命令セットを使うか
+    /// 既定値は`extended-isa`フィーチャーで有効になる
+    pub extended: bool,
+}
+
+impl Default for AssembleOptions {
+    fn default() -> Self {
+        Self {
+            max_errors: 20,
+            listing: false,
+            optimize: false,
+            layout: RamLayout::default(),
+            memory_map: false,
+            extended: cfg!(feature = "extended-isa"),
+        }
+    }
+}
+
+/// アセンブル結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// 機械語 (`OutputFormat::encode`で他の形式に変換できる)
+    pub words: Vec<u16>,
+    /// `AssembleOptions::listing`が有効なときのリスティング
+    pub listing: Option<String>,
+    /// `AssembleOptions::memory_map`が有効なときのメモリマップ
+    pub memory_map: Option<String>,
+    /// `AssembleOptions::optimize`で削った命令数
+    pub saved: usize,
+    /// 数値や式のジャンプ先があるため、最適化しなかったか
+    pub optimize_skipped: bool,
+}
+
+/// `Assembler::assemble_statements`のエラー (`index`は文の番号)
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StatementError {
+    #[error("statement {index}: {kind}")]
+    SymTable {
+        index: usize,
+        kind: SymTableErrorKind,
+    },
+    #[error("statement {index}: {kind}")]
+    Instruction {
+        index: usize,
+        kind: InstructionError,
+    },
+}
+
+/// A命令で指定できる最大値 (15bit)
+const MAX_ADDRESS: u64 = 0x7fff;
+
 pub struct Assembler {
     sym_table: SymbolTable,
+    /// 拡張命令セットのシフト命令を受け付けるか
+    extended: bool,
+}
+
+/// 拡張命令セットが無効なときの、シフト命令のエラー
+/// アセンブルしないサブコマンドでも、アセンブルと同じ命令を受け付けるようにする
+fn extended_only_errors(commands: &[Command], opts: &AssembleOptions) -> Vec<AssembleErrorKind> {
+    if opts.extended {
+        return vec![];
+    }
+    commands
+        .iter()
+        .filter_map(|c| match &c.value {
+            CommandKind::C(CompCommand {
+                comp:
+                    Annot {
+                        value: comp @ CompKind::Shift { .. },
+                        ..
+                    },
+                ..
+            }) => Some(CodeError::extended_only(comp, c.loc.clone()).into()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// .hack形式のテキスト (`run`のように文字列を返すときだけ作る)
+fn hack_text(words: &[u16]) -> String {
+    String::from_utf8(OutputFormat::Hack.encode(words)).unwrap()
 }
 
 impl Assembler {
-    fn new(sym_table: SymbolTable) -> Self {
-        Self { sym_table }
+    fn new(sym_table: SymbolTable, extended: bool) -> Self {
+        Self {
+            sym_table,
+            extended,
+        }
+    }
+
+    pub fn run(input: &str) -> Result<String, AssembleErrors> {
+        Self::run_with_options(input, None, &AssembleOptions::default())
+            .map(|out| hack_text(&out.words))
+    }
+
+    /// エラーメッセージに`path`を含める
+    pub fn run_with_path(input: &str, path: &Path) -> Result<String, AssembleErrors> {
+        Self::run_with_options(input, Some(path), &AssembleOptions::default())
+            .map(|out| hack_text(&out.words))
+    }
+
+    /// ファイル全体を検査してから、エラーがあればまとめて返す
+    pub fn run_with_options(
+        input: &str,
+        path: Option<&Path>,
+        opts: &AssembleOptions,
+    ) -> Result<Output, AssembleErrors> {
+        let mut sources = SourceMap::new(input, path);
+        Self::run_inner(&mut sources, opts).map_err(|kinds| Self::report(kinds, &sources, opts))
+    }
+
+    /// 再配置可能なオブジェクトを作る (`object::link`でまとめる)
+    /// 未定義のシンボルは変数として、リンク時に割り当てる
+    pub fn run_object(
+        input: &str,
+        path: Option<&Path>,
+        opts: &AssembleOptions,
+    ) -> Result<Object, AssembleErrors> {
+        let mut sources = SourceMap::new(input, path);
+        let (commands, mut errors) = sources.parse();
+        match Assembler::compile(&commands, opts.extended) {
+            Ok(obj) if errors.is_empty() => Ok(obj),
+            Ok(_) => Err(Self::report(errors, &sources, opts)),
+            Err(es) => {
+                errors.extend(es);
+                Err(Self::report(errors, &sources, opts))
+            }
+        }
+    }
+
+    /// 構文解析までを行い、ROMの使用量と制御フローを`analysis::analyze`で解析する
+    /// シンボルは解決しないので、ROMに収まらないプログラムも解析できる
+    pub fn analyze(
+        input: &str,
+        path: Option<&Path>,
+        opts: &AssembleOptions,
+    ) -> Result<Analysis, AssembleErrors> {
+        let mut sources = SourceMap::new(input, path);
+        let (commands, mut errors) = sources.parse();
+        errors.extend(extended_only_errors(&commands, opts));
+        if !errors.is_empty() {
+            return Err(Self::report(errors, &sources, opts));
+        }
+        let commands = if opts.optimize {
+            optimize::optimize(commands).0
+        } else {
+            commands
+        };
+        Ok(analysis::analyze(&commands))
+    }
+
+    /// 構文解析までを行い、`lint::lint`の警告を返す
+    /// 構文エラーがあれば検査せずにエラーを返す
+    pub fn lint(
+        input: &str,
+        path: Option<&Path>,
+        opts: &AssembleOptions,
+        lint_opts: &LintOptions,
+    ) -> Result<Vec<LintReport>, AssembleErrors> {
+        let mut sources = SourceMap::new(input, path);
+        let (commands, mut errors) = sources.parse();
+        errors.extend(extended_only_errors(&commands, opts));
+        if !errors.is_empty() {
+            return Err(Self::report(errors, &sources, opts));
+        }
+        let mut warnings = lint::lint(&commands, lint_opts);
+        warnings.sort_by_key(|w| sources.sort_key(&w.loc));
+        let reports = warnings
+            .into_iter()
+            .map(|w| {
+                let (path, snippet, notes) = trace(&w.loc, &sources);
+                LintReport {
+                    warning: w.value,
+                    path,
+                    snippet,
+                    notes,
+                }
+            })
+            .collect();
+        Ok(reports)
+    }
+
+    /// `formatter::format_source`で整形する
+    /// `.include`やマクロは展開せず、このファイルだけを整形する
+    /// 拡張命令セットが無効なら、シフト命令があればエラーにする (ほかの構文エラーは見ない)
+    pub fn format(
+        input: &str,
+        path: Option<&Path>,
+        opts: &AssembleOptions,
+    ) -> Result<String, AssembleErrors> {
+        let formatted = formatter::format_source(input).map_err(|errors| {
+            let sources = SourceMap::new(input, path);
+            let kinds = errors.into_iter().map(AssembleErrorKind::from).collect();
+            Self::report(kinds, &sources, opts)
+        })?;
+        if !opts.extended {
+            let mut sources = SourceMap::new(input, path);
+            let (commands, _) = sources.parse();
+            let errors = extended_only_errors(&commands, opts);
+            if !errors.is_empty() {
+                return Err(Self::report(errors, &sources, opts));
+            }
+        }
+        Ok(formatted)
     }
 
-    pub fn run(input: &str) -> Result<String, AssembleError> {
-        let tokens = lexer::lex(input)?;
-        let commands = parser::parse(tokens)?;
-        Assembler::assemble(commands)
+    /// 位置順に並べ、上限で打ち切る
+    fn report(
+        mut kinds: Vec<AssembleErrorKind>,
+        sources: &SourceMap,
+        opts: &AssembleOptions,
+    ) -> AssembleErrors {
+        kinds.sort_by_key(|k| sources.sort_key(k.loc()));
+        let total = kinds.len();
+        if opts.max_errors > 0 {
+            kinds.truncate(opts.max_errors);
+        }
+        let errors = kinds
+            .into_iter()
+            .map(|kind| AssembleError::located(kind, sources))
+            .collect();
+        AssembleErrors { errors, total }
     }
 
-    fn assemble(commands: Vec<Command>) -> Result<String, AssembleError> {
+    /// テキストを介さずに命令列とラベルから機械語を作る
+    /// エラーがあっても最後まで検査し、すべてのエラーを返す
+    pub fn assemble_statements(program: &[Statement]) -> Result<Vec<u16>, Vec<StatementError>> {
+        let mut errors = vec![];
         let mut sym_table = SymbolTable::new();
-        sym_table.resolve(&commands)?;
-        let asm = Self::new(sym_table);
-        let mut code = String::new();
+        if let Err(es) = sym_table.resolve_statements(program) {
+            errors.extend(es.into_iter().map(|e| StatementError::SymTable {
+                index: e.loc.start(),
+                kind: e.value,
+            }));
+        }
+
+        let mut words = vec![];
+        let mut scope = "";
+        for (index, stmt) in program.iter().enumerate() {
+            match stmt {
+                Statement::Label(label) => {
+                    if !is_local(label) {
+                        scope = label;
+                    }
+                }
+                Statement::Symbol(symbol) => {
+                    // 解決に失敗したシンボルはエラー報告済みなので0で埋める
+                    let symbol = qualify(symbol, scope);
+                    words.push(sym_table.get_address(&symbol).copied().unwrap_or(0));
+                }
+                Statement::Instr(instr) => match instr.encode() {
+                    Ok(word) => words.push(word),
+                    Err(kind) => errors.push(StatementError::Instruction { index, kind }),
+                },
+            }
+        }
 
-        for cmd in commands.iter() {
+        if errors.is_empty() {
+            Ok(words)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn run_inner(
+        sources: &mut SourceMap,
+        opts: &AssembleOptions,
+    ) -> Result<Output, Vec<AssembleErrorKind>> {
+        let (commands, mut errors) = sources.parse();
+        let (commands, saved) = if opts.optimize && !opts.listing {
+            optimize::optimize(commands)
+        } else {
+            (commands, Some(0))
+        };
+
+        let (asm, words) = match Assembler::assemble(&commands, opts) {
+            Ok(res) if errors.is_empty() => res,
+            Ok(_) => return Err(errors),
+            Err(es) => {
+                errors.extend(es);
+                return Err(errors);
+            }
+        };
+
+        let listing = if opts.listing {
+            Some(listing::render(
+                sources.buf(),
+                &commands,
+                &words,
+                &asm.sym_table,
+            ))
+        } else {
+            None
+        };
+        let memory_map = if opts.memory_map {
+            Some(memory_map::render(&commands, &asm.sym_table, &opts.layout))
+        } else {
+            None
+        };
+        Ok(Output {
+            words,
+            listing,
+            memory_map,
+            saved: saved.unwrap_or(0),
+            optimize_skipped: saved.is_none(),
+        })
+    }
+
+    fn assemble(
+        commands: &[Command],
+        opts: &AssembleOptions,
+    ) -> Result<(Self, Vec<u16>), Vec<AssembleErrorKind>> {
+        let mut errors = vec![];
+        let mut sym_table = SymbolTable::with_layout(&opts.layout);
+        if let Err(es) = sym_table.resolve(commands) {
+            errors.extend(es.into_iter().map(AssembleErrorKind::from));
+        }
+        let asm = Self::new(sym_table, opts.extended);
+        let mut words = vec![];
+
+        for (cmd, scope) in commands.iter().zip(scopes(commands)) {
             match cmd {
                 Annot {
                     value: CommandKind::A(cmd),
-                    ..
+                    loc,
+                } => match asm.gen_acode(cmd, scope, loc) {
+                    Ok(word) => words.push(word),
+                    Err(e) => errors.push(e.into()),
+                },
+                Annot {
+                    value: CommandKind::C(cmd),
+                    loc,
+                } => match asm.gen_ccode(cmd, loc) {
+                    Ok(word) => words.push(word),
+                    Err(e) => errors.push(e.into()),
+                },
+                _ => (),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok((asm, words))
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn compile(commands: &[Command], extended: bool) -> Result<Object, Vec<AssembleErrorKind>> {
+        let mut errors = vec![];
+        let mut sym_table = SymbolTable::new();
+        if let Err(es) = sym_table.resolve_labels(commands) {
+            errors.extend(es.into_iter().map(AssembleErrorKind::from));
+        }
+        let externs: HashSet<&str> = commands
+            .iter()
+            .filter_map(|c| match &c.value {
+                CommandKind::D(DirectiveCommand::Extern { name }) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        let asm = Self::new(sym_table, extended);
+        let mut obj = Object::default();
+
+        for (cmd, scope) in commands.iter().zip(scopes(commands)) {
+            let res = match cmd {
+                Annot {
+                    value: CommandKind::A(cmd),
+                    loc,
+                } => match object::reloc_target(&cmd.value, scope, &asm.sym_table, &externs) {
+                    Ok(None) => asm
+                        .gen_acode(cmd, scope, loc)
+                        .map_err(AssembleErrorKind::from),
+                    Ok(Some(target)) => {
+                        obj.relocs.push(object::Reloc {
+                            offset: obj.code.len() as Address,
+                            target,
+                        });
+                        // 足し込む値は負でもよく、足した結果の範囲はリンク時に検査する
+                        match asm.sym_table.eval(&cmd.value, scope) {
+                            Some(value) if value.abs() <= MAX_ADDRESS as i64 => Ok(value as u16),
+                            Some(value) => {
+                                Err(CodeError::expr_out_of_range(&cmd.value, value, loc.clone())
+                                    .into())
+                            }
+                            None => Err(CodeError::expr_overflow(&cmd.value, loc.clone()).into()),
+                        }
+                    }
+                    Err(()) => Err(ObjectError::not_relocatable(&cmd.value, loc.clone()).into()),
+                },
+                Annot {
+                    value: CommandKind::C(cmd),
+                    loc,
+                } => asm.gen_ccode(cmd, loc).map_err(AssembleErrorKind::from),
+                // RAMの配置はリンク時に決まる
+                Annot {
+                    value: CommandKind::D(DirectiveCommand::Var { .. }),
+                    loc,
                 } => {
-                    let line = asm.gen_acode(cmd);
-                    code.push_str(&line);
+                    Err(ObjectError::unsupported_directive(DirectiveKind::Var, loc.clone()).into())
                 }
                 Annot {
-                    value: CommandKind::C(cmd),
-                    ..
+                    value: CommandKind::D(DirectiveCommand::Org { .. }),
+                    loc,
                 } => {
-                    let line = asm.gen_ccode(cmd);
-                    code.push_str(&line);
+                    Err(ObjectError::unsupported_directive(DirectiveKind::Org, loc.clone()).into())
                 }
-                _ => (),
+                _ => continue,
+            };
+            match res {
+                Ok(word) => obj.code.push(word),
+                Err(e) => errors.push(e),
             }
         }
 
-        Ok(code)
+        obj.exports = asm
+            .sym_table
+            .global_labels()
+            .map(|(label, address)| (label.clone(), *address))
+            .collect();
+        obj.exports.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+        obj.imports = externs.into_iter().map(|s| s.to_owned()).collect();
+        obj.imports.sort();
+
+        if errors.is_empty() {
+            Ok(obj)
+        } else {
+            Err(errors)
+        }
     }
 
-    fn gen_acode(&self, cmd: &AddrCommand) -> String {
-        match cmd {
-            AddrCommand {
-                value: NumOrSymbol::Num(n),
-            } => {
-                format!("0{:015b}\n", n)
+    fn gen_acode(&self, cmd: &AddrCommand, scope: &str, loc: &Loc) -> Result<u16, CodeError> {
+        match &cmd.value {
+            Expr::Num(n) => {
+                if *n > MAX_ADDRESS {
+                    return Err(CodeError::address_overflow(*n, loc.clone()));
+                }
+                Ok(*n as u16)
             }
-            AddrCommand {
-                value: NumOrSymbol::Symbol(s),
-            } => {
-                let addrees = self.sym_table.get_address(s).unwrap();
-                format!("0{:015b}\n", addrees)
+            expr => {
+                let value = self
+                    .sym_table
+                    .eval(expr, scope)
+                    .ok_or_else(|| CodeError::expr_overflow(expr, loc.clone()))?;
+                if value < 0 || value > MAX_ADDRESS as i64 {
+                    return Err(CodeError::expr_out_of_range(expr, value, loc.clone()));
+                }
+                Ok(value as u16)
             }
         }
     }
 
-    fn gen_ccode(&self, cmd: &CompCommand) -> String {
-        match cmd {
-            CompCommand {
-                dest,
-                comp: Annot { value: comp, .. },
-                jump,
-            } => {
-                let comp = Assembler::comp_code(comp);
-                let dest = Assembler::dest_code(dest);
-                let jump = Assembler::jump_code(jump);
-                format!("111{}{}{}\n", comp, dest, jump)
+    fn gen_ccode(&self, cmd: &CompCommand, loc: &Loc) -> Result<u16, CodeError> {
+        let CompCommand {
+            dest,
+            comp: Annot { value: comp, .. },
+            jump,
+        } = cmd;
+        let (prefix, code) = match comp {
+            CompKind::Shift { op, e } => {
+                if !self.extended {
+                    return Err(CodeError::extended_only(comp, loc.clone()));
+                }
+                (0b101, Assembler::shift_code(op, e))
             }
+            _ => (0b111, Assembler::comp_code(comp)),
+        };
+        let comp = code.ok_or_else(|| CodeError::invalid_comp(comp, loc.clone()))?;
+        let dest = Assembler::dest_code(dest);
+        let jump = Assembler::jump_code(jump);
+        Ok(prefix << 13 | comp << 6 | dest << 3 | jump)
+    }
+
+    fn dest_code(dest: &Option<MemKind>) -> u16 {
+        match dest {
+            None => 0b000,
+            Some(MemKind::M) => 0b001,
+            Some(MemKind::D) => 0b010,
+            Some(MemKind::MD) => 0b011,
+            Some(MemKind::A) => 0b100,
+            Some(MemKind::AM) => 0b101,
+            Some(MemKind::AD) => 0b110,
+            Some(MemKind::AMD) => 0b111,
         }
     }
 
-    fn dest_code(dest: &Option<MemKind>) -> String {
-        let code = match dest {
-            None => "000",
-            Some(MemKind::M) => "001",
-            Some(MemKind::D) => "010",
-            Some(MemKind::MD) => "011",
-            Some(MemKind::A) => "100",
-            Some(MemKind::AM) => "101",
-            Some(MemKind::AD) => "110",
-            Some(MemKind::AMD) => "111",
+    /// 表記揺れ (`M+D`, `1+D` など) は正規形に直してから引く
+    /// エンコードできない組み合わせは`None`
+    fn comp_code(cmd: &CompKind) -> Option<u16> {
+        Assembler::comp(cmd).map(instruction::Comp::bits)
+    }
+
+    /// 拡張命令セットのシフト (接頭辞`101`に続くa c1..c6)
+    fn shift_code(op: &ShiftKind, e: &MemKind) -> Option<u16> {
+        let code = match (op, e) {
+            (ShiftKind::Left, MemKind::D) => 0b0110000,
+            (ShiftKind::Left, MemKind::A) => 0b0100000,
+            (ShiftKind::Left, MemKind::M) => 0b1100000,
+            (ShiftKind::Right, MemKind::D) => 0b0010000,
+            (ShiftKind::Right, MemKind::A) => 0b0000000,
+            (ShiftKind::Right, MemKind::M) => 0b1000000,
+            _ => return None,
         };
-        code.to_owned()
+        Some(code)
+    }
+
+    /// 正規形の表記 (`1+D` → `D+1`)
+    /// エンコードできない組み合わせは`None`
+    pub(crate) fn canonical_comp(cmd: &CompKind) -> Option<&'static str> {
+        Assembler::comp(cmd).map(instruction::Comp::mnemonic)
     }
 
-    fn comp_code(cmd: &CompKind) -> String {
+    /// 構文木のcompを`instruction::Comp`に対応させる (ビット列は`Comp::bits`だけが持つ)
+    fn comp(cmd: &CompKind) -> Option<instruction::Comp> {
         use CompKind::*;
 
         match cmd {
-            Constant(cons) => Assembler::constant_code(cons),
-            Mem(m) => Assembler::mem_code(m),
+            Constant(cons) => Some(Assembler::constant_comp(cons)),
+            Mem(m) => Assembler::mem_comp(m),
             UniOp {
                 op: Annot { value: op, .. },
                 e,
-            } => Assembler::uniop_code(op, e),
+            } => Assembler::uniop_comp(op, e),
             BinOp {
                 op: Annot { value: op, .. },
                 l,
                 r,
-            } => Assembler::binop_code(op, l, r),
+            } => {
+                let (l, r) = Assembler::normalize_operands(op, l, r);
+                Assembler::binop_comp(op, l, r)
+            }
+            // 接頭辞が異なるので`gen_ccode`で扱う
+            Shift { .. } => None,
+        }
+    }
+
+    fn constant_comp(cons: &Constant) -> instruction::Comp {
+        match cons {
+            Constant::Zero => instruction::Comp::Zero,
+            Constant::One => instruction::Comp::One,
         }
     }
 
-    fn constant_code(cons: &Constant) -> String {
-        let code = match cons {
-            Constant::Zero => "0101010",
-            Constant::One => "0111111",
+    fn mem_comp(mem: &MemKind) -> Option<instruction::Comp> {
+        let comp = match mem {
+            MemKind::D => instruction::Comp::D,
+            MemKind::A => instruction::Comp::A,
+            MemKind::M => instruction::Comp::M,
+            _ => return None,
         };
-        code.to_owned()
+        Some(comp)
     }
 
-    fn mem_code(mem: &MemKind) -> String {
-        use MemKind::*;
+    fn uniop_comp(op: &UniOpKind, e: &Operand) -> Option<instruction::Comp> {
+        use instruction::Comp::*;
+        use MemKind::{A, D, M};
+        use Operand::Mem;
 
-        let code = match mem {
-            D => "0001100",
-            A => "0110000",
-            M => "1110000",
-            _ => unreachable!(),
+        let comp = match (op, e) {
+            (UniOpKind::Minus, Operand::Constant(Constant::One)) => MinusOne,
+            (UniOpKind::Not, Mem(D)) => NotD,
+            (UniOpKind::Minus, Mem(D)) => NegD,
+            (UniOpKind::Not, Mem(A)) => NotA,
+            (UniOpKind::Minus, Mem(A)) => NegA,
+            (UniOpKind::Not, Mem(M)) => NotM,
+            (UniOpKind::Minus, Mem(M)) => NegM,
+            _ => return None,
         };
-        code.to_owned()
+        Some(comp)
+    }
+
+    /// 可換な演算子のオペランドを正規形 (`D`が左、定数が右) に並べ替える
+    fn normalize_operands<'a>(
+        op: &BinOpKind,
+        l: &'a Operand,
+        r: &'a Operand,
+    ) -> (&'a Operand, &'a Operand) {
+        if !op.is_commutative() {
+            return (l, r);
+        }
+        match (l, r) {
+            (Operand::Constant(_), _) => (r, l),
+            (Operand::Mem(MemKind::A), Operand::Mem(MemKind::D))
+            | (Operand::Mem(MemKind::M), Operand::Mem(MemKind::D)) => (r, l),
+            _ => (l, r),
+        }
     }
 
-    fn uniop_code(op: &UniOpKind, e: &Operand) -> String {
-        use MemKind::*;
+    fn binop_comp(op: &BinOpKind, l: &Operand, r: &Operand) -> Option<instruction::Comp> {
+        use instruction::Comp::*;
+        use BinOpKind::{Add, And, Or, Sub};
+        use MemKind::{A, D, M};
         use Operand::Mem;
 
-        let code = match (op, e) {
-            // -1
-            (UniOpKind::Minus, Operand::Constant(Constant::One)) => "0111010",
-            // !D
-            (UniOpKind::Not, Mem(D)) => "0001101",
-            // -D
-            (UniOpKind::Minus, Mem(D)) => "0001111",
-            // !A
-            (UniOpKind::Not, Mem(A)) => "0110001",
-            // -A
-            (UniOpKind::Minus, Mem(A)) => "0110011",
-            // !M
-            (UniOpKind::Not, Mem(M)) => "1110001",
-            // -M
-            (UniOpKind::Minus, Mem(M)) => "1110011",
-            _ => unreachable!(),
-        };
-        code.to_owned()
-    }
-
-    fn binop_code(op: &BinOpKind, l: &MemKind, r: &Operand) -> String {
-        use BinOpKind::*;
-        use MemKind::*;
-
-        let code = match (op, l, r) {
-            // D+1
-            (Add, D, Operand::Constant(Constant::One)) => "0011111",
-            // A+1
-            (Add, A, Operand::Constant(Constant::One)) => "0110111",
-            // D-1
-            (Sub, D, Operand::Constant(Constant::One)) => "0001110",
-            // A-1
-            (Sub, A, Operand::Constant(Constant::One)) => "0110010",
-            // D+A
-            (Add, D, Operand::Mem(A)) => "0000010",
-            // D-A
-            (Sub, D, Operand::Mem(A)) => "0010011",
-            // A-D
-            (Sub, A, Operand::Mem(D)) => "0000111",
-            // D&A
-            (And, D, Operand::Mem(A)) => "0000000",
-            // D|A
-            (Or, D, Operand::Mem(A)) => "0010101",
-            // M+1
-            (Add, M, Operand::Constant(Constant::One)) => "1110111",
-            // M-1
-            (Sub, M, Operand::Constant(Constant::One)) => "1110010",
-            // D+M
-            (Add, D, Operand::Mem(M)) => "1000010",
-            // D-M
-            (Sub, D, Operand::Mem(M)) => "1010011",
-            // M-D
-            (Sub, M, Operand::Mem(D)) => "1000111",
-            // D&M
-            (And, D, Operand::Mem(M)) => "1000000",
-            // D|M
-            (Or, D, Operand::Mem(M)) => "1010101",
-            _ => unreachable!(),
-        };
-        code.to_owned()
-    }
-
-    fn jump_code(jump: &Option<JumpKind>) -> String {
-        let code = match jump {
-            None => "000",
-            Some(JumpKind::Gt) => "001",
-            Some(JumpKind::Eq) => "010",
-            Some(JumpKind::Ge) => "011",
-            Some(JumpKind::Lt) => "100",
-            Some(JumpKind::Ne) => "101",
-            Some(JumpKind::Le) => "110",
-            Some(JumpKind::Jmp) => "111",
+        let comp = match (op, l, r) {
+            (Add, Mem(D), Operand::Constant(Constant::One)) => DPlusOne,
+            (Add, Mem(A), Operand::Constant(Constant::One)) => APlusOne,
+            (Add, Mem(M), Operand::Constant(Constant::One)) => MPlusOne,
+            (Sub, Mem(D), Operand::Constant(Constant::One)) => DMinusOne,
+            (Sub, Mem(A), Operand::Constant(Constant::One)) => AMinusOne,
+            (Sub, Mem(M), Operand::Constant(Constant::One)) => MMinusOne,
+            (Add, Mem(D), Mem(A)) => DPlusA,
+            (Add, Mem(D), Mem(M)) => DPlusM,
+            (Sub, Mem(D), Mem(A)) => DMinusA,
+            (Sub, Mem(D), Mem(M)) => DMinusM,
+            (Sub, Mem(A), Mem(D)) => AMinusD,
+            (Sub, Mem(M), Mem(D)) => MMinusD,
+            (And, Mem(D), Mem(A)) => DAndA,
+            (And, Mem(D), Mem(M)) => DAndM,
+            (Or, Mem(D), Mem(A)) => DOrA,
+            (Or, Mem(D), Mem(M)) => DOrM,
+            _ => return None,
         };
-        code.to_owned()
+        Some(comp)
+    }
+
+    fn jump_code(jump: &Option<JumpKind>) -> u16 {
+        match jump {
+            None => 0b000,
+            Some(JumpKind::Gt) => 0b001,
+            Some(JumpKind::Eq) => 0b010,
+            Some(JumpKind::Ge) => 0b011,
+            Some(JumpKind::Lt) => 0b100,
+            Some(JumpKind::Ne) => 0b101,
+            Some(JumpKind::Le) => 0b110,
+            Some(JumpKind::Jmp) => 0b111,
+        }
     }
 }
 
@@ -396,4 +1098,322 @@ M=D
 "###;
         assert_eq!(actual, expect);
     }
+
+    #[test]
+    fn test_asm_commutative_comp() {
+        let cases = vec![
+            ("D=M+D", "D=D+M"),
+            ("D=A+D", "D=D+A"),
+            ("D=1+D", "D=D+1"),
+            ("M=1+M", "M=M+1"),
+            ("A=1+A", "A=A+1"),
+            ("AM=M&D", "AM=D&M"),
+            ("AM=A&D", "AM=D&A"),
+            ("D=M|D", "D=D|M"),
+            ("D=A|D", "D=D|A"),
+        ];
+        for (input, canonical) in cases {
+            let actual = Assembler::run(input).unwrap();
+            let expect = Assembler::run(canonical).unwrap();
+            assert_eq!(actual, expect, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_asm_every_comp() {
+        // 正規形の表記はすべて`instruction::Comp`と同じ機械語になる
+        for comp in instruction::Comp::ALL.iter() {
+            let input = format!("D={}", comp.mnemonic());
+            let expect = 0xe000 | comp.bits() << 6 | 0b010 << 3;
+            let actual = Assembler::run_with_options(&input, None, &AssembleOptions::default())
+                .unwrap()
+                .words;
+            assert_eq!(actual, vec![expect], "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_asm_invalid_comp() {
+        for input in &[
+            "D=A+M",
+            "D=D-0",
+            "D=1-D",
+            "D=-0",
+            "D=!1",
+            "D=AM",
+            "0;JMP\nD=M-A",
+        ] {
+            match Assembler::run(input) {
+                Err(e) if matches!(*e.errors[0].kind, AssembleErrorKind::Code(_)) => (),
+                actual => panic!("{}: {:?}", input, actual),
+            }
+        }
+    }
+
+    #[test]
+    fn test_asm_extended_isa() {
+        let input = "D=D<<\nM=M>>\nAM=A<<;JMP\nD=A>>\n";
+        let opts = AssembleOptions {
+            extended: true,
+            ..AssembleOptions::default()
+        };
+        let actual = hack_text(
+            &Assembler::run_with_options(input, None, &opts)
+                .unwrap()
+                .words,
+        );
+        let expect = "1010110000010000\n1011000000001000\n1010100000101111\n1010000000010000\n";
+        assert_eq!(actual, expect);
+
+        // アセンブルしないサブコマンドも同じ命令を受け付ける
+        let default = AssembleOptions {
+            extended: false,
+            ..AssembleOptions::default()
+        };
+        assert!(Assembler::analyze(input, None, &opts).is_ok());
+        let err = Assembler::analyze(input, None, &default).unwrap_err();
+        assert_eq!(err.total, 4);
+        assert!(Assembler::lint(input, None, &default, &LintOptions::default()).is_err());
+        assert!(Assembler::format(input, None, &default).is_err());
+        assert!(Assembler::format(input, None, &opts).is_ok());
+
+        let err = Assembler::run_with_options("AD=AD<<", None, &opts).unwrap_err();
+        assert_eq!(
+            err.errors[0].kind.message(),
+            "comp `AD<<` cannot be encoded"
+        );
+
+        let opts = AssembleOptions {
+            extended: false,
+            ..AssembleOptions::default()
+        };
+        let err = Assembler::run_with_options("@0\nD=M<<\n", None, &opts).unwrap_err();
+        assert_eq!(
+            err.errors[0].kind.message(),
+            "`M<<` is a shift instruction of the extended ISA (enable it with `--extended-isa` or the `extended-isa` feature)"
+        );
+        assert_eq!(
+            err.errors[0].snippet.pos,
+            crate::LineCol { line: 2, col: 1 }
+        );
+    }
+
+    #[test]
+    fn test_asm_error_diagnostic() {
+        let input = "@0\nD=M\n  @+\n";
+        let err = Assembler::run_with_path(input, Path::new("Bad.asm")).unwrap_err();
+        let expect = r###"ParseError: unexpected token `+`, expected a number or a symbol
+ --> Bad.asm:3:4
+  |
+3 |   @+
+  |    ^"###;
+        assert_eq!(err.errors[0].to_string(), expect);
+
+        let err = Assembler::run("@0\nD=A+M\n").unwrap_err();
+        let err = &err.errors[0];
+        assert_eq!(err.snippet.pos, crate::LineCol { line: 2, col: 1 });
+        assert_eq!(err.snippet.width, 5);
+    }
+
+    #[test]
+    fn test_asm_all_errors() {
+        let input = "@0\nD=A+M\n@\nD=D+%\nM=D\n(END\n@END\n0;JMP\n";
+        let err = Assembler::run(input).unwrap_err();
+        assert_eq!(err.total, 4);
+        let lines: Vec<usize> = err.errors.iter().map(|e| e.snippet.pos.line).collect();
+        assert_eq!(lines, vec![2, 3, 4, 6]);
+
+        let opts = AssembleOptions {
+            max_errors: 2,
+            ..AssembleOptions::default()
+        };
+        let err = Assembler::run_with_options(input, None, &opts).unwrap_err();
+        assert_eq!(err.total, 4);
+        assert_eq!(err.errors.len(), 2);
+        assert!(err.to_string().contains("2 more errors omitted"));
+    }
+
+    #[test]
+    fn test_asm_address_space() {
+        let actual = Assembler::run("@32767\n").unwrap();
+        assert_eq!(actual, "0111111111111111\n");
+
+        let err = Assembler::run("@32768\n@99999\n").unwrap_err();
+        assert_eq!(err.total, 2);
+        assert!(matches!(
+            *err.errors[0].kind,
+            AssembleErrorKind::Code(Annot {
+                value: CodeErrorKind::AddressOverflow(32768),
+                ..
+            })
+        ));
+
+        let err = Assembler::run("(LOOP)\n@LOOP\n(LOOP)\n0;JMP\n").unwrap_err();
+        assert_eq!(err.total, 1);
+        assert_eq!(err.errors[0].snippet.pos.line, 3);
+
+        // ROMに収まらないプログラム
+        let input = "D=0\n".repeat(crate::sysmbol_table::ROM_SIZE + 2);
+        let err = Assembler::run(&input).unwrap_err();
+        assert_eq!(err.total, 1);
+        assert_eq!(err.errors[0].snippet.pos.line, 32769);
+    }
+
+    #[test]
+    fn test_assemble_statements() {
+        use crate::instruction::*;
+
+        let sym = |s: &str| Statement::Symbol(s.to_owned());
+        let label = |s: &str| Statement::Label(s.to_owned());
+        let program: Vec<Statement> = vec![
+            sym("R0"),
+            Instruction::c(Comp::M).dest(Dest::D).into(),
+            sym("R1"),
+            Instruction::c(Comp::DMinusM).dest(Dest::D).into(),
+            sym("OUTPUT_FIRST"),
+            Instruction::c(Comp::D).jump(Jump::JGT).into(),
+            sym("R1"),
+            Instruction::c(Comp::M).dest(Dest::D).into(),
+            sym("OUTPUT_D"),
+            Instruction::c(Comp::Zero).jump(Jump::JMP).into(),
+            label("OUTPUT_FIRST"),
+            sym("R0"),
+            Instruction::c(Comp::M).dest(Dest::D).into(),
+            label("OUTPUT_D"),
+            sym("R2"),
+            Instruction::c(Comp::D).dest(Dest::M).into(),
+            label("INFINITE_LOOP"),
+            sym("INFINITE_LOOP"),
+            Instruction::c(Comp::Zero).jump(Jump::JMP).into(),
+        ];
+        let words = Assembler::assemble_statements(&program).unwrap();
+        let actual: String = words.iter().map(|w| format!("{:016b}\n", w)).collect();
+        let expect = Assembler::run(include_str!("../../06-assembler/max/Max.asm")).unwrap();
+        assert_eq!(actual, expect);
+
+        let program = vec![label("X"), Instruction::A(0x8000).into(), label("X")];
+        let actual = Assembler::assemble_statements(&program);
+        assert_eq!(
+            actual,
+            Err(vec![
+                StatementError::SymTable {
+                    index: 2,
+                    kind: SymTableErrorKind::DuplicateLabel("X".to_owned()),
+                },
+                StatementError::Instruction {
+                    index: 1,
+                    kind: InstructionError::AddressOverflow(0x8000),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_asm_expr() {
+        let input = "@SCREEN+32\n@KBD-1\n(LOOP)\n@LOOP+2\n@0x4000\n@0b101\n@'A'\n@(i+1)*2<<1\n@SCREEN/32*3/2\n";
+        let actual = Assembler::run(input).unwrap();
+        let words: Vec<u16> = actual
+            .lines()
+            .map(|l| u16::from_str_radix(l, 2).unwrap())
+            .collect();
+        assert_eq!(words, vec![0x4020, 0x5fff, 4, 0x4000, 5, 65, 68, 768]);
+
+        let err = Assembler::run("@KBD*2\n@0-1\n@1<<70\n@1/(2-2)\n").unwrap_err();
+        let kinds: Vec<String> = err.errors.iter().map(|e| e.kind.message()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "`@KBD*2` evaluates to 49152, which does not fit in 15 bits (0..=32767)",
+                "`@0-1` evaluates to -1, which does not fit in 15 bits (0..=32767)",
+                "`@1<<70` overflows or divides by zero during evaluation",
+                "`@1/(2-2)` overflows or divides by zero during evaluation",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_asm_include() {
+        use std::fs;
+
+        let dir = std::env::temp_dir().join(format!("hackasm-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/consts.asm"), ".equ WIDTH 32\n").unwrap();
+        fs::write(
+            dir.join("lib/clear.asm"),
+            ".include \"consts.asm\"\n(CLEAR)\n@WIDTH\nD=A\n",
+        )
+        .unwrap();
+        fs::write(dir.join("lib/bad.asm"), "@1\nD=%\n").unwrap();
+        fs::write(dir.join("lib/cycle.asm"), ".include \"cycle.asm\"\n").unwrap();
+
+        let main = dir.join("main.asm");
+        let input = ".include \"lib/clear.asm\"\n@CLEAR\n0;JMP\n";
+        let actual = Assembler::run_with_path(input, &main).unwrap();
+        let expect = Assembler::run("@32\nD=A\n@0\n0;JMP\n").unwrap();
+        assert_eq!(actual, expect);
+
+        // 取り込んだファイルのエラーはそのファイルの位置で報告する
+        let input =
+            "@0\n.include \"lib/bad.asm\"\n.include \"lib/cycle.asm\"\n.include \"none.asm\"\n";
+        let err = Assembler::run_with_path(input, &main).unwrap_err();
+        assert_eq!(err.total, 3);
+        assert_eq!(err.errors[0].path, Some(main.clone()));
+        assert!(err.errors[0].kind.message().starts_with("cannot read"));
+        assert_eq!(err.errors[0].snippet.pos.line, 4);
+        assert_eq!(err.errors[1].path, Some(dir.join("lib/bad.asm")));
+        assert_eq!(
+            err.errors[1].snippet.pos,
+            crate::LineCol { line: 2, col: 3 }
+        );
+        assert_eq!(err.errors[2].path, Some(dir.join("lib/cycle.asm")));
+        assert!(err.errors[2].kind.message().ends_with("includes itself"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_asm_macro() {
+        let input = r###".macro PUSH_D
+@SP
+A=M
+M=D
+@SP
+M=M+1
+.endm
+.macro PUSH_CONST value
+@value
+D=A
+PUSH_D
+.endm
+PUSH_CONST 7
+PUSH_CONST SCREEN+1
+"###;
+        let actual = Assembler::run(input).unwrap();
+        let expect = Assembler::run(
+            "@7\nD=A\n@SP\nA=M\nM=D\n@SP\nM=M+1\n@16385\nD=A\n@SP\nA=M\nM=D\n@SP\nM=M+1\n",
+        )
+        .unwrap();
+        assert_eq!(actual, expect);
+
+        // 展開した行のエラーは定義の位置で報告し、呼び出し箇所を添える
+        let input = ".macro SET x\n@x\nD=D+x\n.endm\nSET 2\n";
+        let err = Assembler::run_with_path(input, Path::new("Macro.asm")).unwrap_err();
+        assert_eq!(err.total, 1);
+        let expect = r###"ParseError: unexpected number `2`, expected `A`, `D`, `M`, `0` or `1`
+ --> Macro.asm:3:5
+  |
+3 | D=D+x
+  |     ^
+ = note: in expansion of macro `SET`
+ --> Macro.asm:5:1
+  |
+5 | SET 2
+  | ^^^^^
+ = note: macro `SET` defined here
+ --> Macro.asm:1:1
+  |
+1 | .macro SET x
+  | ^^^^^^^^^^^^"###;
+        assert_eq!(err.errors[0].to_string(), expect);
+    }
 }
@@ -0,0 +1,112 @@
+use std::fmt;
+use std::path::Path;
+
+/// ソース上の位置 (1-indexed)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LineCol {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for LineCol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// エラー箇所の行・列と、その行の抜粋
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Snippet {
+    pub pos: LineCol,
+    /// エラー箇所を含む行 (改行を除く)
+    pub line_text: String,
+    /// 下線の長さ (文字数)
+    pub width: usize,
+}
+
+impl Snippet {
+    /// バイト位置の区間 [start..end) から作る
+    pub fn new(src: &str, start: usize, end: usize) -> Self {
+        let start = floor_char_boundary(src, start);
+        let end = floor_char_boundary(src, end.max(start));
+
+        let line_start = src[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = src[start..]
+            .find('\n')
+            .map(|i| start + i)
+            .unwrap_or_else(|| src.len());
+        let line_text = src[line_start..line_end].trim_end_matches('\r');
+
+        let line = src[..start].matches('\n').count() + 1;
+        let col = src[line_start..start].chars().count() + 1;
+        let width = src[start..end.min(line_end)].chars().count().max(1);
+
+        Self {
+            pos: LineCol { line, col },
+            line_text: line_text.to_owned(),
+            width,
+        }
+    }
+
+    /// `--> path:line:col` と下線付きの該当行を書き出す
+    pub fn render(&self, f: &mut fmt::Formatter, path: Option<&Path>) -> fmt::Result {
+        let line_no = self.pos.line.to_string();
+        let pad = " ".repeat(line_no.len());
+        match path {
+            Some(path) => writeln!(f, "{}--> {}:{}", pad, path.display(), self.pos)?,
+            None => writeln!(f, "{}--> {}", pad, self.pos)?,
+        }
+        writeln!(f, "{} |", pad)?;
+        writeln!(f, "{} | {}", line_no, self.line_text)?;
+        // タブはそのまま残して下線の位置を揃える
+        let indent: String = self
+            .line_text
+            .chars()
+            .take(self.pos.col - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(f, "{} | {}{}", pad, indent, "^".repeat(self.width))
+    }
+}
+
+fn floor_char_boundary(src: &str, mut i: usize) -> usize {
+    if i >= src.len() {
+        return src.len();
+    }
+    while !src.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snippet_line_col() {
+        let src = "@0\nD=M\n  D;JGT\n";
+        let actual = Snippet::new(src, 11, 14);
+        assert_eq!(actual.pos, LineCol { line: 3, col: 5 });
+        assert_eq!(actual.line_text, "  D;JGT");
+        assert_eq!(actual.width, 3);
+
+        // 行末を越える区間は行末で切る
+        let actual = Snippet::new(src, 3, 100);
+        assert_eq!(actual.pos, LineCol { line: 2, col: 1 });
+        assert_eq!(actual.width, 3);
+
+        // 入力末尾
+        let actual = Snippet::new(src, src.len(), src.len());
+        assert_eq!(actual.pos, LineCol { line: 4, col: 1 });
+        assert_eq!(actual.width, 1);
+    }
+
+    #[test]
+    fn test_snippet_crlf() {
+        let src = "@0\r\nD=M\r\n";
+        let actual = Snippet::new(src, 4, 5);
+        assert_eq!(actual.pos, LineCol { line: 2, col: 1 });
+        assert_eq!(actual.line_text, "D=M");
+    }
+}
@@ -0,0 +1,284 @@
+use crate::instruction::{Instruction, InstructionError, Jump};
+use crate::types::Address;
+
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DisasmError {
+    #[error("ROM[{address}]: `{line}` is not a 16-bit binary word")]
+    InvalidFormat { address: Address, line: String },
+    #[error("ROM[{address}]: unknown comp bits {bits:07b} in {word:016b}")]
+    InvalidComp {
+        address: Address,
+        word: u16,
+        bits: u16,
+    },
+    #[error("ROM[{address}]: invalid C-instruction prefix in {word:016b}")]
+    InvalidPrefix { address: Address, word: u16 },
+}
+
+/// 変数の割り当て開始アドレス (SymbolTable と同じ)
+const VARIABLE_BASE: u16 = 16;
+const SCREEN: u16 = 0x4000;
+const KBD: u16 = 0x6000;
+
+pub struct Disassembler {
+    instrs: Vec<Instruction>,
+    /// ジャンプ先アドレス -> ラベル名
+    labels: HashMap<u16, String>,
+    /// RAMアドレス -> 変数名
+    variables: HashMap<u16, String>,
+}
+
+impl Disassembler {
+    /// .hack形式のテキストを.asmに戻す
+    pub fn run(input: &str) -> Result<String, DisasmError> {
+        let words = Self::read_words(input)?;
+        Self::run_words(&words)
+    }
+
+    /// 機械語列を.asmに戻す
+    pub fn run_words(words: &[u16]) -> Result<String, DisasmError> {
+        let instrs = words
+            .iter()
+            .enumerate()
+            .map(|(i, w)| Self::decode(i as Address, *w))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut disasm = Self {
+            instrs,
+            labels: HashMap::new(),
+            variables: HashMap::new(),
+        };
+        disasm.collect_labels();
+        disasm.collect_variables();
+        Ok(disasm.emit())
+    }
+
+    /// .hack形式のテキストを機械語列として読む (空行は無視する)
+    pub fn read_words(input: &str) -> Result<Vec<u16>, DisasmError> {
+        input
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                let ok = line.len() == 16 && line.bytes().all(|b| b == b'0' || b == b'1');
+                if ok {
+                    Ok(u16::from_str_radix(line, 2).unwrap())
+                } else {
+                    Err(DisasmError::InvalidFormat {
+                        address: i as Address,
+                        line: line.to_owned(),
+                    })
+                }
+            })
+            .collect()
+    }
+
+    fn decode(address: Address, word: u16) -> Result<Instruction, DisasmError> {
+        Instruction::decode(word).map_err(|e| match e {
+            InstructionError::InvalidComp { word, bits } => DisasmError::InvalidComp {
+                address,
+                word,
+                bits,
+            },
+            _ => DisasmError::InvalidPrefix { address, word },
+        })
+    }
+
+    /// ジャンプ命令の直前でAレジスタにロードされるアドレスをラベルとみなす
+    fn collect_labels(&mut self) {
+        let len = self.instrs.len() as u16;
+        for i in 0..self.instrs.len() {
+            if let Some(addr) = self.jump_target(i) {
+                if addr <= len {
+                    self.labels
+                        .entry(addr)
+                        .or_insert_with(|| format!("LABEL_{}", addr));
+                }
+            }
+        }
+    }
+
+    /// `i`番目がA命令で、次の命令がジャンプするならそのアドレス
+    fn jump_target(&self, i: usize) -> Option<u16> {
+        match (self.instrs.get(i), self.instrs.get(i + 1)) {
+            (Some(Instruction::A(addr)), Some(Instruction::C { jump, .. }))
+                if *jump != Jump::Null =>
+            {
+                Some(*addr)
+            }
+            _ => None,
+        }
+    }
+
+    /// 複数回参照されるRAMアドレスを変数とみなす
+    /// 再アセンブルで同じアドレスが割り当たるよう、初出順に16から連番になるものだけを採用する
+    fn collect_variables(&mut self) {
+        let mut count = HashMap::<u16, usize>::new();
+        for instr in self.instrs.iter() {
+            if let Instruction::A(addr) = instr {
+                *count.entry(*addr).or_insert(0) += 1;
+            }
+        }
+
+        let mut vacant = VARIABLE_BASE;
+        let mut seen = HashSet::new();
+        for instr in self.instrs.iter() {
+            let addr = match instr {
+                Instruction::A(addr) => *addr,
+                _ => continue,
+            };
+            let candidate = (VARIABLE_BASE..SCREEN).contains(&addr)
+                && count[&addr] >= 2
+                && !self.labels.contains_key(&addr);
+            if !candidate || !seen.insert(addr) {
+                continue;
+            }
+            if addr != vacant {
+                // 以降の変数はアドレスがずれるので打ち切る
+                break;
+            }
+            self.variables.insert(addr, format!("var_{}", addr));
+            vacant += 1;
+        }
+    }
+
+    /// ラベルにするのはジャンプ先として使うときだけで、同じ値のデータのロードは数値のまま
+    fn operand(&self, i: usize, addr: u16) -> String {
+        if self.jump_target(i).is_some() {
+            if let Some(label) = self.labels.get(&addr) {
+                return label.clone();
+            }
+        }
+        if let Some(var) = self.variables.get(&addr) {
+            return var.clone();
+        }
+        match addr {
+            SCREEN => "SCREEN".to_owned(),
+            KBD => "KBD".to_owned(),
+            _ => addr.to_string(),
+        }
+    }
+
+    fn emit(&self) -> String {
+        let mut code = String::new();
+        for (i, instr) in self.instrs.iter().enumerate() {
+            if let Some(label) = self.labels.get(&(i as u16)) {
+                code.push_str(&format!("({})\n", label));
+            }
+            let line = match instr {
+                Instruction::A(addr) => format!("@{}", self.operand(i, *addr)),
+                c => c.to_string(),
+            };
+            code.push_str(&format!("    {}\n", line));
+        }
+        // プログラム末尾を指すラベル
+        if let Some(label) = self.labels.get(&(self.instrs.len() as u16)) {
+            code.push_str(&format!("({})\n", label));
+        }
+        code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AssembleOptions, Assembler};
+
+    #[test]
+    fn test_disasm_max() {
+        let input = include_str!("../../05-computer-achitecture/Max.hack");
+        let actual = Disassembler::run(input).unwrap();
+        let expect = r###"    @0
+    D=M
+    @1
+    D=D-M
+    @LABEL_10
+    D;JGT
+    @1
+    D=M
+    @LABEL_12
+    0;JMP
+(LABEL_10)
+    @0
+    D=M
+(LABEL_12)
+    @2
+    M=D
+(LABEL_14)
+    @LABEL_14
+    0;JMP
+"###;
+        assert_eq!(actual, expect);
+    }
+
+    #[test]
+    fn test_disasm_data_load() {
+        // ジャンプ先と同じ値でも、ジャンプに使わなければ数値のまま
+        let words = Assembler::run_with_options(
+            "(LOOP)\n@0\nD=A\n@LOOP\n0;JMP\n",
+            None,
+            &AssembleOptions::default(),
+        )
+        .unwrap()
+        .words;
+        let actual = Disassembler::run_words(&words).unwrap();
+        let expect = r###"(LABEL_0)
+    @0
+    D=A
+    @LABEL_0
+    0;JMP
+"###;
+        assert_eq!(actual, expect);
+    }
+
+    #[test]
+    fn test_disasm_roundtrip() {
+        for input in [
+            include_str!("../../05-computer-achitecture/Add.hack"),
+            include_str!("../../05-computer-achitecture/Max.hack"),
+            include_str!("../../05-computer-achitecture/Rect.hack"),
+        ]
+        .iter()
+        {
+            let asm = Disassembler::run(input).unwrap();
+            let actual = Assembler::run(&asm).unwrap();
+            let expect: String = input.lines().map(|l| format!("{}\n", l.trim())).collect();
+            assert_eq!(actual, expect);
+        }
+    }
+
+    #[test]
+    fn test_disasm_variables() {
+        let input = include_str!("../../05-computer-achitecture/Rect.hack");
+        let actual = Disassembler::run(input).unwrap();
+        assert!(actual.contains("@var_16\n"));
+        assert!(actual.contains("@var_17\n"));
+        assert!(actual.contains("@SCREEN\n"));
+    }
+
+    #[test]
+    fn test_disasm_invalid() {
+        let actual = Disassembler::run("0000000000000001\n1111111111000000\n");
+        let expect = DisasmError::InvalidComp {
+            address: 1,
+            word: 0b1111111111000000,
+            bits: 0b1111111,
+        };
+        assert_eq!(actual, Err(expect));
+
+        let actual = Disassembler::run("0000000000000001\n00001\n");
+        assert!(matches!(
+            actual,
+            Err(DisasmError::InvalidFormat { address: 1, .. })
+        ));
+
+        let actual = Disassembler::run("1000000000000000\n");
+        assert!(matches!(
+            actual,
+            Err(DisasmError::InvalidPrefix { address: 0, .. })
+        ));
+    }
+}
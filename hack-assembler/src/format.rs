@@ -0,0 +1,213 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("unknown output format `{0}` (hack, bin, hex, ihex, logisim)")]
+pub struct UnknownFormat(String);
+
+/// 機械語の出力形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    /// 1行に1命令の'0'/'1'テキスト (.hack)
+    Hack,
+    /// ビッグエンディアン16bitを詰めたバイナリ
+    Binary,
+    /// 1行に1命令の16進数 (Verilogの`$readmemh`形式)
+    Hex,
+    /// Intel HEX
+    IntelHex,
+    /// Logisimのメモリイメージ ("v2.0 raw")
+    Logisim,
+}
+
+impl OutputFormat {
+    /// 出力ファイルの拡張子
+    pub fn extension(&self) -> &'static str {
+        use self::OutputFormat::*;
+        match self {
+            Hack => "hack",
+            Binary => "bin",
+            Hex => "hex",
+            IntelHex => "ihex",
+            Logisim => "img",
+        }
+    }
+
+    pub fn encode(&self, words: &[u16]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(words.len() * 17);
+        // Vecへの書き込みは失敗しない
+        self.write_to(words, &mut buf).unwrap();
+        buf
+    }
+
+    /// 命令ごとの文字列を作らずに`writer`へ書き出す
+    pub fn write_to<W: Write + ?Sized>(&self, words: &[u16], writer: &mut W) -> io::Result<()> {
+        use self::OutputFormat::*;
+        match self {
+            Hack => words
+                .iter()
+                .try_for_each(|w| writer.write_all(&hack_line(*w))),
+            Binary => words
+                .iter()
+                .try_for_each(|w| writer.write_all(&w.to_be_bytes())),
+            Hex => words.iter().try_for_each(|w| writeln!(writer, "{:04x}", w)),
+            IntelHex => writer.write_all(intel_hex(words).as_bytes()),
+            Logisim => writer.write_all(logisim(words).as_bytes()),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::OutputFormat::*;
+        match self {
+            Hack => write!(f, "hack"),
+            Binary => write!(f, "bin"),
+            Hex => write!(f, "hex"),
+            IntelHex => write!(f, "ihex"),
+            Logisim => write!(f, "logisim"),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = UnknownFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::OutputFormat::*;
+        match s {
+            "hack" => Ok(Hack),
+            "bin" => Ok(Binary),
+            "hex" => Ok(Hex),
+            "ihex" => Ok(IntelHex),
+            "logisim" => Ok(Logisim),
+            _ => Err(UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+/// .hack形式の1行 ('0'/'1'の16文字と改行)
+fn hack_line(word: u16) -> [u8; 17] {
+    let mut line = [b'\n'; 17];
+    for (i, b) in line[..16].iter_mut().enumerate() {
+        *b = b'0' + (word >> (15 - i) & 1) as u8;
+    }
+    line
+}
+
+/// 1レコードあたりのデータバイト数
+const IHEX_RECORD_LEN: usize = 16;
+
+/// ROMは32Kワード = 64KBなので、拡張アドレスレコードは不要
+fn intel_hex(words: &[u16]) -> String {
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|w| w.to_be_bytes().to_vec())
+        .collect();
+    let mut buf = String::new();
+    for (i, chunk) in bytes.chunks(IHEX_RECORD_LEN).enumerate() {
+        let address = (i * IHEX_RECORD_LEN) as u16;
+        let mut record = vec![chunk.len() as u8];
+        record.extend_from_slice(&address.to_be_bytes());
+        // データレコード
+        record.push(0x00);
+        record.extend_from_slice(chunk);
+        buf.push_str(&ihex_record(&record));
+    }
+    // EOFレコード
+    buf.push_str(&ihex_record(&[0x00, 0x00, 0x00, 0x01]));
+    buf
+}
+
+fn ihex_record(record: &[u8]) -> String {
+    let sum = record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    let checksum = (!sum).wrapping_add(1);
+    let body: String = record.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(":{}{:02X}\n", body, checksum)
+}
+
+/// 1行あたりの語数
+const LOGISIM_WORDS_PER_LINE: usize = 8;
+
+/// 同じ値が続く場合は Logisim と同じく`N*value`にまとめる
+fn logisim(words: &[u16]) -> String {
+    let mut items = vec![];
+    let mut i = 0;
+    while i < words.len() {
+        let run = words[i..].iter().take_while(|w| **w == words[i]).count();
+        if run >= 4 {
+            items.push(format!("{}*{:x}", run, words[i]));
+        } else {
+            for _ in 0..run {
+                items.push(format!("{:x}", words[i]));
+            }
+        }
+        i += run;
+    }
+
+    let mut buf = String::from("v2.0 raw\n");
+    for line in items.chunks(LOGISIM_WORDS_PER_LINE) {
+        buf.push_str(&line.join(" "));
+        buf.push('\n');
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORDS: [u16; 3] = [0x0002, 0xec10, 0x0003];
+
+    #[test]
+    fn test_encode_hack() {
+        let actual = OutputFormat::Hack.encode(&WORDS);
+        let expect = "0000000000000010\n1110110000010000\n0000000000000011\n";
+        assert_eq!(String::from_utf8(actual).unwrap(), expect);
+    }
+
+    #[test]
+    fn test_encode_binary() {
+        let actual = OutputFormat::Binary.encode(&WORDS);
+        assert_eq!(actual, vec![0x00, 0x02, 0xec, 0x10, 0x00, 0x03]);
+    }
+
+    #[test]
+    fn test_encode_hex() {
+        let actual = OutputFormat::Hex.encode(&WORDS);
+        assert_eq!(String::from_utf8(actual).unwrap(), "0002\nec10\n0003\n");
+    }
+
+    #[test]
+    fn test_encode_intel_hex() {
+        let actual = OutputFormat::IntelHex.encode(&WORDS);
+        let expect = ":060000000002EC100003F9\n:00000001FF\n";
+        assert_eq!(String::from_utf8(actual).unwrap(), expect);
+
+        // 16バイトごとにレコードを分ける
+        let words = [0u16; 9];
+        let actual = String::from_utf8(OutputFormat::IntelHex.encode(&words)).unwrap();
+        let lines: Vec<&str> = actual.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with(":02001000"));
+    }
+
+    #[test]
+    fn test_encode_logisim() {
+        let words = [0x0002, 0xec10, 0, 0, 0, 0, 0x0003];
+        let actual = OutputFormat::Logisim.encode(&words);
+        let expect = "v2.0 raw\n2 ec10 4*0 3\n";
+        assert_eq!(String::from_utf8(actual).unwrap(), expect);
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert_eq!("ihex".parse(), Ok(OutputFormat::IntelHex));
+        assert_eq!(
+            "srec".parse::<OutputFormat>(),
+            Err(UnknownFormat("srec".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,282 @@
+use crate::code::Assembler;
+use crate::parser::command::*;
+use crate::parser::common::*;
+use crate::parser::lexer::{self, LexError, TriviaKind};
+use crate::parser::token::*;
+use crate::parser::{parse_all, split_lines};
+
+/// 命令とディレクティブの字下げ
+const INDENT: &str = "    ";
+/// 行末のコメントの前に最低限空ける幅
+const COMMENT_GAP: usize = 2;
+
+/// 整形後の1行
+#[derive(Debug)]
+enum Row {
+    Blank,
+    Line {
+        indent: bool,
+        code: String,
+        comment: Option<String>,
+    },
+}
+
+/// ソースを一定の書式に整える
+/// ラベルとマクロ定義の行は字下げせず、それ以外の行を字下げする
+/// C命令は正規形の表記 (`1+D` → `D+1`) に直し、行末のコメントは空行までの範囲で桁をそろえる
+/// 字句解析のエラーがあれば整形しない
+/// 入力がCRLFを含めば、CRLFで出力する
+pub fn format_source(input: &str) -> Result<String, Vec<LexError>> {
+    let (tokens, trivia, errors) = lexer::lex_with_trivia(input);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let lines = split_lines(tokens, input);
+    let mut rows = vec![];
+    // 行末のコメントを付ける行の終わりの位置
+    let mut last_end = None;
+    let (mut i, mut k) = (0, 0);
+    while i < lines.len() || k < trivia.len() {
+        let line_start = lines.get(i).map(|l| l[0].loc.start());
+        let trivia_start = trivia.get(k).map(|t| t.loc.start());
+        match (line_start, trivia_start) {
+            (Some(l), t) if t.is_none_or(|t| l < t) => {
+                let line = &lines[i];
+                let (indent, code) = format_line(line, input);
+                rows.push(Row::Line {
+                    indent,
+                    code,
+                    comment: None,
+                });
+                last_end = Some(line[line.len() - 1].loc.end());
+                i += 1;
+            }
+            _ => {
+                let t = &trivia[k];
+                match &t.value {
+                    TriviaKind::BlankLine => {
+                        rows.push(Row::Blank);
+                        last_end = None;
+                    }
+                    TriviaKind::Comment(text) => {
+                        let text = text.trim_end().replace("\r\n", "\n");
+                        let trailing =
+                            last_end.is_some_and(|end| !input[end..t.loc.start()].contains('\n'));
+                        match rows.last_mut() {
+                            Some(Row::Line { comment, .. }) if trailing => *comment = Some(text),
+                            _ => {
+                                let head = input[..t.loc.start()].rfind('\n').map_or(0, |p| p + 1);
+                                rows.push(Row::Line {
+                                    indent: head != t.loc.start(),
+                                    code: String::new(),
+                                    comment: Some(text),
+                                });
+                            }
+                        }
+                        last_end = None;
+                    }
+                    // `lex_lossless`でしか作られない
+                    TriviaKind::Whitespace(_) | TriviaKind::Skipped(_) => (),
+                }
+                k += 1;
+            }
+        }
+    }
+    let out = render(rows);
+    // 改行コードは入力に合わせる
+    if input.contains("\r\n") {
+        Ok(out.replace('\n', "\r\n"))
+    } else {
+        Ok(out)
+    }
+}
+
+/// 字下げするかどうかと、整形した行
+fn format_line(line: &[Token], src: &str) -> (bool, String) {
+    match &line[0].value {
+        TokenKind::LParen => (false, join_tokens(line, src)),
+        TokenKind::Directive(DirectiveKind::Macro) | TokenKind::Directive(DirectiveKind::Endm) => {
+            (false, join_tokens(line, src))
+        }
+        TokenKind::At | TokenKind::Directive(_) => (true, join_tokens(line, src)),
+        // マクロの呼び出しや本体の行はC命令として読めないので、そのまま並べる
+        _ => match parse_all(line.to_vec(), src) {
+            (commands, errors) if errors.is_empty() && commands.len() == 1 => {
+                match &commands[0].value {
+                    CommandKind::C(c) => (true, format_ccommand(c)),
+                    _ => (true, join_tokens(line, src)),
+                }
+            }
+            _ => (true, join_tokens(line, src)),
+        },
+    }
+}
+
+fn format_ccommand(c: &CompCommand) -> String {
+    let mut s = String::new();
+    if let Some(dest) = &c.dest {
+        s.push_str(dest.mnemonic());
+        s.push('=');
+    }
+    match Assembler::canonical_comp(&c.comp.value) {
+        Some(comp) => s.push_str(comp),
+        None => s.push_str(&c.comp.value.to_string()),
+    }
+    if let Some(jump) = &c.jump {
+        s.push(';');
+        s.push_str(&jump.to_string());
+    }
+    s
+}
+
+/// トークンを元の綴りのまま並べる
+/// 空白を入れるのは名前や数値が続くところと`,`の後だけ
+fn join_tokens(line: &[Token], src: &str) -> String {
+    let is_word = |t: &TokenKind| {
+        matches!(
+            t,
+            TokenKind::Number(_)
+                | TokenKind::Mem(_)
+                | TokenKind::Jump(_)
+                | TokenKind::Symbol(_)
+                | TokenKind::Directive(_)
+                | TokenKind::Str(_)
+        )
+    };
+    let mut s = String::new();
+    for (i, tok) in line.iter().enumerate() {
+        if i > 0 {
+            let prev = &line[i - 1].value;
+            let space = match prev {
+                TokenKind::Comma => true,
+                _ => is_word(prev) && (is_word(&tok.value) || tok.value == TokenKind::LParen),
+            };
+            if space {
+                s.push(' ');
+            }
+        }
+        s.push_str(&src[tok.loc.start()..tok.loc.end()]);
+    }
+    s
+}
+
+fn render(rows: Vec<Row>) -> String {
+    let width = |row: &Row| match row {
+        Row::Line { indent, code, .. } if !code.is_empty() => {
+            code.len() + if *indent { INDENT.len() } else { 0 }
+        }
+        _ => 0,
+    };
+
+    let mut out = String::new();
+    // 空行で区切られた段落ごとに、行末のコメントの桁をそろえる
+    for para in rows.split(|r| matches!(r, Row::Blank)) {
+        if para.is_empty() {
+            continue;
+        }
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        let column = para
+            .iter()
+            .filter(|r| {
+                matches!(
+                    r,
+                    Row::Line {
+                        comment: Some(_),
+                        ..
+                    }
+                )
+            })
+            .map(width)
+            .max()
+            .unwrap_or(0);
+        for row in para {
+            if let Row::Line {
+                indent,
+                code,
+                comment,
+            } = row
+            {
+                let mut line = String::new();
+                if *indent {
+                    line.push_str(INDENT);
+                }
+                line.push_str(code);
+                if let Some(comment) = comment {
+                    if !code.is_empty() {
+                        let pad = column - line.len() + COMMENT_GAP;
+                        line.push_str(&" ".repeat(pad));
+                    }
+                    line.push_str(comment);
+                }
+                out.push_str(&line);
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_source() {
+        let input = r###"
+
+// Adds 1 + ... + 100
+   @i
+M=1   // i=1
+(LOOP)
+  @ i
+  D = M    ;   JGT // jump
+    D=1+D
+AM=M&D
+
+
+// macro
+.macro INC x
+@x
+M=M+1
+.endm
+  .equ  N  (SCREEN + 1)
+INC N, 'a'
+"###;
+        let expect = r###"// Adds 1 + ... + 100
+    @i
+    M=1      // i=1
+(LOOP)
+    @i
+    D=M;JGT  // jump
+    D=D+1
+    AM=D&M
+
+// macro
+.macro INC x
+    @x
+    M=M+1
+.endm
+    .equ N (SCREEN+1)
+    INC N, 'a'
+"###;
+        let actual = format_source(input).unwrap();
+        assert_eq!(actual, expect);
+        // 整形済みなら変わらない
+        assert_eq!(format_source(&actual).unwrap(), actual);
+    }
+
+    #[test]
+    fn test_format_source_crlf() {
+        let input = "(LOOP)\r\n@i  /* i */\r\n\r\n\r\nM=1+M\r\n";
+        let expect = "(LOOP)\r\n    @i  /* i */\r\n\r\n    M=M+1\r\n";
+        assert_eq!(format_source(input).unwrap(), expect);
+    }
+
+    #[test]
+    fn test_format_source_lex_error() {
+        assert!(format_source("@1\nD=%\n").is_err());
+    }
+}
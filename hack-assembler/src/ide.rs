@@ -0,0 +1,394 @@
+use crate::instruction::{Comp, Dest, Jump};
+use crate::parser::common::*;
+use crate::parser::lexer;
+use crate::parser::split_lines;
+use crate::parser::token::*;
+use crate::source::SourceMap;
+use crate::sysmbol_table::*;
+
+use std::collections::HashSet;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RenameError {
+    #[error("no symbol at the cursor")]
+    NoSymbol,
+    #[error("predefined symbol `{0}` cannot be renamed")]
+    Predefined(String),
+    #[error("`{0}` is not a valid symbol name")]
+    InvalidName(String),
+    #[error("`{0}` is already defined")]
+    AlreadyDefined(String),
+    #[error("`{old}` cannot be renamed to `{new}` (a local label must stay local and vice versa)")]
+    LocalityChanged { old: String, new: String },
+}
+
+/// シンボルの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    /// ROMアドレス
+    Label,
+    /// 自動で割り当てたRAMアドレスか、`.var`のブロック
+    Variable,
+    /// `.equ`で定義した定数
+    Constant,
+    /// `SP`, `R0`..`R15`, `SCREEN`, `KBD`など
+    Predefined,
+    Macro,
+}
+
+/// ファイル中のシンボルの出現
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Occurrence {
+    /// ソースに書かれた名前
+    pub text: String,
+    /// スコープで修飾した名前
+    pub name: String,
+    pub loc: Loc,
+    pub is_definition: bool,
+}
+
+/// 補完の候補
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub label: String,
+    /// シンボルでなければ`None` (ニーモニックと指令)
+    pub kind: Option<SymbolKind>,
+    pub detail: String,
+}
+
+/// エディタ向けに、1つのファイルのシンボルの出現と解決したアドレスをまとめたもの
+/// 位置はすべてファイル先頭からのバイト位置
+pub struct SourceIndex {
+    occurrences: Vec<Occurrence>,
+    macros: HashSet<String>,
+    /// (開始位置, スコープ) の位置順
+    scopes: Vec<(usize, String)>,
+    sym_table: SymbolTable,
+}
+
+impl SourceIndex {
+    /// エラーがあっても分かる範囲で集める
+    /// アドレスは`.include`とマクロを展開して解決する
+    pub fn new(text: &str, path: Option<&Path>) -> Self {
+        let mut sources = SourceMap::new(text, path);
+        let (commands, _) = sources.parse();
+        let mut sym_table = SymbolTable::new();
+        let _ = sym_table.resolve(&commands);
+
+        let mut index = Self {
+            occurrences: vec![],
+            macros: HashSet::new(),
+            scopes: vec![(0, String::new())],
+            sym_table,
+        };
+        index.collect(text);
+        index
+    }
+
+    fn collect(&mut self, text: &str) {
+        let (tokens, _) = lexer::lex_all(text);
+        let mut scope = String::new();
+        // マクロ本体の中なら、その仮引数
+        let mut params: Option<Vec<String>> = None;
+        for line in split_lines(tokens, text) {
+            let mut defined = None;
+            match (&line[0].value, line.get(1).map(|t| &t.value)) {
+                (TokenKind::LParen, Some(TokenKind::Symbol(label))) => {
+                    defined = Some(1);
+                    if !is_local(label) {
+                        scope = label.clone();
+                        self.scopes.push((line[0].loc.start(), scope.clone()));
+                    }
+                }
+                (TokenKind::Directive(DirectiveKind::Macro), Some(TokenKind::Symbol(name))) => {
+                    self.macros.insert(name.clone());
+                    self.push(name, &scope, &line[1].loc, true);
+                    params = Some(
+                        line[2..]
+                            .iter()
+                            .filter_map(|t| match &t.value {
+                                TokenKind::Symbol(s) => Some(s.clone()),
+                                _ => None,
+                            })
+                            .collect(),
+                    );
+                    continue;
+                }
+                (TokenKind::Directive(DirectiveKind::Endm), _) => {
+                    params = None;
+                    continue;
+                }
+                (TokenKind::Directive(DirectiveKind::Equ), Some(TokenKind::Symbol(_)))
+                | (TokenKind::Directive(DirectiveKind::Var), Some(TokenKind::Symbol(_))) => {
+                    defined = Some(1)
+                }
+                _ => (),
+            }
+            for (i, tok) in line.iter().enumerate() {
+                if let TokenKind::Symbol(s) = &tok.value {
+                    if params.as_ref().is_some_and(|p| p.contains(s)) {
+                        continue;
+                    }
+                    self.push(s, &scope, &tok.loc, defined == Some(i));
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, text: &str, scope: &str, loc: &Loc, is_definition: bool) {
+        self.occurrences.push(Occurrence {
+            text: text.to_owned(),
+            name: qualify(text, scope),
+            loc: loc.clone(),
+            is_definition,
+        });
+    }
+
+    /// `offset`にあるシンボル
+    pub fn occurrence_at(&self, offset: usize) -> Option<&Occurrence> {
+        // 名前の直後にカーソルがある場合も含める
+        self.occurrences
+            .iter()
+            .find(|o| o.loc.start() <= offset && offset <= o.loc.end())
+    }
+
+    /// このファイルでの定義の位置
+    pub fn definition(&self, name: &str) -> Option<&Loc> {
+        self.occurrences
+            .iter()
+            .find(|o| o.is_definition && o.name == name)
+            .map(|o| &o.loc)
+    }
+
+    /// このファイルでの出現の位置 (定義を含めるかを選べる)
+    pub fn references(&self, name: &str, include_definition: bool) -> Vec<&Loc> {
+        self.occurrences
+            .iter()
+            .filter(|o| o.name == name && (include_definition || !o.is_definition))
+            .map(|o| &o.loc)
+            .collect()
+    }
+
+    pub fn kind(&self, name: &str) -> Option<SymbolKind> {
+        if self.macros.contains(name) {
+            Some(SymbolKind::Macro)
+        } else if self.sym_table.predefined().contains_key(name) {
+            Some(SymbolKind::Predefined)
+        } else if self.sym_table.labels().contains_key(name) {
+            Some(SymbolKind::Label)
+        } else if self.sym_table.constants().contains_key(name) {
+            Some(SymbolKind::Constant)
+        } else if self.sym_table.variables().contains_key(name) {
+            Some(SymbolKind::Variable)
+        } else {
+            None
+        }
+    }
+
+    /// シンボルが何を指すかの説明 (Markdown)
+    pub fn hover(&self, name: &str) -> Option<String> {
+        let kind = self.kind(name)?;
+        let address = self.sym_table.get_address(name).copied();
+        let text = match (kind, address) {
+            (SymbolKind::Macro, _) => format!("macro `{}`", name),
+            (SymbolKind::Predefined, Some(a)) => {
+                let note = match name {
+                    "SCREEN" => " (screen memory map)",
+                    "KBD" => " (keyboard memory map)",
+                    _ => "",
+                };
+                format!("predefined symbol `{}`: RAM[{}]{}", name, a, note)
+            }
+            (SymbolKind::Label, Some(a)) => format!("ROM label `{}`: ROM[{}]", name, a),
+            (SymbolKind::Constant, Some(a)) => format!("constant `{}` = {}", name, a),
+            (SymbolKind::Variable, Some(a)) => format!("RAM variable `{}`: RAM[{}]", name, a),
+            _ => return None,
+        };
+        Some(text)
+    }
+
+    /// `offset`のシンボルを`new_name`に書き換える位置
+    pub fn rename(&self, offset: usize, new_name: &str) -> Result<Vec<&Loc>, RenameError> {
+        let occ = self.occurrence_at(offset).ok_or(RenameError::NoSymbol)?;
+        if self.kind(&occ.name) == Some(SymbolKind::Predefined) {
+            return Err(RenameError::Predefined(occ.name.clone()));
+        }
+        match lexer::lex(new_name).as_deref() {
+            Ok(
+                [Token {
+                    value: TokenKind::Symbol(s),
+                    ..
+                }],
+            ) if s == new_name => (),
+            _ => return Err(RenameError::InvalidName(new_name.to_owned())),
+        }
+        if is_local(&occ.text) != is_local(new_name) {
+            return Err(RenameError::LocalityChanged {
+                old: occ.text.clone(),
+                new: new_name.to_owned(),
+            });
+        }
+        let scope = &occ.name[..occ.name.len() - occ.text.len()];
+        let renamed = qualify(new_name, scope);
+        if self.kind(&renamed).is_some() || self.definition(&renamed).is_some() {
+            return Err(RenameError::AlreadyDefined(new_name.to_owned()));
+        }
+        Ok(self.references(&occ.name, true))
+    }
+
+    /// `offset`で使えるシンボル、ニーモニック、指令
+    /// ローカルラベルは`offset`のスコープのものだけ
+    pub fn completions(&self, offset: usize) -> Vec<Completion> {
+        let scope = self
+            .scopes
+            .iter()
+            .take_while(|(start, _)| *start <= offset)
+            .last()
+            .map_or("", |(_, s)| s.as_str());
+
+        let mut seen = HashSet::new();
+        let mut items = vec![];
+        let mut push = |label: &str, kind, detail: String| {
+            if seen.insert(label.to_owned()) {
+                items.push(Completion {
+                    label: label.to_owned(),
+                    kind,
+                    detail,
+                });
+            }
+        };
+
+        let mut names: Vec<&String> = self
+            .sym_table
+            .predefined()
+            .keys()
+            .chain(self.sym_table.global_labels().map(|(l, _)| l))
+            .chain(self.sym_table.constants().keys())
+            .chain(self.sym_table.variables().keys())
+            .chain(self.macros.iter())
+            .collect();
+        names.sort();
+        for name in names {
+            // マクロ内のラベルは展開ごとに名前が変わる
+            if name.contains('$') {
+                continue;
+            }
+            let detail = self.hover(name).unwrap_or_default();
+            push(name, self.kind(name), detail);
+        }
+        for o in self.occurrences.iter() {
+            if is_local(&o.text) && o.is_definition && o.name == qualify(&o.text, scope) {
+                let detail = self.hover(&o.name).unwrap_or_default();
+                push(&o.text, self.kind(&o.name), detail);
+            }
+        }
+
+        for c in Comp::ALL.iter() {
+            push(c.mnemonic(), None, "comp".to_owned());
+        }
+        for d in Dest::ALL.iter().filter(|d| **d != Dest::Null) {
+            push(d.mnemonic(), None, "dest".to_owned());
+        }
+        for j in Jump::ALL.iter().filter(|j| **j != Jump::Null) {
+            push(j.mnemonic(), None, "jump".to_owned());
+        }
+        for d in [
+            ".equ", ".include", ".org", ".var", ".extern", ".macro", ".endm",
+        ]
+        .iter()
+        {
+            push(d, None, "directive".to_owned());
+        }
+        items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = r###".equ N 5
+@i
+M=0
+(Main)
+(.loop)
+@i
+D=M
+@N
+D=D-A
+@.loop
+D;JLT
+@SCREEN
+(Sub)
+(.loop)
+@.loop
+0;JMP
+"###;
+
+    #[test]
+    fn test_definition_and_references() {
+        let index = SourceIndex::new(INPUT, None);
+        // `@.loop` (Main)
+        let offset = INPUT.find("@.loop").unwrap() + 1;
+        let occ = index.occurrence_at(offset).unwrap();
+        assert_eq!(occ.name, "Main.loop");
+        let def = index.definition(&occ.name).unwrap();
+        assert_eq!(def.start(), INPUT.find("(.loop)").unwrap() + 1);
+        assert_eq!(index.references("Main.loop", true).len(), 2);
+        assert_eq!(index.references("Main.loop", false).len(), 1);
+        assert_eq!(index.references("i", true).len(), 2);
+    }
+
+    #[test]
+    fn test_hover() {
+        let index = SourceIndex::new(INPUT, None);
+        assert_eq!(index.hover("Main").unwrap(), "ROM label `Main`: ROM[2]");
+        assert_eq!(index.hover("i").unwrap(), "RAM variable `i`: RAM[16]");
+        assert_eq!(index.hover("N").unwrap(), "constant `N` = 5");
+        assert_eq!(
+            index.hover("SCREEN").unwrap(),
+            "predefined symbol `SCREEN`: RAM[16384] (screen memory map)"
+        );
+        assert_eq!(index.hover("undefined"), None);
+    }
+
+    #[test]
+    fn test_rename() {
+        let index = SourceIndex::new(INPUT, None);
+        let offset = INPUT.find("@i").unwrap() + 1;
+        assert_eq!(index.rename(offset, "count").unwrap().len(), 2);
+        assert_eq!(
+            index.rename(offset, "N"),
+            Err(RenameError::AlreadyDefined("N".to_owned()))
+        );
+        assert_eq!(
+            index.rename(offset, "D"),
+            Err(RenameError::InvalidName("D".to_owned()))
+        );
+        assert!(matches!(
+            index.rename(offset, ".i"),
+            Err(RenameError::LocalityChanged { .. })
+        ));
+        let offset = INPUT.find("@SCREEN").unwrap() + 1;
+        assert_eq!(
+            index.rename(offset, "S"),
+            Err(RenameError::Predefined("SCREEN".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_completions() {
+        let index = SourceIndex::new(INPUT, None);
+        let offset = INPUT.find("D;JLT").unwrap();
+        let items = index.completions(offset);
+        let has = |label: &str| items.iter().any(|c| c.label == label);
+        assert!(has("R13") && has("Main") && has("N") && has("i"));
+        assert!(has("D+1") && has("AMD") && has("JLT") && has(".equ"));
+        // スコープの違うローカルラベルは1つにまとまる
+        assert_eq!(items.iter().filter(|c| c.label == ".loop").count(), 1);
+        let local = items.iter().find(|c| c.label == ".loop").unwrap();
+        assert_eq!(local.detail, "ROM label `Main.loop`: ROM[2]");
+    }
+}
@@ -0,0 +1,498 @@
+use crate::types::Address;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum InstructionError {
+    #[error("`@{0}` does not fit in 15 bits (0..=32767)")]
+    AddressOverflow(Address),
+    #[error("invalid C-instruction prefix in {0:016b}")]
+    InvalidPrefix(u16),
+    #[error("unknown comp bits {bits:07b} in {word:016b}")]
+    InvalidComp { word: u16, bits: u16 },
+    #[error("unknown mnemonic `{0}`")]
+    UnknownMnemonic(String),
+}
+
+/// A命令で指定できる最大値 (15bit)
+pub const MAX_A_VALUE: Address = 0x7fff;
+
+/// 保存先 (d1 d2 d3 = A D M)
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Dest {
+    Null,
+    M,
+    D,
+    MD,
+    A,
+    AM,
+    AD,
+    AMD,
+}
+
+impl Dest {
+    pub const ALL: [Dest; 8] = [
+        Dest::Null,
+        Dest::M,
+        Dest::D,
+        Dest::MD,
+        Dest::A,
+        Dest::AM,
+        Dest::AD,
+        Dest::AMD,
+    ];
+
+    pub fn bits(self) -> u16 {
+        self as u16
+    }
+
+    pub fn from_bits(bits: u16) -> Self {
+        Self::ALL[(bits & 0x7) as usize]
+    }
+
+    /// アセンブリ上の表記 (`Null`は空文字列)
+    pub fn mnemonic(self) -> &'static str {
+        use self::Dest::*;
+        match self {
+            Null => "",
+            M => "M",
+            D => "D",
+            MD => "MD",
+            A => "A",
+            AM => "AM",
+            AD => "AD",
+            AMD => "AMD",
+        }
+    }
+}
+
+/// 計算 (a c1..c6 の7bit)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Comp {
+    Zero,
+    One,
+    MinusOne,
+    D,
+    A,
+    M,
+    NotD,
+    NotA,
+    NotM,
+    NegD,
+    NegA,
+    NegM,
+    DPlusOne,
+    APlusOne,
+    MPlusOne,
+    DMinusOne,
+    AMinusOne,
+    MMinusOne,
+    DPlusA,
+    DPlusM,
+    DMinusA,
+    DMinusM,
+    AMinusD,
+    MMinusD,
+    DAndA,
+    DAndM,
+    DOrA,
+    DOrM,
+}
+
+impl Comp {
+    pub const ALL: [Comp; 28] = [
+        Comp::Zero,
+        Comp::One,
+        Comp::MinusOne,
+        Comp::D,
+        Comp::A,
+        Comp::M,
+        Comp::NotD,
+        Comp::NotA,
+        Comp::NotM,
+        Comp::NegD,
+        Comp::NegA,
+        Comp::NegM,
+        Comp::DPlusOne,
+        Comp::APlusOne,
+        Comp::MPlusOne,
+        Comp::DMinusOne,
+        Comp::AMinusOne,
+        Comp::MMinusOne,
+        Comp::DPlusA,
+        Comp::DPlusM,
+        Comp::DMinusA,
+        Comp::DMinusM,
+        Comp::AMinusD,
+        Comp::MMinusD,
+        Comp::DAndA,
+        Comp::DAndM,
+        Comp::DOrA,
+        Comp::DOrM,
+    ];
+
+    pub fn bits(self) -> u16 {
+        use self::Comp::*;
+        match self {
+            Zero => 0b0101010,
+            One => 0b0111111,
+            MinusOne => 0b0111010,
+            D => 0b0001100,
+            A => 0b0110000,
+            M => 0b1110000,
+            NotD => 0b0001101,
+            NotA => 0b0110001,
+            NotM => 0b1110001,
+            NegD => 0b0001111,
+            NegA => 0b0110011,
+            NegM => 0b1110011,
+            DPlusOne => 0b0011111,
+            APlusOne => 0b0110111,
+            MPlusOne => 0b1110111,
+            DMinusOne => 0b0001110,
+            AMinusOne => 0b0110010,
+            MMinusOne => 0b1110010,
+            DPlusA => 0b0000010,
+            DPlusM => 0b1000010,
+            DMinusA => 0b0010011,
+            DMinusM => 0b1010011,
+            AMinusD => 0b0000111,
+            MMinusD => 0b1000111,
+            DAndA => 0b0000000,
+            DAndM => 0b1000000,
+            DOrA => 0b0010101,
+            DOrM => 0b1010101,
+        }
+    }
+
+    pub fn from_bits(bits: u16) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| c.bits() == bits)
+    }
+
+    /// 正規形の表記 (`D`が左、定数が右)
+    pub fn mnemonic(self) -> &'static str {
+        use self::Comp::*;
+        match self {
+            Zero => "0",
+            One => "1",
+            MinusOne => "-1",
+            D => "D",
+            A => "A",
+            M => "M",
+            NotD => "!D",
+            NotA => "!A",
+            NotM => "!M",
+            NegD => "-D",
+            NegA => "-A",
+            NegM => "-M",
+            DPlusOne => "D+1",
+            APlusOne => "A+1",
+            MPlusOne => "M+1",
+            DMinusOne => "D-1",
+            AMinusOne => "A-1",
+            MMinusOne => "M-1",
+            DPlusA => "D+A",
+            DPlusM => "D+M",
+            DMinusA => "D-A",
+            DMinusM => "D-M",
+            AMinusD => "A-D",
+            MMinusD => "M-D",
+            DAndA => "D&A",
+            DAndM => "D&M",
+            DOrA => "D|A",
+            DOrM => "D|M",
+        }
+    }
+}
+
+/// ジャンプ条件
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Jump {
+    Null,
+    JGT,
+    JEQ,
+    JGE,
+    JLT,
+    JNE,
+    JLE,
+    JMP,
+}
+
+impl Jump {
+    pub const ALL: [Jump; 8] = [
+        Jump::Null,
+        Jump::JGT,
+        Jump::JEQ,
+        Jump::JGE,
+        Jump::JLT,
+        Jump::JNE,
+        Jump::JLE,
+        Jump::JMP,
+    ];
+
+    pub fn bits(self) -> u16 {
+        self as u16
+    }
+
+    pub fn from_bits(bits: u16) -> Self {
+        Self::ALL[(bits & 0x7) as usize]
+    }
+
+    /// アセンブリ上の表記 (`Null`は空文字列)
+    pub fn mnemonic(self) -> &'static str {
+        use self::Jump::*;
+        match self {
+            Null => "",
+            JGT => "JGT",
+            JEQ => "JEQ",
+            JGE => "JGE",
+            JLT => "JLT",
+            JNE => "JNE",
+            JLE => "JLE",
+            JMP => "JMP",
+        }
+    }
+}
+
+macro_rules! impl_mnemonic_traits {
+    ($($ty:ident),*) => {$(
+        impl fmt::Display for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self.mnemonic())
+            }
+        }
+
+        impl FromStr for $ty {
+            type Err = InstructionError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Self::ALL
+                    .iter()
+                    .copied()
+                    .find(|x| x.mnemonic() == s)
+                    .ok_or_else(|| InstructionError::UnknownMnemonic(s.to_owned()))
+            }
+        }
+    )*};
+}
+
+impl_mnemonic_traits!(Dest, Comp, Jump);
+
+/// Hackの機械語1命令
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    /// `@value`
+    A(Address),
+    /// `dest=comp;jump`
+    C { dest: Dest, comp: Comp, jump: Jump },
+}
+
+impl Instruction {
+    /// `@value` (15bitに収まらなければエラー)
+    pub fn a(value: Address) -> Result<Self, InstructionError> {
+        if value > MAX_A_VALUE {
+            return Err(InstructionError::AddressOverflow(value));
+        }
+        Ok(Instruction::A(value))
+    }
+
+    /// C命令のビルダー (dest, jumpは省略するとnull)
+    pub fn c(comp: Comp) -> CBuilder {
+        CBuilder {
+            dest: Dest::Null,
+            comp,
+            jump: Jump::Null,
+        }
+    }
+
+    pub fn encode(&self) -> Result<u16, InstructionError> {
+        match *self {
+            Instruction::A(value) if value > MAX_A_VALUE => {
+                Err(InstructionError::AddressOverflow(value))
+            }
+            Instruction::A(value) => Ok(value),
+            Instruction::C { dest, comp, jump } => {
+                Ok(0xe000 | comp.bits() << 6 | dest.bits() << 3 | jump.bits())
+            }
+        }
+    }
+
+    pub fn decode(word: u16) -> Result<Self, InstructionError> {
+        if word & 0x8000 == 0 {
+            return Ok(Instruction::A(word));
+        }
+        if word & 0xe000 != 0xe000 {
+            return Err(InstructionError::InvalidPrefix(word));
+        }
+        let bits = (word >> 6) & 0x7f;
+        let comp = Comp::from_bits(bits).ok_or(InstructionError::InvalidComp { word, bits })?;
+        Ok(Instruction::C {
+            dest: Dest::from_bits(word >> 3),
+            comp,
+            jump: Jump::from_bits(word),
+        })
+    }
+}
+
+impl TryFrom<u16> for Instruction {
+    type Error = InstructionError;
+
+    fn try_from(word: u16) -> Result<Self, Self::Error> {
+        Instruction::decode(word)
+    }
+}
+
+impl TryFrom<Instruction> for u16 {
+    type Error = InstructionError;
+
+    fn try_from(instr: Instruction) -> Result<Self, Self::Error> {
+        instr.encode()
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::A(value) => write!(f, "@{}", value),
+            Instruction::C { dest, comp, jump } => {
+                if *dest != Dest::Null {
+                    write!(f, "{}=", dest)?;
+                }
+                write!(f, "{}", comp)?;
+                if *jump != Jump::Null {
+                    write!(f, ";{}", jump)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// `Instruction::c`から始めるC命令のビルダー
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CBuilder {
+    dest: Dest,
+    comp: Comp,
+    jump: Jump,
+}
+
+impl CBuilder {
+    pub fn dest(mut self, dest: Dest) -> Self {
+        self.dest = dest;
+        self
+    }
+
+    pub fn jump(mut self, jump: Jump) -> Self {
+        self.jump = jump;
+        self
+    }
+
+    pub fn build(self) -> Instruction {
+        Instruction::C {
+            dest: self.dest,
+            comp: self.comp,
+            jump: self.jump,
+        }
+    }
+}
+
+impl From<CBuilder> for Instruction {
+    fn from(builder: CBuilder) -> Self {
+        builder.build()
+    }
+}
+
+/// テキストを介さずにアセンブルするためのプログラムの1要素
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Statement {
+    /// `(LABEL)`
+    Label(String),
+    /// `@symbol` (ラベル・定義済みシンボル・変数)
+    Symbol(String),
+    Instr(Instruction),
+}
+
+impl From<Instruction> for Statement {
+    fn from(instr: Instruction) -> Self {
+        Statement::Instr(instr)
+    }
+}
+
+impl From<CBuilder> for Statement {
+    fn from(builder: CBuilder) -> Self {
+        Statement::Instr(builder.build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instruction_roundtrip() {
+        // A命令とエンコード可能なすべてのC命令
+        let mut words: Vec<u16> = vec![0, 1, 0x4000, MAX_A_VALUE];
+        for comp in Comp::ALL.iter() {
+            for dest in Dest::ALL.iter() {
+                for jump in Jump::ALL.iter() {
+                    words.push(0xe000 | comp.bits() << 6 | dest.bits() << 3 | jump.bits());
+                }
+            }
+        }
+        for word in words {
+            let instr = Instruction::try_from(word).unwrap();
+            assert_eq!(u16::try_from(instr), Ok(word));
+        }
+    }
+
+    #[test]
+    fn test_instruction_builder() {
+        let actual = Instruction::c(Comp::DMinusM).dest(Dest::D).build();
+        assert_eq!(actual.encode(), Ok(0b1111010011010000));
+        assert_eq!(actual.to_string(), "D=D-M");
+
+        let actual: Instruction = Instruction::c(Comp::D).jump(Jump::JGT).into();
+        assert_eq!(actual.encode(), Ok(0b1110001100000001));
+        assert_eq!(actual.to_string(), "D;JGT");
+
+        assert_eq!(
+            Instruction::a(0x8000),
+            Err(InstructionError::AddressOverflow(0x8000))
+        );
+        assert_eq!(
+            Instruction::A(0x8000).encode(),
+            Err(InstructionError::AddressOverflow(0x8000))
+        );
+    }
+
+    #[test]
+    fn test_instruction_decode_error() {
+        assert_eq!(
+            Instruction::decode(0b1000000000000000),
+            Err(InstructionError::InvalidPrefix(0b1000000000000000))
+        );
+        assert_eq!(
+            Instruction::decode(0b1111111111000000),
+            Err(InstructionError::InvalidComp {
+                word: 0b1111111111000000,
+                bits: 0b1111111
+            })
+        );
+    }
+
+    #[test]
+    fn test_mnemonic_from_str() {
+        assert_eq!("AMD".parse(), Ok(Dest::AMD));
+        assert_eq!("D|M".parse(), Ok(Comp::DOrM));
+        assert_eq!("JMP".parse(), Ok(Jump::JMP));
+        assert_eq!(
+            "M+D".parse::<Comp>(),
+            Err(InstructionError::UnknownMnemonic("M+D".to_owned()))
+        );
+    }
+}
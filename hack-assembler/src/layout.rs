@@ -0,0 +1,294 @@
+use crate::sysmbol_table::{SymbolTable, AVAILABLE_ADDRESS_END, VARIABLE_BASE};
+use crate::types::Address;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LayoutError {
+    #[error("invalid address `{0}` (decimal or 0x hex)")]
+    InvalidNumber(String),
+    #[error("invalid range `{0}` (expected START..END or START..=END)")]
+    InvalidRange(String),
+    #[error("invalid fixed symbol `{0}` (expected NAME=ADDRESS)")]
+    InvalidFixed(String),
+    #[error("unknown entry `{0}` (expected base, reserve or fixed)")]
+    UnknownEntry(String),
+    #[error(
+        "variable base {0} is outside the RAM for variables (0..{})",
+        AVAILABLE_ADDRESS_END
+    )]
+    BaseOutOfRange(Address),
+    #[error(
+        "reserved range {0} is empty or outside the RAM for variables (0..{})",
+        AVAILABLE_ADDRESS_END
+    )]
+    RangeOutOfRange(RamRange),
+    #[error(
+        "fixed symbol `{name}` at {address} is outside the RAM for variables (0..{})",
+        AVAILABLE_ADDRESS_END
+    )]
+    FixedOutOfRange { name: String, address: Address },
+    #[error("fixed symbol `{0}` conflicts with a predefined symbol")]
+    PredefinedFixed(String),
+    #[error("fixed symbol `{0}` is given more than once")]
+    DuplicateFixed(String),
+}
+
+/// レイアウトファイルの読み込みエラー
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+#[error("line {line}: {kind}")]
+pub struct LayoutFileError {
+    pub line: usize,
+    pub kind: LayoutError,
+}
+
+/// 10進数か`0x`で始まる16進数
+pub fn parse_address(s: &str) -> Result<Address, LayoutError> {
+    let res = match s.strip_prefix("0x") {
+        Some(hex) => Address::from_str_radix(hex, 16),
+        None => s.parse::<Address>(),
+    };
+    res.map_err(|_| LayoutError::InvalidNumber(s.to_owned()))
+}
+
+/// RAMの区間 [start, end)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RamRange {
+    pub start: Address,
+    pub end: Address,
+}
+
+impl RamRange {
+    pub fn contains(&self, address: Address) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+
+    pub fn overlaps(&self, start: Address, end: Address) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+impl fmt::Display for RamRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+/// `START..END` (終端を含まない) か `START..=END` (終端を含む)
+impl FromStr for RamRange {
+    type Err = LayoutError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once("..")
+            .ok_or_else(|| LayoutError::InvalidRange(s.to_owned()))?;
+        let start = parse_address(start.trim())?;
+        let end = match end.strip_prefix('=') {
+            Some(last) => parse_address(last.trim())?
+                .checked_add(1)
+                .ok_or_else(|| LayoutError::InvalidNumber(last.to_owned()))?,
+            None => parse_address(end.trim())?,
+        };
+        Ok(Self { start, end })
+    }
+}
+
+/// アドレスを固定するシンボル (`NAME=ADDRESS`)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FixedSymbol {
+    pub name: String,
+    pub address: Address,
+}
+
+impl FromStr for FixedSymbol {
+    type Err = LayoutError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, address) = s
+            .split_once('=')
+            .filter(|(name, _)| !name.trim().is_empty())
+            .ok_or_else(|| LayoutError::InvalidFixed(s.to_owned()))?;
+        Ok(Self {
+            name: name.trim().to_owned(),
+            address: parse_address(address.trim())?,
+        })
+    }
+}
+
+/// 変数のRAM配置
+/// 自動で割り当てる変数は`variable_base`から、予約した区間と固定したアドレスを避けて置く
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RamLayout {
+    /// 変数の割り当て開始アドレス
+    pub variable_base: Address,
+    /// 変数を置かない区間
+    pub reserved: Vec<RamRange>,
+    /// アドレスを固定するシンボル
+    pub fixed: Vec<FixedSymbol>,
+}
+
+impl Default for RamLayout {
+    fn default() -> Self {
+        Self {
+            variable_base: VARIABLE_BASE,
+            reserved: vec![],
+            fixed: vec![],
+        }
+    }
+}
+
+impl RamLayout {
+    /// 範囲外のアドレスや、定義済みシンボル・重複した名前の固定を検査する
+    pub fn validate(&self) -> Result<(), Vec<LayoutError>> {
+        let mut errors = vec![];
+        if self.variable_base >= AVAILABLE_ADDRESS_END {
+            errors.push(LayoutError::BaseOutOfRange(self.variable_base));
+        }
+        for range in self.reserved.iter() {
+            if range.start >= range.end || range.end > AVAILABLE_ADDRESS_END {
+                errors.push(LayoutError::RangeOutOfRange(*range));
+            }
+        }
+        let predefined = SymbolTable::new();
+        let mut names = HashSet::new();
+        for FixedSymbol { name, address } in self.fixed.iter() {
+            if *address >= AVAILABLE_ADDRESS_END {
+                errors.push(LayoutError::FixedOutOfRange {
+                    name: name.clone(),
+                    address: *address,
+                });
+            }
+            if predefined.predefined().contains_key(name) {
+                errors.push(LayoutError::PredefinedFixed(name.clone()));
+            } else if !names.insert(name.as_str()) {
+                errors.push(LayoutError::DuplicateFixed(name.clone()));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// 自動で割り当てる変数が避ける区間 (固定したアドレスを含む)
+    pub(crate) fn avoided(&self) -> Vec<RamRange> {
+        let fixed = self.fixed.iter().map(|f| RamRange {
+            start: f.address,
+            end: f.address.saturating_add(1),
+        });
+        self.reserved.iter().copied().chain(fixed).collect()
+    }
+}
+
+/// レイアウトファイル
+/// 1行に1項目で、`#`から行末まではコメント
+///
+/// ```text
+/// base 0x100
+/// reserve 0x20..0x40
+/// fixed os_flag 0x1000
+/// ```
+impl FromStr for RamLayout {
+    type Err = LayoutFileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut layout = RamLayout::default();
+        for (i, text) in s.lines().enumerate() {
+            let text = text.split('#').next().unwrap().trim();
+            let err = |kind| LayoutFileError { line: i + 1, kind };
+            let fields: Vec<&str> = text.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => (),
+                ["base", address] => layout.variable_base = parse_address(address).map_err(err)?,
+                ["reserve", range] => layout.reserved.push(range.parse().map_err(err)?),
+                ["fixed", name, address] => layout.fixed.push(FixedSymbol {
+                    name: name.to_string(),
+                    address: parse_address(address).map_err(err)?,
+                }),
+                _ => return Err(err(LayoutError::UnknownEntry(text.to_owned()))),
+            }
+        }
+        Ok(layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_layout() {
+        let input = "# OSと共有する\nbase 0x100\nreserve 0x20..0x40   # 作業領域\nreserve 300..=309\n\nfixed os_flag 0x1000\n";
+        let layout: RamLayout = input.parse().unwrap();
+        assert_eq!(layout.variable_base, 0x100);
+        assert_eq!(
+            layout.reserved,
+            vec![
+                RamRange {
+                    start: 0x20,
+                    end: 0x40
+                },
+                RamRange {
+                    start: 300,
+                    end: 310
+                },
+            ]
+        );
+        assert_eq!(
+            layout.fixed,
+            vec![FixedSymbol {
+                name: "os_flag".to_owned(),
+                address: 0x1000
+            }]
+        );
+        assert!(layout.validate().is_ok());
+
+        let actual = "base 16\nreserve 10-20\n".parse::<RamLayout>();
+        assert_eq!(
+            actual,
+            Err(LayoutFileError {
+                line: 2,
+                kind: LayoutError::InvalidRange("10-20".to_owned())
+            })
+        );
+        assert_eq!(
+            "fixed x=1".parse::<RamLayout>().unwrap_err().to_string(),
+            "line 1: unknown entry `fixed x=1` (expected base, reserve or fixed)"
+        );
+    }
+
+    #[test]
+    fn test_validate_layout() {
+        let layout = RamLayout {
+            variable_base: 0x4000,
+            reserved: vec!["20..10".parse().unwrap()],
+            fixed: vec![
+                "SP=100".parse().unwrap(),
+                "x=0x4000".parse().unwrap(),
+                "y=1".parse().unwrap(),
+                "y=2".parse().unwrap(),
+            ],
+        };
+        assert_eq!(
+            layout.validate(),
+            Err(vec![
+                LayoutError::BaseOutOfRange(0x4000),
+                LayoutError::RangeOutOfRange(RamRange { start: 20, end: 10 }),
+                LayoutError::PredefinedFixed("SP".to_owned()),
+                LayoutError::FixedOutOfRange {
+                    name: "x".to_owned(),
+                    address: 0x4000
+                },
+                LayoutError::DuplicateFixed("y".to_owned()),
+            ])
+        );
+        assert_eq!(
+            "x".parse::<FixedSymbol>(),
+            Err(LayoutError::InvalidFixed("x".to_owned()))
+        );
+    }
+}
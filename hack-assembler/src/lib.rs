@@ -1,5 +1,33 @@
+mod analysis;
+pub use analysis::*;
 mod code;
 pub use code::*;
+mod diagnostic;
+pub use diagnostic::*;
+mod disasm;
+pub use disasm::*;
+mod format;
+pub use format::*;
+mod formatter;
+mod ide;
+pub use ide::*;
+mod instruction;
+pub use instruction::*;
+mod layout;
+pub use layout::*;
+mod lint;
+pub use lint::*;
+mod listing;
+mod memory_map;
+mod object;
+pub use object::*;
+mod optimize;
 mod parser;
+// 字句解析の結果を使うツール向け
+pub use parser::common::{Annot, DirectiveKind, JumpKind, Loc, MemKind};
+pub use parser::lexer::{lex_lossless, LexError, LexErrorKind, Lexeme, Lexer, Trivia, TriviaKind};
+pub use parser::token::{Token, TokenKind};
+mod source;
 mod sysmbol_table;
+pub use sysmbol_table::{SymTableErrorKind, ROM_SIZE};
 mod types;
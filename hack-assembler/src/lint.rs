@@ -0,0 +1,336 @@
+use crate::parser::command::*;
+use crate::parser::common::*;
+use crate::sysmbol_table::*;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("unknown lint `{0}` (single-use-variable, label-data-access, jump-with-a-dest, unused-label, unreachable-code)")]
+pub struct UnknownLint(String);
+
+/// 個別に有効・無効を切り替えられる検査
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LintCheck {
+    /// 1回しか使われない変数 (ラベルの打ち間違い)
+    SingleUseVariable,
+    /// `@LABEL`の直後の`M`の読み書き
+    LabelDataAccess,
+    /// `A=...;JMP`のようにAへの代入とジャンプを同時に行う
+    JumpWithADest,
+    /// 参照されないラベル
+    UnusedLabel,
+    /// ラベルのない`0;JMP`の後ろ
+    UnreachableCode,
+}
+
+impl LintCheck {
+    pub const ALL: [LintCheck; 5] = [
+        LintCheck::SingleUseVariable,
+        LintCheck::LabelDataAccess,
+        LintCheck::JumpWithADest,
+        LintCheck::UnusedLabel,
+        LintCheck::UnreachableCode,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        use self::LintCheck::*;
+        match self {
+            SingleUseVariable => "single-use-variable",
+            LabelDataAccess => "label-data-access",
+            JumpWithADest => "jump-with-a-dest",
+            UnusedLabel => "unused-label",
+            UnreachableCode => "unreachable-code",
+        }
+    }
+}
+
+impl fmt::Display for LintCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for LintCheck {
+    type Err = UnknownLint;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LintCheck::ALL
+            .iter()
+            .find(|c| c.name() == s)
+            .copied()
+            .ok_or_else(|| UnknownLint(s.to_owned()))
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LintWarningKind {
+    #[error("variable `{0}` is used only once (a misspelled label?)")]
+    SingleUseVariable(String),
+    #[error("`@{0}` is a ROM label, but the next instruction accesses `M` (RAM)")]
+    LabelDataAccess(String),
+    #[error("`{0}` writes A and jumps; the jump uses the old value of A")]
+    JumpWithADest(String),
+    #[error("label `{0}` is never referenced")]
+    UnusedLabel(String),
+    #[error("unreachable code after an unconditional jump")]
+    UnreachableCode,
+}
+
+impl LintWarningKind {
+    pub fn check(&self) -> LintCheck {
+        use self::LintWarningKind::*;
+        match self {
+            SingleUseVariable(_) => LintCheck::SingleUseVariable,
+            LabelDataAccess(_) => LintCheck::LabelDataAccess,
+            JumpWithADest(_) => LintCheck::JumpWithADest,
+            UnusedLabel(_) => LintCheck::UnusedLabel,
+            UnreachableCode => LintCheck::UnreachableCode,
+        }
+    }
+}
+
+pub type LintWarning = Annot<LintWarningKind>;
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.loc, self.value)
+    }
+}
+
+/// 行う検査 (既定ではすべて)
+#[derive(Debug, Clone)]
+pub struct LintOptions {
+    pub checks: HashSet<LintCheck>,
+}
+
+impl Default for LintOptions {
+    fn default() -> Self {
+        Self {
+            checks: LintCheck::ALL.iter().copied().collect(),
+        }
+    }
+}
+
+/// 構文解析したコマンド列を検査し、警告を位置順に返す
+/// シンボルの解決に失敗してもエラーにはせず、分かる範囲で検査する
+pub fn lint(commands: &[Command], opts: &LintOptions) -> Vec<LintWarning> {
+    let mut sym_table = SymbolTable::new();
+    let _ = sym_table.resolve_labels(commands);
+    let scopes = scopes(commands);
+
+    let mut warnings = vec![];
+    let enabled = |c: LintCheck| opts.checks.contains(&c);
+    if enabled(LintCheck::SingleUseVariable) || enabled(LintCheck::UnusedLabel) {
+        check_references(commands, &scopes, &sym_table, opts, &mut warnings);
+    }
+    if enabled(LintCheck::LabelDataAccess) {
+        check_label_data_access(commands, &scopes, &sym_table, &mut warnings);
+    }
+    if enabled(LintCheck::JumpWithADest) {
+        check_jump_with_a_dest(commands, &mut warnings);
+    }
+    if enabled(LintCheck::UnreachableCode) {
+        check_unreachable(commands, &mut warnings);
+    }
+    warnings.sort_by_key(|w| w.loc.start());
+    warnings
+}
+
+fn check_references(
+    commands: &[Command],
+    scopes: &[&str],
+    sym_table: &SymbolTable,
+    opts: &LintOptions,
+    warnings: &mut Vec<LintWarning>,
+) {
+    // 修飾した名前 → (参照回数, 最初の参照)
+    let mut refs: HashMap<String, (usize, &Loc)> = HashMap::new();
+    let mut add_refs = |expr: &Expr, scope: &str, loc| {
+        for s in expr.symbols() {
+            refs.entry(qualify(s, scope)).or_insert((0, loc)).0 += 1;
+        }
+    };
+    for (cmd, scope) in commands.iter().zip(scopes) {
+        match &cmd.value {
+            CommandKind::A(AddrCommand { value }) => add_refs(value, scope, &cmd.loc),
+            CommandKind::D(DirectiveCommand::Equ { value, .. }) => add_refs(value, scope, &cmd.loc),
+            CommandKind::D(DirectiveCommand::Org { address }) => add_refs(address, scope, &cmd.loc),
+            CommandKind::D(DirectiveCommand::Var { size, .. }) => add_refs(size, scope, &cmd.loc),
+            _ => (),
+        }
+    }
+
+    if opts.checks.contains(&LintCheck::SingleUseVariable) {
+        for (name, (count, loc)) in refs.iter() {
+            // 定義済みシンボル・ラベル・定数・`.var`でなければ、自動で割り当てる変数
+            if *count == 1 && !is_local(name) && sym_table.get_address(name).is_none() {
+                let kind = LintWarningKind::SingleUseVariable(name.clone());
+                warnings.push(LintWarning::new(kind, (*loc).clone()));
+            }
+        }
+    }
+    if opts.checks.contains(&LintCheck::UnusedLabel) {
+        for (cmd, scope) in commands.iter().zip(scopes) {
+            if let CommandKind::L(LabelCommand { label }) = &cmd.value {
+                if !refs.contains_key(&qualify(label, scope)) {
+                    let kind = LintWarningKind::UnusedLabel(label.clone());
+                    warnings.push(LintWarning::new(kind, cmd.loc.clone()));
+                }
+            }
+        }
+    }
+}
+
+fn check_label_data_access(
+    commands: &[Command],
+    scopes: &[&str],
+    sym_table: &SymbolTable,
+    warnings: &mut Vec<LintWarning>,
+) {
+    for (i, (cmd, scope)) in commands.iter().zip(scopes).enumerate() {
+        let label = match &cmd.value {
+            CommandKind::A(AddrCommand {
+                value: Expr::Symbol(s),
+            }) if sym_table.labels().contains_key(&qualify(s, scope)) => s,
+            _ => continue,
+        };
+        // 間にラベルがあれば別の経路から来ることもあるので、直後の命令だけを見る
+        let next = commands[i + 1..]
+            .iter()
+            .find(|c| !matches!(c.value, CommandKind::D(_)));
+        if let Some(Annot {
+            value: CommandKind::C(c),
+            loc,
+        }) = next
+        {
+            if reads_m(&c.comp.value) || c.dest.as_ref().is_some_and(writes_m) {
+                let kind = LintWarningKind::LabelDataAccess(label.clone());
+                warnings.push(LintWarning::new(kind, loc.clone()));
+            }
+        }
+    }
+}
+
+fn check_jump_with_a_dest(commands: &[Command], warnings: &mut Vec<LintWarning>) {
+    for cmd in commands.iter() {
+        if let CommandKind::C(CompCommand {
+            dest: Some(dest),
+            comp,
+            jump: Some(jump),
+        }) = &cmd.value
+        {
+            if writes_a(dest) {
+                let text = format!("{}={};{}", dest.mnemonic(), comp.value, jump);
+                let kind = LintWarningKind::JumpWithADest(text);
+                warnings.push(LintWarning::new(kind, cmd.loc.clone()));
+            }
+        }
+    }
+}
+
+fn check_unreachable(commands: &[Command], warnings: &mut Vec<LintWarning>) {
+    let mut after_jump = false;
+    for cmd in commands.iter() {
+        match &cmd.value {
+            CommandKind::L(_) => after_jump = false,
+            CommandKind::D(_) => (),
+            CommandKind::A(_) | CommandKind::C(_) if after_jump => {
+                warnings.push(LintWarning::new(
+                    LintWarningKind::UnreachableCode,
+                    cmd.loc.clone(),
+                ));
+                // 同じ区間は1回だけ報告する
+                after_jump = false;
+            }
+            CommandKind::C(CompCommand {
+                jump: Some(JumpKind::Jmp),
+                ..
+            }) => after_jump = true,
+            _ => (),
+        }
+    }
+}
+
+fn reads_m(comp: &CompKind) -> bool {
+    let is_m = |o: &Operand| *o == Operand::Mem(MemKind::M);
+    match comp {
+        CompKind::Constant(_) => false,
+        CompKind::Mem(m) => *m == MemKind::M,
+        CompKind::UniOp { e, .. } => is_m(e),
+        CompKind::BinOp { l, r, .. } => is_m(l) || is_m(r),
+        CompKind::Shift { e, .. } => *e == MemKind::M,
+    }
+}
+
+fn writes_m(dest: &MemKind) -> bool {
+    matches!(dest, MemKind::M | MemKind::MD | MemKind::AM | MemKind::AMD)
+}
+
+fn writes_a(dest: &MemKind) -> bool {
+    matches!(dest, MemKind::A | MemKind::AM | MemKind::AD | MemKind::AMD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{lexer, parse_all};
+
+    fn lint_str(input: &str, opts: &LintOptions) -> Vec<LintWarningKind> {
+        let (tokens, _) = lexer::lex_all(input);
+        let (commands, errors) = parse_all(tokens, input);
+        assert!(errors.is_empty());
+        lint(&commands, opts).into_iter().map(|w| w.value).collect()
+    }
+
+    #[test]
+    fn test_lint() {
+        let input = r###"
+@i
+M=0
+(LOOP)
+@i
+M=M+1
+@LOOP
+D=M
+@LOOPP
+AM=M-1;JMP
+(UNUSED)
+@LOOP
+0;JMP
+@i
+(END)
+@END
+0;JMP
+"###;
+        let actual = lint_str(input, &LintOptions::default());
+        assert_eq!(
+            actual,
+            vec![
+                LintWarningKind::LabelDataAccess("LOOP".to_owned()),
+                LintWarningKind::SingleUseVariable("LOOPP".to_owned()),
+                LintWarningKind::JumpWithADest("AM=M-1;JMP".to_owned()),
+                LintWarningKind::UnusedLabel("UNUSED".to_owned()),
+                LintWarningKind::UnreachableCode,
+            ]
+        );
+
+        let mut opts = LintOptions::default();
+        opts.checks.remove(&LintCheck::UnusedLabel);
+        opts.checks.remove(&LintCheck::SingleUseVariable);
+        let actual = lint_str(input, &opts);
+        assert_eq!(actual.len(), 3);
+        assert!(actual.iter().all(|w| opts.checks.contains(&w.check())));
+    }
+
+    #[test]
+    fn test_lint_check_from_str() {
+        assert_eq!("unused-label".parse(), Ok(LintCheck::UnusedLabel));
+        assert_eq!(
+            "typo".parse::<LintCheck>(),
+            Err(UnknownLint("typo".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use crate::parser::command::*;
+use crate::sysmbol_table::SymbolTable;
+use crate::types::Address;
+
+use std::collections::HashMap;
+
+/// リスティング (ROMアドレス、機械語、元のソース) とシンボル表を書き出す
+/// `words`は`commands`のうちA/C命令に1対1で対応する
+pub fn render(src: &str, commands: &[Command], words: &[u16], sym_table: &SymbolTable) -> String {
+    let mut buf = String::new();
+    buf.push_str("ROM   BIN               HEX   SOURCE\n");
+
+    let mut words = words.iter();
+    let mut address = 0;
+    for cmd in commands.iter() {
+        let text = &src[cmd.loc.start()..cmd.loc.end()];
+        match cmd.value {
+            CommandKind::L(_) | CommandKind::D(_) => {
+                buf.push_str(&format!("{:04x}  {:16}  {:4}  {}\n", address, "", "", text));
+            }
+            _ => {
+                let word = words.next().unwrap();
+                buf.push_str(&format!(
+                    "{:04x}  {:016b}  {:04x}  {}\n",
+                    address, word, word, text
+                ));
+                address += 1;
+            }
+        }
+    }
+
+    buf.push_str("\nLabels (ROM):\n");
+    render_symbols(&mut buf, sym_table.labels());
+    buf.push_str("\nVariables (RAM):\n");
+    render_symbols(&mut buf, sym_table.variables());
+    if !sym_table.constants().is_empty() {
+        buf.push_str("\nConstants:\n");
+        render_symbols(&mut buf, sym_table.constants());
+    }
+    buf
+}
+
+/// アドレス順に並べる
+fn render_symbols(buf: &mut String, symbols: &HashMap<String, Address>) {
+    let mut symbols: Vec<(&String, &Address)> = symbols.iter().collect();
+    symbols.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+    if symbols.is_empty() {
+        buf.push_str("  (none)\n");
+    }
+    for (name, address) in symbols {
+        buf.push_str(&format!("  {:04x}  {:5}  {}\n", address, address, name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_listing() {
+        let input = r###"
+   @counter
+   M=D
+(LOOP)
+   @LOOP
+   0;JMP   // infinite loop
+"###;
+        let opts = AssembleOptions {
+            listing: true,
+            ..AssembleOptions::default()
+        };
+        let actual = Assembler::run_with_options(input, None, &opts)
+            .unwrap()
+            .listing
+            .unwrap();
+        let expect = r###"ROM   BIN               HEX   SOURCE
+0000  0000000000010000  0010  @counter
+0001  1110001100001000  e308  M=D
+0002                          (LOOP)
+0002  0000000000000010  0002  @LOOP
+0003  1110101010000111  ea87  0;JMP
+
+Labels (ROM):
+  0002      2  LOOP
+
+Variables (RAM):
+  0010     16  counter
+"###;
+        assert_eq!(actual, expect);
+    }
+
+    #[test]
+    fn test_listing_ignores_optimize() {
+        // 最適化すると機械語がソースの行と合わなくなるので、リスティングでは最適化しない
+        let input = "@NEXT\nD=D-1;JGT\n(NEXT)\n@NEXT\n0;JMP\n";
+        let opts = AssembleOptions {
+            listing: true,
+            optimize: true,
+            ..AssembleOptions::default()
+        };
+        let output = Assembler::run_with_options(input, None, &opts).unwrap();
+        assert_eq!(output.saved, 0);
+        let expect = r###"ROM   BIN               HEX   SOURCE
+0000  0000000000000010  0002  @NEXT
+0001  1110001110010001  e391  D=D-1;JGT
+0002                          (NEXT)
+0002  0000000000000010  0002  @NEXT
+0003  1110101010000111  ea87  0;JMP
+"###;
+        assert!(output.listing.unwrap().starts_with(expect));
+    }
+}
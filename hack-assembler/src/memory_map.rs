@@ -0,0 +1,111 @@
+use crate::layout::RamLayout;
+use crate::parser::command::*;
+use crate::sysmbol_table::{is_local, SymbolTable};
+use crate::types::Address;
+
+use std::collections::HashMap;
+
+/// 変数のRAM配置 (アドレス、大きさ、参照するA命令の数) と予約した区間を書き出す
+pub fn render(commands: &[Command], sym_table: &SymbolTable, layout: &RamLayout) -> String {
+    let mut refs: HashMap<&str, usize> = HashMap::new();
+    for cmd in commands.iter() {
+        if let CommandKind::A(AddrCommand { value }) = &cmd.value {
+            let mut symbols = value.symbols();
+            symbols.sort_unstable();
+            symbols.dedup();
+            for symbol in symbols.into_iter().filter(|s| !is_local(s)) {
+                *refs.entry(symbol).or_default() += 1;
+            }
+        }
+    }
+
+    let mut buf = format!(
+        "RAM map (variables from {:04x}):\nADDR  DEC    SIZE  REFS  NAME\n",
+        layout.variable_base
+    );
+    let mut variables: Vec<(&String, &Address)> = sym_table.variables().iter().collect();
+    variables.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+    if variables.is_empty() {
+        buf.push_str("  (none)\n");
+    }
+    for (name, address) in variables {
+        let block = sym_table
+            .blocks()
+            .iter()
+            .find(|(start, _)| start == address);
+        let size = block.map_or(1, |(start, end)| end - start);
+        let note = if block.is_some() {
+            "  (.var)"
+        } else if layout.fixed.iter().any(|f| &f.name == name) {
+            "  (fixed)"
+        } else {
+            ""
+        };
+        buf.push_str(&format!(
+            "{:04x}  {:5}  {:4}  {:4}  {}{}\n",
+            address,
+            address,
+            size,
+            refs.get(name.as_str()).unwrap_or(&0),
+            name,
+            note
+        ));
+    }
+
+    if !layout.reserved.is_empty() {
+        buf.push_str("\nReserved:\n");
+        for r in layout.reserved.iter() {
+            buf.push_str(&format!(
+                "  {:04x}..{:04x}  ({} words)\n",
+                r.start,
+                r.end,
+                r.end - r.start
+            ));
+        }
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_memory_map() {
+        let input = r###"
+.var buf 2
+   @i
+   M=0
+   @buf
+   D=A
+   @i
+   A=D+M
+   M=0
+   @os_flag
+   M=1
+"###;
+        let opts = AssembleOptions {
+            memory_map: true,
+            layout: RamLayout {
+                variable_base: 0x20,
+                reserved: vec!["0x22..0x30".parse().unwrap()],
+                fixed: vec!["os_flag=0x100".parse().unwrap()],
+            },
+            ..AssembleOptions::default()
+        };
+        let actual = Assembler::run_with_options(input, None, &opts)
+            .unwrap()
+            .memory_map
+            .unwrap();
+        let expect = r###"RAM map (variables from 0020):
+ADDR  DEC    SIZE  REFS  NAME
+0020     32     2     1  buf  (.var)
+0030     48     1     2  i
+0100    256     1     1  os_flag  (fixed)
+
+Reserved:
+  0022..0030  (14 words)
+"###;
+        assert_eq!(actual, expect);
+    }
+}
@@ -0,0 +1,524 @@
+use crate::layout::RamLayout;
+use crate::parser::command::*;
+use crate::parser::common::*;
+use crate::sysmbol_table::*;
+use crate::types::Address;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// オブジェクトファイルを作るときのエラー
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ObjectErrorKind {
+    #[error("`@{0}` cannot be relocated (use `SYMBOL`, `SYMBOL+n` or `SYMBOL-n`)")]
+    NotRelocatable(String),
+    #[error("`{0}` is not supported in object files (RAM is assigned by the linker)")]
+    UnsupportedDirective(DirectiveKind),
+}
+
+pub type ObjectError = Annot<ObjectErrorKind>;
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.loc, self.value)
+    }
+}
+
+impl ObjectError {
+    pub(crate) fn not_relocatable(expr: &Expr, loc: Loc) -> Self {
+        Self::new(ObjectErrorKind::NotRelocatable(expr.to_string()), loc)
+    }
+    pub(crate) fn unsupported_directive(kind: DirectiveKind, loc: Loc) -> Self {
+        Self::new(ObjectErrorKind::UnsupportedDirective(kind), loc)
+    }
+}
+
+/// オブジェクトファイルの読み込みエラー
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+#[error("line {line}: {message}")]
+pub struct ObjectFormatError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl ObjectFormatError {
+    fn new(line: usize, message: &str) -> Self {
+        Self {
+            line,
+            message: message.to_owned(),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LinkError {
+    #[error("`{object}`: {kind}")]
+    SymTable {
+        object: String,
+        kind: SymTableErrorKind,
+    },
+    #[error("label `{name}` is exported by both `{first}` and `{second}`")]
+    DuplicateSymbol {
+        name: String,
+        first: String,
+        second: String,
+    },
+    #[error("`{object}` imports `{name}`, but no object exports it")]
+    Unresolved { name: String, object: String },
+    #[error("`{object}` uses `{name}` as a variable, but `{owner}` exports a label with that name (declare it with `.extern`)")]
+    Undeclared {
+        name: String,
+        object: String,
+        owner: String,
+    },
+    #[error(
+        "linked program has {0} words and does not fit in ROM ({} words)",
+        ROM_SIZE
+    )]
+    RomLimit(usize),
+    #[error("`{object}`: relocated value {value} at offset {offset} does not fit in 15 bits")]
+    ValueOutOfRange {
+        object: String,
+        offset: Address,
+        value: i64,
+    },
+}
+
+/// 再配置で足し込む値
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RelocTarget {
+    /// このオブジェクトの先頭のROMアドレス (内部のラベル)
+    Base,
+    /// 他のオブジェクトのラベル (`.extern`)
+    Label(String),
+    /// リンク時に割り当てる変数
+    Variable(String),
+}
+
+impl fmt::Display for RelocTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RelocTarget::Base => write!(f, "base"),
+            RelocTarget::Label(name) => write!(f, "label {}", name),
+            RelocTarget::Variable(name) => write!(f, "var {}", name),
+        }
+    }
+}
+
+/// `code[offset]`に`target`の値を足す
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Reloc {
+    pub offset: Address,
+    pub target: RelocTarget,
+}
+
+/// 再配置可能なオブジェクト
+/// 再配置するA命令には、ラベルのオブジェクト内での位置と定数の和が入る
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    pub code: Vec<u16>,
+    /// 公開するラベルと、オブジェクト内での位置 (ローカルラベルは含まない)
+    pub exports: Vec<(String, Address)>,
+    /// `.extern`で宣言したラベル
+    pub imports: Vec<String>,
+    pub relocs: Vec<Reloc>,
+}
+
+/// オブジェクトファイルの先頭行
+const OBJECT_MAGIC: &str = "hackobj 1";
+
+/// 1行に1項目のテキスト形式
+/// ```text
+/// hackobj 1
+/// export Main.main 0
+/// import Sys.init
+/// reloc 3 base
+/// reloc 5 var i
+/// code 12
+/// 0000000000000010
+/// ...
+/// ```
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", OBJECT_MAGIC)?;
+        for (name, address) in self.exports.iter() {
+            writeln!(f, "export {} {}", name, address)?;
+        }
+        for name in self.imports.iter() {
+            writeln!(f, "import {}", name)?;
+        }
+        for reloc in self.relocs.iter() {
+            writeln!(f, "reloc {} {}", reloc.offset, reloc.target)?;
+        }
+        writeln!(f, "code {}", self.code.len())?;
+        for word in self.code.iter() {
+            writeln!(f, "{:016b}", word)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Object {
+    type Err = ObjectFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines().enumerate().map(|(i, l)| (i + 1, l.trim()));
+        match lines.next() {
+            Some((_, OBJECT_MAGIC)) => (),
+            _ => return Err(ObjectFormatError::new(1, "not a hack object file")),
+        }
+
+        let mut obj = Object::default();
+        let mut code_len = None;
+        for (line, text) in lines {
+            if let Some(len) = code_len {
+                if obj.code.len() == len {
+                    return Err(ObjectFormatError::new(line, "extra line after code"));
+                }
+                let word = u16::from_str_radix(text, 2)
+                    .ok()
+                    .filter(|_| text.len() == 16)
+                    .ok_or_else(|| ObjectFormatError::new(line, "invalid machine code"))?;
+                obj.code.push(word);
+                continue;
+            }
+
+            let fields: Vec<&str> = text.split_whitespace().collect();
+            let number = |s: &str| {
+                s.parse::<Address>()
+                    .map_err(|_| ObjectFormatError::new(line, "invalid number"))
+            };
+            match fields.as_slice() {
+                ["export", name, address] => {
+                    obj.exports.push((name.to_string(), number(address)?));
+                }
+                ["import", name] => obj.imports.push(name.to_string()),
+                ["reloc", offset, "base"] => obj.relocs.push(Reloc {
+                    offset: number(offset)?,
+                    target: RelocTarget::Base,
+                }),
+                ["reloc", offset, "label", name] => obj.relocs.push(Reloc {
+                    offset: number(offset)?,
+                    target: RelocTarget::Label(name.to_string()),
+                }),
+                ["reloc", offset, "var", name] => obj.relocs.push(Reloc {
+                    offset: number(offset)?,
+                    target: RelocTarget::Variable(name.to_string()),
+                }),
+                ["code", len] => code_len = Some(number(len)? as usize),
+                _ => return Err(ObjectFormatError::new(line, "unknown record")),
+            }
+        }
+
+        match code_len {
+            Some(len) if len == obj.code.len() => (),
+            _ => {
+                let line = s.lines().count();
+                return Err(ObjectFormatError::new(line, "code is truncated"));
+            }
+        }
+        if let Some(r) = obj
+            .relocs
+            .iter()
+            .find(|r| r.offset as usize >= obj.code.len())
+        {
+            let message = format!("relocation offset {} is out of code", r.offset);
+            return Err(ObjectFormatError::new(s.lines().count(), &message));
+        }
+        Ok(obj)
+    }
+}
+
+/// A命令の値に足し込むシンボル
+/// 再配置できるのは、シンボル1つに定数を足し引きした形だけ
+/// 再配置できなければ`Err`
+pub(crate) fn reloc_target(
+    expr: &Expr,
+    scope: &str,
+    sym_table: &SymbolTable,
+    externs: &HashSet<&str>,
+) -> Result<Option<RelocTarget>, ()> {
+    match expr {
+        Expr::Num(_) => Ok(None),
+        Expr::Symbol(s) => {
+            let name = qualify(s, scope);
+            let target = if sym_table.labels().contains_key(&name) {
+                Some(RelocTarget::Base)
+            } else if sym_table.get_address(&name).is_some() {
+                // 定義済みシンボルと定数はそのまま
+                None
+            } else if externs.contains(name.as_str()) {
+                Some(RelocTarget::Label(name))
+            } else {
+                Some(RelocTarget::Variable(name))
+            };
+            Ok(target)
+        }
+        Expr::BinOp { op, l, r } => {
+            let l = reloc_target(l, scope, sym_table, externs)?;
+            let r = reloc_target(r, scope, sym_table, externs)?;
+            match (op, l, r) {
+                (_, None, None) => Ok(None),
+                (ExprOpKind::Add, Some(t), None)
+                | (ExprOpKind::Add, None, Some(t))
+                | (ExprOpKind::Sub, Some(t), None) => Ok(Some(t)),
+                _ => Err(()),
+            }
+        }
+    }
+}
+
+/// オブジェクトを順に並べ、ラベルを解決し、変数を割り当てる
+/// エラーがあっても最後まで検査し、すべてのエラーを返す
+pub fn link(objects: &[(String, Object)]) -> Result<Vec<u16>, Vec<LinkError>> {
+    link_with_layout(objects, &RamLayout::default())
+}
+
+/// 変数の配置を指定してリンクする
+pub fn link_with_layout(
+    objects: &[(String, Object)],
+    layout: &RamLayout,
+) -> Result<Vec<u16>, Vec<LinkError>> {
+    let mut errors = vec![];
+    let mut sym_table = SymbolTable::with_layout(layout);
+    // ラベル → 公開したオブジェクト
+    let mut owners: HashMap<&str, &str> = HashMap::new();
+    let loc = Loc::new(0, 0);
+
+    let mut bases = vec![];
+    let mut base = 0;
+    for (name, obj) in objects.iter() {
+        bases.push(base);
+        for (label, address) in obj.exports.iter() {
+            if let Some(first) = owners.get(label.as_str()) {
+                errors.push(LinkError::DuplicateSymbol {
+                    name: label.clone(),
+                    first: first.to_string(),
+                    second: name.clone(),
+                });
+                continue;
+            }
+            if let Err(e) = sym_table.add_label(label, base + *address as usize, &loc) {
+                errors.push(LinkError::SymTable {
+                    object: name.clone(),
+                    kind: e.value,
+                });
+                continue;
+            }
+            owners.insert(label, name);
+        }
+        base += obj.code.len();
+    }
+    if base > ROM_SIZE {
+        errors.push(LinkError::RomLimit(base));
+    }
+
+    for (name, obj) in objects.iter() {
+        for import in obj.imports.iter() {
+            if !owners.contains_key(import.as_str()) {
+                errors.push(LinkError::Unresolved {
+                    name: import.clone(),
+                    object: name.clone(),
+                });
+            }
+        }
+        // 他のオブジェクトのラベルを使うには`.extern`の宣言がいる (書き間違いを黙って結びつけない)
+        let mut undeclared = HashSet::new();
+        for reloc in obj.relocs.iter() {
+            if let RelocTarget::Variable(var) = &reloc.target {
+                if let Some(owner) = owners.get(var.as_str()) {
+                    if undeclared.insert(var) {
+                        errors.push(LinkError::Undeclared {
+                            name: var.clone(),
+                            object: name.clone(),
+                            owner: owner.to_string(),
+                        });
+                    }
+                    continue;
+                }
+                if let Err(e) = sym_table.add_symbol(var, &loc) {
+                    errors.push(LinkError::SymTable {
+                        object: name.clone(),
+                        kind: e.value,
+                    });
+                }
+            }
+        }
+    }
+
+    let mut words = vec![];
+    for ((name, obj), base) in objects.iter().zip(bases) {
+        let start = words.len();
+        words.extend_from_slice(&obj.code);
+        for reloc in obj.relocs.iter() {
+            let value = match &reloc.target {
+                RelocTarget::Base => base as i64,
+                RelocTarget::Label(s) | RelocTarget::Variable(s) => {
+                    // 解決に失敗したシンボルはエラー報告済みなので0とみなす
+                    sym_table.get_address(s).copied().unwrap_or(0) as i64
+                }
+            };
+            // 命令語には足し込む値が2の補数で入っている
+            let word = &mut words[start + reloc.offset as usize];
+            let sum = *word as i16 as i64 + value;
+            if !(0..=MAX_VALUE).contains(&sum) {
+                errors.push(LinkError::ValueOutOfRange {
+                    object: name.clone(),
+                    offset: reloc.offset,
+                    value: sum,
+                });
+            } else {
+                *word = sum as u16;
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(words)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{AssembleOptions, Assembler, CodeErrorKind};
+
+    fn compile(input: &str) -> Object {
+        Assembler::run_object(input, None, &AssembleOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn test_object_compile() {
+        let obj = compile(".extern F\n(MAIN)\n@i\nM=0\n(.loop)\n@.loop+1\n@F\n@SCREEN+2\n");
+        assert_eq!(obj.code, vec![0, 0xea88, 3, 0, 0x4002]);
+        assert_eq!(obj.exports, vec![("MAIN".to_owned(), 0)]);
+        assert_eq!(obj.imports, vec!["F".to_owned()]);
+        let targets: Vec<(Address, RelocTarget)> = obj
+            .relocs
+            .into_iter()
+            .map(|r| (r.offset, r.target))
+            .collect();
+        assert_eq!(
+            targets,
+            vec![
+                (0, RelocTarget::Variable("i".to_owned())),
+                (2, RelocTarget::Base),
+                (3, RelocTarget::Label("F".to_owned())),
+            ]
+        );
+
+        let errors = Assembler::run_object("@i*2\n.var buf 4\n", None, &AssembleOptions::default())
+            .unwrap_err();
+        let kinds: Vec<String> = errors.errors.iter().map(|e| e.kind.message()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ObjectErrorKind::NotRelocatable("i*2".to_owned()).to_string(),
+                ObjectErrorKind::UnsupportedDirective(DirectiveKind::Var).to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_object_addend_out_of_range() {
+        // 足し込む値が15bitに収まらなければ、コンパイル時にエラー
+        let errors = Assembler::run_object(
+            "(F)\n@x+65536\n@F-70000\n",
+            None,
+            &AssembleOptions::default(),
+        )
+        .unwrap_err();
+        let kinds: Vec<String> = errors.errors.iter().map(|e| e.kind.message()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                CodeErrorKind::ExprOutOfRange {
+                    expr: "x+65536".to_owned(),
+                    value: 65536,
+                }
+                .to_string(),
+                CodeErrorKind::ExprOutOfRange {
+                    expr: "F-70000".to_owned(),
+                    value: -70000,
+                }
+                .to_string(),
+            ]
+        );
+
+        // 足した結果が15bitに収まらなければ、リンク時にエラー
+        let objects = vec![("a".to_owned(), compile("@x+32767\n"))];
+        assert_eq!(
+            link(&objects),
+            Err(vec![LinkError::ValueOutOfRange {
+                object: "a".to_owned(),
+                offset: 0,
+                value: 16 + 32767,
+            }])
+        );
+    }
+
+    #[test]
+    fn test_object_text_roundtrip() {
+        let obj = compile(".extern F\n(MAIN)\n@i\n@MAIN\n@F\n");
+        let text = obj.to_string();
+        assert!(text.starts_with("hackobj 1\nexport MAIN 0\nimport F\nreloc 0 var i\n"));
+        assert_eq!(text.parse::<Object>(), Ok(obj));
+
+        let actual = "hackobj 1\ncode 2\n0000000000000000\n".parse::<Object>();
+        assert_eq!(actual, Err(ObjectFormatError::new(3, "code is truncated")));
+        let actual = "hackobj 1\nexport X\n".parse::<Object>();
+        assert_eq!(actual, Err(ObjectFormatError::new(2, "unknown record")));
+    }
+
+    #[test]
+    fn test_link() {
+        let main = compile(".extern F\n(MAIN)\n@i\nM=0\n@F\n0;JMP\n(.end)\n@.end\n");
+        let lib = compile("(F)\n@j\n@i\n@F-1\n");
+        let objects = vec![("main".to_owned(), main), ("lib".to_owned(), lib)];
+        let words = link(&objects).unwrap();
+        // iとjは最初に使われた順に割り当てる
+        assert_eq!(words, vec![16, 0xea88, 5, 0xea87, 4, 17, 16, 4]);
+
+        let objects = vec![
+            ("a".to_owned(), compile("(F)\n@F-1\n")),
+            ("b".to_owned(), compile(".extern G\n(F)\n@G\n")),
+        ];
+        assert_eq!(
+            link(&objects),
+            Err(vec![
+                LinkError::DuplicateSymbol {
+                    name: "F".to_owned(),
+                    first: "a".to_owned(),
+                    second: "b".to_owned(),
+                },
+                LinkError::Unresolved {
+                    name: "G".to_owned(),
+                    object: "b".to_owned(),
+                },
+                LinkError::ValueOutOfRange {
+                    object: "a".to_owned(),
+                    offset: 0,
+                    value: -1,
+                },
+            ])
+        );
+
+        // `.extern`を忘れると、他のオブジェクトのラベルを変数として使うことになる
+        let objects = vec![
+            ("main".to_owned(), compile("@F\n0;JMP\n@F\n")),
+            ("lib".to_owned(), compile("(F)\n@F\n")),
+        ];
+        assert_eq!(
+            link(&objects),
+            Err(vec![LinkError::Undeclared {
+                name: "F".to_owned(),
+                object: "main".to_owned(),
+                owner: "lib".to_owned(),
+            }])
+        );
+    }
+}
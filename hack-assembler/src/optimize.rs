@@ -0,0 +1,316 @@
+use crate::parser::command::*;
+use crate::parser::common::*;
+use crate::sysmbol_table::{qualify, scopes};
+
+use std::collections::HashSet;
+
+/// 直前の命令から分かっているAの値
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AValue {
+    /// `@expr`の値
+    Addr(Expr),
+    /// `@expr`の後の`A=M`の値 (RAM[expr]は書き換わっていない)
+    Load(Expr),
+}
+
+/// アセンブル前の覗き穴最適化
+/// ラベルの位置では何も分からないとみなし、ラベルをまたいだ書き換えはしない
+/// 削った命令数も返す
+/// 命令を削るとROMアドレスがずれるので、数値や式のジャンプ先があれば何もせず`None`を返す
+pub(crate) fn optimize(mut commands: Vec<Command>) -> (Vec<Command>, Option<usize>) {
+    if jumps_to_fixed_address(&commands) {
+        return (commands, None);
+    }
+    let mut saved = 0;
+    // ある書き換えで別の書き換えができるようになるので、変わらなくなるまで繰り返す
+    loop {
+        let (next, n) = optimize_pass(&commands);
+        commands = next;
+        if n == 0 {
+            return (commands, Some(saved));
+        }
+        saved += n;
+    }
+}
+
+/// ラベルで書かれていないジャンプ先 (`@10; D;JGT`など) や、ラベルを使った式 (`@LOOP+2`) があるか
+fn jumps_to_fixed_address(commands: &[Command]) -> bool {
+    let scopes = scopes(commands);
+    let labels: HashSet<String> = commands
+        .iter()
+        .zip(scopes.iter())
+        .filter_map(|(c, scope)| match &c.value {
+            CommandKind::L(LabelCommand { label }) => Some(qualify(label, scope)),
+            _ => None,
+        })
+        .collect();
+    let is_label = |e: &Expr, scope: &str| match e {
+        Expr::Symbol(s) => labels.contains(&qualify(s, scope)),
+        _ => false,
+    };
+
+    commands.iter().enumerate().any(|(i, c)| {
+        let value = match &c.value {
+            CommandKind::A(AddrCommand { value }) => value,
+            _ => return false,
+        };
+        if is_label(value, scopes[i]) {
+            return false;
+        }
+        // ラベルからの相対アドレスは、どこで使われてもずれる
+        if value
+            .symbols()
+            .iter()
+            .any(|s| labels.contains(&qualify(s, scopes[i])))
+        {
+            return true;
+        }
+        // Aを書き換えるか、ラベルに着くまでにジャンプするか
+        for cmd in commands[i + 1..].iter() {
+            match &cmd.value {
+                CommandKind::D(_) => (),
+                CommandKind::C(c) if c.jump.is_some() => return true,
+                CommandKind::C(c) if !writes_a(c) => (),
+                _ => return false,
+            }
+        }
+        false
+    })
+}
+
+fn writes_a(c: &CompCommand) -> bool {
+    matches!(
+        c.dest,
+        Some(MemKind::A) | Some(MemKind::AM) | Some(MemKind::AD) | Some(MemKind::AMD)
+    )
+}
+
+fn optimize_pass(commands: &[Command]) -> (Vec<Command>, usize) {
+    let scopes = scopes(commands);
+    let mut out: Vec<Command> = vec![];
+    let mut saved = 0;
+    let mut a: Option<AValue> = None;
+
+    let mut i = 0;
+    while i < commands.len() {
+        let cmd = &commands[i];
+        match &cmd.value {
+            CommandKind::L(_) => a = None,
+            CommandKind::D(_) => (),
+            CommandKind::A(AddrCommand { value }) => {
+                // 次の命令へのジャンプ
+                if let Some(replace) = jump_to_next(commands, &scopes, i) {
+                    saved += 2;
+                    if let Some(c) = replace {
+                        out.push(c);
+                        saved -= 1;
+                    }
+                    i += 2;
+                    continue;
+                }
+                match &a {
+                    // Aの値が変わっていない`@expr`
+                    Some(AValue::Addr(e)) if e == value => {
+                        saved += 1;
+                        i += 1;
+                        continue;
+                    }
+                    // `@expr; A=M`をもう一度行っても、Aは変わらない
+                    Some(AValue::Load(e)) if e == value && is_a_eq_m(commands.get(i + 1)) => {
+                        saved += 2;
+                        i += 2;
+                        continue;
+                    }
+                    _ => a = Some(AValue::Addr(value.clone())),
+                }
+            }
+            CommandKind::C(c) => {
+                // `D=M`の直後の`M=D`は同じ値を書き戻すだけ
+                if is_m_eq_d(c) && out.last().is_some_and(|prev| is_d_eq_m(&prev.value)) {
+                    saved += 1;
+                    i += 1;
+                    continue;
+                }
+                a = next_a(&a, c);
+            }
+        }
+        out.push(cmd.clone());
+        i += 1;
+    }
+    (out, saved)
+}
+
+/// C命令を実行した後のAの値
+fn next_a(a: &Option<AValue>, c: &CompCommand) -> Option<AValue> {
+    match &c.dest {
+        Some(MemKind::A) if c.comp.value == CompKind::Mem(MemKind::M) => match a {
+            Some(AValue::Addr(e)) => Some(AValue::Load(e.clone())),
+            _ => None,
+        },
+        Some(MemKind::A) | Some(MemKind::AM) | Some(MemKind::AD) | Some(MemKind::AMD) => None,
+        // RAMに書き込めば、どのアドレスが変わったかは分からない
+        Some(MemKind::M) | Some(MemKind::MD) => match a {
+            Some(AValue::Load(_)) => None,
+            _ => a.clone(),
+        },
+        _ => a.clone(),
+    }
+}
+
+/// `@LABEL; comp;jump`の直後が`(LABEL)`で、その後がA命令なら書き換えられる
+/// ジャンプしてもしなくても同じ命令に進むので、残すのは`comp`の副作用だけ
+/// 書き換えられなければ`None`、書き換えたC命令がいらなければ`Some(None)`
+fn jump_to_next(commands: &[Command], scopes: &[&str], i: usize) -> Option<Option<Command>> {
+    let target = match &commands[i].value {
+        CommandKind::A(AddrCommand {
+            value: Expr::Symbol(s),
+        }) => qualify(s, scopes[i]),
+        _ => return None,
+    };
+    let (jump, loc) = match commands.get(i + 1) {
+        Some(Annot {
+            value: CommandKind::C(c),
+            loc,
+        }) if c.jump.is_some() => (c, loc),
+        _ => return None,
+    };
+
+    let mut found = false;
+    let mut k = i + 2;
+    while let Some(cmd) = commands.get(k) {
+        match &cmd.value {
+            CommandKind::L(LabelCommand { label }) => {
+                found |= qualify(label, scopes[k]) == target;
+            }
+            CommandKind::D(_) => (),
+            // `@LABEL`を消すとラベルの後でAの値が変わるので、すぐにAを上書きしていなければならない
+            CommandKind::A(_) => break,
+            CommandKind::C(_) => return None,
+        }
+        k += 1;
+    }
+    if !found {
+        return None;
+    }
+
+    match &jump.dest {
+        None => Some(None),
+        // `comp`が`A`と`M`を使わなければ、`@LABEL`を消しても同じ値になる
+        Some(MemKind::D) if !uses_a(&jump.comp.value) => {
+            let c = CompCommand::dest(MemKind::D, jump.comp.clone());
+            Some(Some(Command::comp(c, loc.clone())))
+        }
+        _ => None,
+    }
+}
+
+fn uses_a(comp: &CompKind) -> bool {
+    let is_a = |o: &Operand| matches!(o, Operand::Mem(MemKind::A) | Operand::Mem(MemKind::M));
+    match comp {
+        CompKind::Constant(_) => false,
+        CompKind::Mem(m) => matches!(m, MemKind::A | MemKind::M),
+        CompKind::UniOp { e, .. } => is_a(e),
+        CompKind::BinOp { l, r, .. } => is_a(l) || is_a(r),
+        CompKind::Shift { e, .. } => matches!(e, MemKind::A | MemKind::M),
+    }
+}
+
+fn is_a_eq_m(cmd: Option<&Command>) -> bool {
+    matches!(cmd, Some(Annot { value: CommandKind::C(c), .. })
+        if c.dest == Some(MemKind::A) && c.comp.value == CompKind::Mem(MemKind::M) && c.jump.is_none())
+}
+
+fn is_d_eq_m(cmd: &CommandKind) -> bool {
+    matches!(cmd, CommandKind::C(c)
+        if c.dest == Some(MemKind::D) && c.comp.value == CompKind::Mem(MemKind::M) && c.jump.is_none())
+}
+
+fn is_m_eq_d(c: &CompCommand) -> bool {
+    c.dest == Some(MemKind::M) && c.comp.value == CompKind::Mem(MemKind::D) && c.jump.is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{lexer, parse_all};
+
+    fn optimize_str(input: &str) -> (Vec<String>, Option<usize>) {
+        let (tokens, _) = lexer::lex_all(input);
+        let (commands, errors) = parse_all(tokens, input);
+        assert!(errors.is_empty());
+        let (commands, saved) = optimize(commands);
+        let lines = commands
+            .iter()
+            .map(|c| match &c.value {
+                CommandKind::A(a) => format!("@{}", a.value),
+                CommandKind::L(l) => format!("({})", l.label),
+                CommandKind::C(c) => {
+                    let dest = c.dest.as_ref().map_or("", |d| d.mnemonic());
+                    let jump = c.jump.as_ref().map_or(String::new(), |j| j.to_string());
+                    format!("{}={};{}", dest, c.comp.value, jump)
+                }
+                CommandKind::D(_) => String::new(),
+            })
+            .collect();
+        (lines, saved)
+    }
+
+    #[test]
+    fn test_optimize_repeated_load() {
+        let (actual, saved) = optimize_str("@SP\nM=M+1\n@SP\nA=M\nM=D\n@SP\nA=M\n@SP\nA=M\nD=M\n");
+        assert_eq!(
+            actual,
+            vec!["@SP", "M=M+1;", "A=M;", "M=D;", "@SP", "A=M;", "D=M;"]
+        );
+        assert_eq!(saved, Some(3));
+
+        // ラベルをまたがない
+        let (actual, saved) = optimize_str("@SP\nD=M\n(L)\n@SP\nM=D\n@L\n0;JMP\n");
+        assert_eq!(actual.len(), 7);
+        assert_eq!(saved, Some(0));
+    }
+
+    #[test]
+    fn test_optimize_store_back() {
+        let (actual, saved) = optimize_str("@x\nD=M\nM=D\n@x\nD=M\n(L)\nM=D\n@L\n0;JMP\n");
+        assert_eq!(
+            actual,
+            vec!["@x", "D=M;", "D=M;", "(L)", "M=D;", "@L", "=0;JMP"]
+        );
+        assert_eq!(saved, Some(2));
+    }
+
+    #[test]
+    fn test_optimize_jump_to_next() {
+        let (actual, saved) =
+            optimize_str("@NEXT\nD=D-1;JGT\n(NEXT)\n@END\n0;JMP\n(END)\n(L)\n@L\n0;JMP\n");
+        assert_eq!(
+            actual,
+            vec!["D=D-1;", "(NEXT)", "(END)", "(L)", "@L", "=0;JMP"]
+        );
+        assert_eq!(saved, Some(3));
+
+        // ラベルの後でAを使っていれば残す
+        let (_, saved) = optimize_str("@NEXT\n0;JMP\n(NEXT)\nD=A\n");
+        assert_eq!(saved, Some(0));
+        let (_, saved) = optimize_str("@NEXT\nD=M;JGT\n(NEXT)\n@0\n");
+        assert_eq!(saved, Some(0));
+    }
+
+    #[test]
+    fn test_optimize_fixed_jump_target() {
+        // 命令を削ると`@4`の指す命令がずれる
+        let input = "@x\nD=M\n@x\nD=D-1\n@4\nD;JGT\n(END)\n@END\n0;JMP\n";
+        let (actual, saved) = optimize_str(input);
+        assert_eq!(actual.len(), 9);
+        assert_eq!(saved, None);
+
+        // ラベルからの相対アドレスも同じ
+        let (_, saved) = optimize_str("@x\nD=M\n@x\n(L)\n@L+1\nD=A\n");
+        assert_eq!(saved, None);
+
+        // 数値をデータとして使うだけなら最適化する
+        let (_, saved) = optimize_str("@x\nD=M\n@x\n@4\nD=D+A\n@x\nM=D\n");
+        assert_eq!(saved, Some(1));
+    }
+}
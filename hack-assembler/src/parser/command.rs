@@ -1,29 +1,120 @@
 use super::common::*;
+use std::fmt;
 
+/// A命令の値に使える演算子
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExprOpKind {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Shl,
+}
+
+impl ExprOpKind {
+    /// 結合の強さ (`<<` < `+ -` < `* /`)
+    pub fn precedence(self) -> u8 {
+        match self {
+            ExprOpKind::Shl => 0,
+            ExprOpKind::Add | ExprOpKind::Sub => 1,
+            ExprOpKind::Mul | ExprOpKind::Div => 2,
+        }
+    }
+}
+
+impl fmt::Display for ExprOpKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExprOpKind::Add => write!(f, "+"),
+            ExprOpKind::Sub => write!(f, "-"),
+            ExprOpKind::Mul => write!(f, "*"),
+            ExprOpKind::Div => write!(f, "/"),
+            ExprOpKind::Shl => write!(f, "<<"),
+        }
+    }
+}
+
+/// A命令の値 (数値・シンボル・定数式)
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub enum NumOrSymbol {
+pub enum Expr {
     Num(u64),
     Symbol(String),
+    BinOp {
+        op: ExprOpKind,
+        l: Box<Expr>,
+        r: Box<Expr>,
+    },
+}
+
+impl Expr {
+    pub fn binop(op: ExprOpKind, l: Expr, r: Expr) -> Self {
+        Expr::BinOp {
+            op,
+            l: Box::new(l),
+            r: Box::new(r),
+        }
+    }
+
+    /// 式に現れるシンボル (左から順に)
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expr::Num(_) => vec![],
+            Expr::Symbol(s) => vec![s.as_str()],
+            Expr::BinOp { l, r, .. } => {
+                let mut symbols = l.symbols();
+                symbols.extend(r.symbols());
+                symbols
+            }
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::BinOp { op, .. } => op.precedence(),
+            _ => u8::MAX,
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Num(n) => n.fmt(f),
+            Expr::Symbol(s) => s.fmt(f),
+            Expr::BinOp { op, l, r } => {
+                // 左結合なので、右側は同じ強さでも括弧が要る
+                if l.precedence() < op.precedence() {
+                    write!(f, "({})", l)?;
+                } else {
+                    write!(f, "{}", l)?;
+                }
+                write!(f, "{}", op)?;
+                if r.precedence() <= op.precedence() {
+                    write!(f, "({})", r)
+                } else {
+                    write!(f, "{}", r)
+                }
+            }
+        }
+    }
 }
 
 /// A命令
-/// ex: @hoge, @42
+/// ex: @hoge, @42, @SCREEN+32
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct AddrCommand {
-    pub value: NumOrSymbol,
+    pub value: Expr,
 }
 
 impl AddrCommand {
-    fn new(value: NumOrSymbol) -> Self {
+    pub fn new(value: Expr) -> Self {
         Self { value }
     }
     pub fn num(n: u64) -> Self {
-        let n = NumOrSymbol::Num(n);
-        Self::new(n)
+        Self::new(Expr::Num(n))
     }
     pub fn symbol(s: &str) -> Self {
-        let s = NumOrSymbol::Symbol(s.to_string());
-        Self::new(s)
+        Self::new(Expr::Symbol(s.to_string()))
     }
 }
 
@@ -33,6 +124,15 @@ pub enum UniOpKind {
     Not,
 }
 
+impl fmt::Display for UniOpKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UniOpKind::Minus => write!(f, "-"),
+            UniOpKind::Not => write!(f, "!"),
+        }
+    }
+}
+
 pub type UniOp = Annot<UniOpKind>;
 
 impl UniOp {
@@ -52,6 +152,24 @@ pub enum BinOpKind {
     Or,
 }
 
+impl BinOpKind {
+    /// 交換法則が成り立つか
+    pub fn is_commutative(&self) -> bool {
+        !matches!(self, BinOpKind::Sub)
+    }
+}
+
+impl fmt::Display for BinOpKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BinOpKind::Add => write!(f, "+"),
+            BinOpKind::Sub => write!(f, "-"),
+            BinOpKind::And => write!(f, "&"),
+            BinOpKind::Or => write!(f, "|"),
+        }
+    }
+}
+
 pub type BinOp = Annot<BinOpKind>;
 
 impl BinOp {
@@ -85,6 +203,15 @@ impl Constant {
     }
 }
 
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Constant::Zero => write!(f, "0"),
+            Constant::One => write!(f, "1"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Operand {
     Constant(Constant),
@@ -100,12 +227,63 @@ impl Operand {
     }
 }
 
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Constant(c) => c.fmt(f),
+            Operand::Mem(m) => write!(f, "{}", m.mnemonic()),
+        }
+    }
+}
+
+/// 拡張命令セットのシフト
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ShiftKind {
+    /// <<
+    Left,
+    /// >>
+    Right,
+}
+
+impl fmt::Display for ShiftKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShiftKind::Left => write!(f, "<<"),
+            ShiftKind::Right => write!(f, ">>"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum CompKind {
     Constant(Constant),
     Mem(MemKind),
-    UniOp { op: UniOp, e: Operand },
-    BinOp { op: BinOp, l: MemKind, r: Operand },
+    UniOp {
+        op: UniOp,
+        e: Operand,
+    },
+    BinOp {
+        op: BinOp,
+        l: Operand,
+        r: Operand,
+    },
+    /// `D<<`, `A>>` など (拡張命令セットのみ)
+    Shift {
+        op: ShiftKind,
+        e: MemKind,
+    },
+}
+
+impl fmt::Display for CompKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompKind::Constant(c) => c.fmt(f),
+            CompKind::Mem(m) => write!(f, "{}", m.mnemonic()),
+            CompKind::UniOp { op, e } => write!(f, "{}{}", op.value, e),
+            CompKind::BinOp { op, l, r } => write!(f, "{}{}{}", l, op.value, r),
+            CompKind::Shift { op, e } => write!(f, "{}{}", e.mnemonic(), op),
+        }
+    }
 }
 
 pub type Comp = Annot<CompKind>;
@@ -118,13 +296,16 @@ impl Comp {
         Self::new(CompKind::Mem(m), loc)
     }
     pub fn uniop(op: UniOp, e: Operand, loc: Loc) -> Self {
-        let uniop = CompKind::UniOp { op: op, e: e };
+        let uniop = CompKind::UniOp { op, e };
         Self::new(uniop, loc)
     }
-    pub fn binop(op: BinOp, l: MemKind, r: Operand, loc: Loc) -> Self {
-        let binop = CompKind::BinOp { op: op, l: l, r: r };
+    pub fn binop(op: BinOp, l: Operand, r: Operand, loc: Loc) -> Self {
+        let binop = CompKind::BinOp { op, l, r };
         Self::new(binop, loc)
     }
+    pub fn shift(op: ShiftKind, e: MemKind, loc: Loc) -> Self {
+        Self::new(CompKind::Shift { op, e }, loc)
+    }
 }
 
 /// C命令
@@ -166,11 +347,27 @@ impl LabelCommand {
     }
 }
 
+/// アセンブラ指令 (ROMを消費しない)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DirectiveCommand {
+    /// `.equ NAME value`: RAMを割り当てない定数
+    Equ { name: String, value: Expr },
+    /// `.include "file.asm"`
+    Include { path: String },
+    /// `.org address`: 以降の`.var`を置くRAMアドレス
+    Org { address: Expr },
+    /// `.var NAME size`: 連続したRAMを確保する
+    Var { name: String, size: Expr },
+    /// `.extern NAME`: 他のオブジェクトで定義されるラベル
+    Extern { name: String },
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum CommandKind {
     A(AddrCommand),
     C(CompCommand),
     L(LabelCommand),
+    D(DirectiveCommand),
 }
 
 pub type Command = Annot<CommandKind>;
@@ -185,6 +382,9 @@ impl Command {
     pub fn label(cmd: LabelCommand, loc: Loc) -> Self {
         Self::new(CommandKind::L(cmd), loc)
     }
+    pub fn directive(cmd: DirectiveCommand, loc: Loc) -> Self {
+        Self::new(CommandKind::D(cmd), loc)
+    }
     pub fn cmd_type(&self) -> &CommandKind {
         &self.value
     }
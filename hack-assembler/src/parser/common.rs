@@ -12,6 +12,12 @@ impl Loc {
     pub fn new(l: usize, r: usize) -> Self {
         Self(l, r)
     }
+    pub fn start(&self) -> usize {
+        self.0
+    }
+    pub fn end(&self) -> usize {
+        self.1
+    }
     pub fn merge(&self, other: &Loc) -> Self {
         use std::cmp::{max, min};
         Self(min(self.0, other.0), max(self.1, other.1))
@@ -37,6 +43,7 @@ impl<T> Annot<T> {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum MemKind {
     M, // Memory
@@ -48,6 +55,22 @@ pub enum MemKind {
     AMD,
 }
 
+impl MemKind {
+    /// アセンブリ上の表記
+    pub fn mnemonic(&self) -> &'static str {
+        use self::MemKind::*;
+        match self {
+            M => "M",
+            D => "D",
+            A => "A",
+            MD => "MD",
+            AM => "AM",
+            AD => "AD",
+            AMD => "AMD",
+        }
+    }
+}
+
 impl fmt::Display for MemKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::MemKind::*;
@@ -87,3 +110,30 @@ impl fmt::Display for JumpKind {
         }
     }
 }
+
+/// アセンブラ指令
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DirectiveKind {
+    Equ,
+    Include,
+    Org,
+    Var,
+    Extern,
+    Macro,
+    Endm,
+}
+
+impl fmt::Display for DirectiveKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DirectiveKind::*;
+        match self {
+            Equ => write!(f, ".equ"),
+            Include => write!(f, ".include"),
+            Org => write!(f, ".org"),
+            Var => write!(f, ".var"),
+            Extern => write!(f, ".extern"),
+            Macro => write!(f, ".macro"),
+            Endm => write!(f, ".endm"),
+        }
+    }
+}
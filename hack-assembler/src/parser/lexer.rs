@@ -5,22 +5,23 @@ use thiserror::Error;
 
 #[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum LexErrorKind {
-    #[error("")]
+    #[error("invalid char '{0}'")]
     InvalidChar(char),
-    #[error("eof")]
+    #[error("unexpected end of file")]
     Eof,
+    #[error("number `{0}` is too large")]
+    NumberOverflow(String),
+    #[error("invalid literal `{0}`")]
+    InvalidLiteral(String),
+    #[error("unterminated block comment")]
+    UnterminatedComment,
 }
 
 pub type LexError = Annot<LexErrorKind>;
 
 impl fmt::Display for LexError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use self::LexErrorKind::*;
-        let loc = &self.loc;
-        match self.value {
-            InvalidChar(c) => write!(f, "{}: invalid char '{}'", loc, c),
-            Eof => write!(f, "End of file"),
-        }
+        write!(f, "{}: {}", self.loc, self.value)
     }
 }
 
@@ -31,58 +32,276 @@ impl LexError {
     fn eof(loc: Loc) -> Self {
         LexError::new(LexErrorKind::Eof, loc)
     }
+    fn number_overflow(s: &str, loc: Loc) -> Self {
+        LexError::new(LexErrorKind::NumberOverflow(s.to_owned()), loc)
+    }
+    fn invalid_literal(s: &str, loc: Loc) -> Self {
+        LexError::new(LexErrorKind::InvalidLiteral(s.to_owned()), loc)
+    }
+    fn unterminated_comment(loc: Loc) -> Self {
+        LexError::new(LexErrorKind::UnterminatedComment, loc)
+    }
+}
+
+/// 構文には関わらないが、整形で残したいもの
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TriviaKind {
+    /// `//`から行末まで、または`/*`から`*/`まで (区切りを含む)
+    Comment(String),
+    /// 空白だけの行
+    BlankLine,
+    /// 空白と改行 (`lex_lossless`のみ)
+    Whitespace(String),
+    /// エラーで読み飛ばした部分 (`lex_lossless`のみ)
+    Skipped(String),
 }
 
+pub type Trivia = Annot<TriviaKind>;
+
+impl Trivia {
+    fn comment(s: &str, loc: Loc) -> Self {
+        Self::new(TriviaKind::Comment(s.to_owned()), loc)
+    }
+    fn blank_line(loc: Loc) -> Self {
+        Self::new(TriviaKind::BlankLine, loc)
+    }
+    fn whitespace(s: &str, loc: Loc) -> Self {
+        Self::new(TriviaKind::Whitespace(s.to_owned()), loc)
+    }
+    fn skipped(s: &str, loc: Loc) -> Self {
+        Self::new(TriviaKind::Skipped(s.to_owned()), loc)
+    }
+}
+
+/// トークンか、読み飛ばしたもの
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Lexeme {
+    Token(Token),
+    Trivia(Trivia),
+}
+
+impl Lexeme {
+    pub fn loc(&self) -> &Loc {
+        match self {
+            Lexeme::Token(t) => &t.loc,
+            Lexeme::Trivia(t) => &t.loc,
+        }
+    }
+}
+
+/// 最初のエラーで止まる
 pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
-    let mut tokens = vec![];
-    let input = input.as_bytes();
-    let mut pos = 0;
-    macro_rules! lex_a_token {
-        ($lexer:expr) => {{
-            let (tok, p) = $lexer?;
-            tokens.push(tok);
-            pos = p;
-        }};
-    }
-
-    while pos < input.len() {
-        match input[pos] {
-            b'0'..=b'9' => lex_a_token!(lex_number(input, pos)),
-            b'+' => lex_a_token!(lex_plus(input, pos)),
-            b'-' => lex_a_token!(lex_minus(input, pos)),
-            b'&' => lex_a_token!(lex_and(input, pos)),
-            b'|' => lex_a_token!(lex_or(input, pos)),
-            b'!' => lex_a_token!(lex_not(input, pos)),
-            b'@' => lex_a_token!(lex_at(input, pos)),
-            b'=' => lex_a_token!(lex_eq(input, pos)),
-            b';' => lex_a_token!(lex_semicolon(input, pos)),
-            b'(' => lex_a_token!(lex_lparen(input, pos)),
-            b')' => lex_a_token!(lex_rparen(input, pos)),
-            b if available_char_in_ident_head(b as char) => {
-                lex_a_token!(lex_ident(input, pos))
+    let (tokens, errors) = lex_all(input);
+    match errors.into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(tokens),
+    }
+}
+
+/// 不正な文字があってもその行を読み飛ばして字句解析を続け、すべてのエラーを返す
+/// エラーのあった行のトークンは捨てる
+pub fn lex_all(input: &str) -> (Vec<Token>, Vec<LexError>) {
+    let (tokens, _, errors) = Lexer::new(input).finish();
+    (tokens, errors)
+}
+
+/// `lex_all`と同じだが、読み飛ばしたコメントと空行も位置順に返す
+pub fn lex_with_trivia(input: &str) -> (Vec<Token>, Vec<Trivia>, Vec<LexError>) {
+    Lexer::with_mode(input, false, true).finish()
+}
+
+/// トークンと、空白・コメント・エラーで読み飛ばした部分を位置順に並べる
+/// 各要素の位置の部分をつなげると入力に戻る
+/// エラーのあった行のトークンも捨てない
+pub fn lex_lossless(input: &str) -> (Vec<Lexeme>, Vec<LexError>) {
+    let (tokens, trivia, errors) = Lexer::with_mode(input, true, true).finish();
+    let mut lexemes = Vec::with_capacity(tokens.len() + trivia.len());
+    let mut tokens = tokens.into_iter().peekable();
+    let mut trivia = trivia.into_iter().peekable();
+    loop {
+        let lexeme = match (tokens.peek(), trivia.peek()) {
+            (Some(tok), Some(t)) if tok.loc.start() < t.loc.start() => {
+                Lexeme::Token(tokens.next().unwrap())
             }
-            b' ' | b'\n' | b'\t' => {
-                let ((), p) = skip_spaces(input, pos)?;
-                pos = p;
+            (_, Some(_)) => Lexeme::Trivia(trivia.next().unwrap()),
+            (Some(_), None) => Lexeme::Token(tokens.next().unwrap()),
+            (None, None) => break,
+        };
+        lexemes.push(lexeme);
+    }
+    (lexemes, errors)
+}
+
+/// 字句解析器
+/// `next_line`で1行ずつ取り出せば、ファイル全体のトークン列を作らずに済む
+pub struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    /// 空白をそのまま残し、エラーのあった行のトークンを捨てない
+    lossless: bool,
+    /// コメントと空行を集めるか
+    keep_trivia: bool,
+    tokens: Vec<Token>,
+    trivia: Vec<Trivia>,
+    errors: Vec<LexError>,
+    /// 現在の行の先頭トークンの添字
+    line_head: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a str) -> Self {
+        Self::with_mode(src, false, false)
+    }
+
+    fn with_mode(src: &'a str, lossless: bool, keep_trivia: bool) -> Self {
+        Self {
+            src,
+            pos: 0,
+            lossless,
+            keep_trivia,
+            tokens: vec![],
+            trivia: vec![],
+            errors: vec![],
+            line_head: 0,
+        }
+    }
+
+    /// 次の行のトークンで`line`を置き換え、見つけたエラーを`errors`に足す
+    /// トークンのある行が残っていなければ`false`
+    pub fn next_line(&mut self, line: &mut Vec<Token>, errors: &mut Vec<LexError>) -> bool {
+        line.clear();
+        while self.pos < self.src.len() {
+            self.step();
+            errors.append(&mut self.errors);
+            // 改行を読んだ時点で、前の行のトークンがそろっている
+            if self.line_head > 0 {
+                line.extend(self.tokens.drain(..self.line_head));
+                self.line_head = 0;
+                return true;
             }
-            b'/' => {
-                if let Some(next) = peek(input, pos + 1) {
-                    if next == '/' {
-                        let ((), p) = skip_comment(input, pos)?;
-                        pos = p;
-                        continue;
+        }
+        line.append(&mut self.tokens);
+        !line.is_empty()
+    }
+
+    /// 残りをすべて読む
+    fn finish(mut self) -> (Vec<Token>, Vec<Trivia>, Vec<LexError>) {
+        while self.pos < self.src.len() {
+            self.step();
+        }
+        (self.tokens, self.trivia, self.errors)
+    }
+
+    fn push(&mut self, res: Result<(Token, usize), LexError>) {
+        match res {
+            Ok((tok, p)) => {
+                self.tokens.push(tok);
+                self.pos = p;
+            }
+            Err(e) => {
+                self.errors.push(e);
+                let (src, pos) = (self.src, self.pos);
+                let end = skip_comment(src.as_bytes(), pos);
+                if self.lossless {
+                    let loc = Loc::new(pos, end);
+                    self.trivia.push(Trivia::skipped(&src[pos..end], loc));
+                } else {
+                    self.tokens.truncate(self.line_head);
+                }
+                self.pos = end;
+            }
+        }
+    }
+
+    /// トークンか、空白・コメントを1つ読む
+    fn step(&mut self) {
+        let (src, pos) = (self.src, self.pos);
+        let input = src.as_bytes();
+        match input[pos] {
+            b'0'..=b'9' => self.push(lex_number(input, pos)),
+            b'+' => self.push(lex_plus(input, pos)),
+            b'-' => self.push(lex_minus(input, pos)),
+            b'&' => self.push(lex_and(input, pos)),
+            b'|' => self.push(lex_or(input, pos)),
+            b'!' => self.push(lex_not(input, pos)),
+            b'*' => self.push(lex_star(input, pos)),
+            b'<' => self.push(lex_shl(input, pos)),
+            b'>' => self.push(lex_shr(input, pos)),
+            b'\'' => self.push(lex_char(input, pos)),
+            b'"' => self.push(lex_string(input, pos)),
+            b'@' => self.push(lex_at(input, pos)),
+            b'=' => self.push(lex_eq(input, pos)),
+            b';' => self.push(lex_semicolon(input, pos)),
+            b',' => self.push(lex_comma(input, pos)),
+            b'(' => self.push(lex_lparen(input, pos)),
+            b')' => self.push(lex_rparen(input, pos)),
+            b if available_char_in_ident_head(b as char) => self.push(lex_ident(input, pos)),
+            b'/' => match peek(input, pos + 1) {
+                Some('/') => {
+                    let end = comment_end(input, pos);
+                    if self.keep_trivia {
+                        let loc = Loc::new(pos, end);
+                        self.trivia.push(Trivia::comment(&src[pos..end], loc));
+                    }
+                    self.pos = end;
+                }
+                Some('*') => match src[pos + 2..].find("*/") {
+                    Some(i) => {
+                        let end = pos + 2 + i + 2;
+                        if src[pos..end].contains('\n') {
+                            self.line_head = self.tokens.len();
+                        }
+                        if self.keep_trivia {
+                            let loc = Loc::new(pos, end);
+                            self.trivia.push(Trivia::comment(&src[pos..end], loc));
+                        }
+                        self.pos = end;
+                    }
+                    None => {
+                        // 閉じていなければ、残りをすべて読み飛ばす
+                        let loc = Loc::new(pos, pos + 2);
+                        self.errors.push(LexError::unterminated_comment(loc));
+                        if self.lossless {
+                            let loc = Loc::new(pos, input.len());
+                            self.trivia.push(Trivia::skipped(&src[pos..], loc));
+                        }
+                        self.pos = input.len();
                     }
+                },
+                // ひとつだけの`/`は割り算
+                _ => self.push(lex_slash(input, pos)),
+            },
+            _ if char_at(src, pos).is_whitespace() => {
+                let p = skip_spaces(src, pos);
+                if input[pos..p].contains(&b'\n') {
+                    self.line_head = self.tokens.len();
                 }
-                // ひとつだけの`/`は認めない
-                return Err(LexError::invalid_char(
-                    input[pos] as char,
-                    Loc::new(pos, pos + 1),
-                ));
+                if self.lossless {
+                    let loc = Loc::new(pos, p);
+                    self.trivia.push(Trivia::whitespace(&src[pos..p], loc));
+                } else if self.keep_trivia {
+                    // 2つ目以降の改行 (ファイルの先頭では1つ目から) の手前は空行
+                    let mut newlines = (pos..p).filter(|&i| input[i] == b'\n');
+                    if pos > 0 {
+                        newlines.next();
+                    }
+                    let blank_lines = newlines.map(|i| Trivia::blank_line(Loc::new(i, i)));
+                    self.trivia.extend(blank_lines);
+                }
+                self.pos = p;
+            }
+            _ => {
+                let c = char_at(src, pos);
+                let loc = Loc::new(pos, pos + c.len_utf8());
+                self.push(Err(LexError::invalid_char(c, loc)))
             }
-            b => return Err(LexError::invalid_char(b as char, Loc::new(pos, pos + 1))),
         }
     }
-    Ok(tokens)
+}
+
+/// `pos`から始まる文字 (`pos`は文字の境界にある)
+fn char_at(src: &str, pos: usize) -> char {
+    src[pos..].chars().next().unwrap()
 }
 
 /// 先読み
@@ -118,12 +337,55 @@ fn recognize_many(input: &[u8], mut pos: usize, mut f: impl FnMut(u8) -> bool) -
     pos
 }
 
+/// 10進数のほか、`0x`(16進数)と`0b`(2進数)の接頭辞を認める
 fn lex_number(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
     use std::str::from_utf8;
 
-    let end = recognize_many(input, start, |b| b"1234567890".contains(&b));
-    let n = from_utf8(&input[start..end]).unwrap().parse().unwrap();
-    Ok((Token::number(n, Loc::new(start, end)), end))
+    let (radix, digits) = match (input[start], peek(input, start + 1)) {
+        (b'0', Some('x')) | (b'0', Some('X')) => (16, start + 2),
+        (b'0', Some('b')) | (b'0', Some('B')) => (2, start + 2),
+        _ => (10, start),
+    };
+    let end = if radix == 10 {
+        recognize_many(input, start, |b| b"1234567890".contains(&b))
+    } else {
+        // 不正な桁もまとめてエラーにする
+        recognize_many(input, digits, |b| b.is_ascii_alphanumeric())
+    };
+    let s = from_utf8(&input[start..end]).unwrap();
+    let loc = Loc::new(start, end);
+    if digits == end {
+        return Err(LexError::invalid_literal(s, loc));
+    }
+    let body = from_utf8(&input[digits..end]).unwrap();
+    match u64::from_str_radix(body, radix) {
+        Ok(n) => Ok((Token::number(n, loc), end)),
+        Err(e) if *e.kind() == std::num::IntErrorKind::PosOverflow => {
+            Err(LexError::number_overflow(s, loc))
+        }
+        Err(_) => Err(LexError::invalid_literal(s, loc)),
+    }
+}
+
+/// `'c'` (ASCIIの印字可能文字) を文字コードとして読む
+fn lex_char(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+    let (_, pos) = consume_byte(input, start, b'\'')?;
+    match (peek(input, pos), peek(input, pos + 1)) {
+        (Some(c), Some('\'')) if c.is_ascii_graphic() || c == ' ' => {
+            let end = pos + 2;
+            Ok((Token::number(c as u64, Loc::new(start, end)), end))
+        }
+        _ => {
+            let end = recognize_many(input, pos, |b| b != b'\'' && b != b'\n');
+            let end = if peek(input, end) == Some('\'') {
+                end + 1
+            } else {
+                end
+            };
+            let s = String::from_utf8_lossy(&input[start..end]);
+            Err(LexError::invalid_literal(&s, Loc::new(start, end)))
+        }
+    }
 }
 
 fn available_char_in_ident_head(c: char) -> bool {
@@ -144,7 +406,7 @@ fn lex_ident(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
         available_char_in_ident(c)
     });
 
-    let ident = from_utf8(&input[start..end]).unwrap().into();
+    let ident = from_utf8(&input[start..end]).unwrap();
     let loc = Loc::new(start, end);
 
     // キーワードとユーザ定義シンボルの識別
@@ -165,6 +427,14 @@ fn lex_ident(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
         "JNE" => Token::jump(JumpKind::Ne, loc),
         "JLE" => Token::jump(JumpKind::Le, loc),
         "JMP" => Token::jump(JumpKind::Jmp, loc),
+        // directive
+        ".equ" => Token::directive(DirectiveKind::Equ, loc),
+        ".include" => Token::directive(DirectiveKind::Include, loc),
+        ".org" => Token::directive(DirectiveKind::Org, loc),
+        ".var" => Token::directive(DirectiveKind::Var, loc),
+        ".extern" => Token::directive(DirectiveKind::Extern, loc),
+        ".macro" => Token::directive(DirectiveKind::Macro, loc),
+        ".endm" => Token::directive(DirectiveKind::Endm, loc),
         // symbol
         _ => Token::symbol(ident, loc),
     };
@@ -172,14 +442,40 @@ fn lex_ident(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
     Ok((token, end))
 }
 
-fn skip_spaces(input: &[u8], start: usize) -> Result<((), usize), LexError> {
-    let pos = recognize_many(input, start, |b| b" \n\t".contains(&b));
-    Ok(((), pos))
+/// `"..."` (エスケープはなく、行をまたげない)
+fn lex_string(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+    let (_, pos) = consume_byte(input, start, b'"')?;
+    let end = recognize_many(input, pos, |b| b != b'"' && b != b'\n');
+    let s = String::from_utf8_lossy(&input[pos..end]);
+    if peek(input, end) != Some('"') {
+        let s = String::from_utf8_lossy(&input[start..end]);
+        return Err(LexError::invalid_literal(&s, Loc::new(start, end)));
+    }
+    Ok((Token::string(&s, Loc::new(start, end + 1)), end + 1))
+}
+
+/// 空白と改行 (CRLFの`\r`やUnicodeの空白も含む) を読み飛ばす
+fn skip_spaces(src: &str, start: usize) -> usize {
+    src[start..]
+        .char_indices()
+        .find(|(_, c)| !c.is_whitespace())
+        .map_or(src.len(), |(i, _)| start + i)
 }
 
-fn skip_comment(input: &[u8], start: usize) -> Result<((), usize), LexError> {
-    let pos = recognize_many(input, start, |b| b != b'\n');
-    Ok(((), pos))
+/// 改行の手前まで読み飛ばす
+/// エラーから復帰するときにも使う
+fn skip_comment(input: &[u8], start: usize) -> usize {
+    recognize_many(input, start, |b| b != b'\n')
+}
+
+/// `//`のコメントの終わり (CRLFの`\r`は含めない)
+fn comment_end(input: &[u8], start: usize) -> usize {
+    let end = skip_comment(input, start);
+    if end > start && input[end - 1] == b'\r' {
+        end - 1
+    } else {
+        end
+    }
 }
 
 fn lex_at(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
@@ -206,6 +502,24 @@ fn lex_not(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
     consume_byte(input, start, b'!').map(|(_, end)| (Token::not(Loc::new(start, end)), end))
 }
 
+fn lex_star(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+    consume_byte(input, start, b'*').map(|(_, end)| (Token::star(Loc::new(start, end)), end))
+}
+
+fn lex_slash(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+    consume_byte(input, start, b'/').map(|(_, end)| (Token::slash(Loc::new(start, end)), end))
+}
+
+fn lex_shl(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+    let (_, pos) = consume_byte(input, start, b'<')?;
+    consume_byte(input, pos, b'<').map(|(_, end)| (Token::shl(Loc::new(start, end)), end))
+}
+
+fn lex_shr(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+    let (_, pos) = consume_byte(input, start, b'>')?;
+    consume_byte(input, pos, b'>').map(|(_, end)| (Token::shr(Loc::new(start, end)), end))
+}
+
 fn lex_eq(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
     consume_byte(input, start, b'=').map(|(_, end)| (Token::eq(Loc::new(start, end)), end))
 }
@@ -214,6 +528,10 @@ fn lex_semicolon(input: &[u8], start: usize) -> Result<(Token, usize), LexError>
     consume_byte(input, start, b';').map(|(_, end)| (Token::semicolon(Loc::new(start, end)), end))
 }
 
+fn lex_comma(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+    consume_byte(input, start, b',').map(|(_, end)| (Token::comma(Loc::new(start, end)), end))
+}
+
 fn lex_lparen(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
     consume_byte(input, start, b'(').map(|(_, end)| (Token::lparen(Loc::new(start, end)), end))
 }
@@ -290,6 +608,11 @@ mod tests {
         assert_eq!(tokens, vec![Token::lparen(Loc::new(0, 1))]);
         let tokens = lex(")").unwrap();
         assert_eq!(tokens, vec![Token::rparen(Loc::new(0, 1))]);
+        let tokens = lex("<<>>").unwrap();
+        assert_eq!(
+            tokens,
+            vec![Token::shl(Loc::new(0, 2)), Token::shr(Loc::new(2, 4))]
+        );
     }
 
     #[test]
@@ -359,8 +682,195 @@ mod tests {
         let input = "1000 // hoge kaokokok 4567uhgy7ik";
         let tokens = lex(input).unwrap();
         assert_eq!(tokens, vec![Token::number(1000, Loc::new(0, 4))]);
-        let input = "100 / hoge kaokokok 4567uhgy7ik";
-        assert!(lex(input).is_err());
+        // ひとつだけの`/`は割り算
+        let tokens = lex("100/ 2").unwrap();
+        assert_eq!(tokens[1], Token::slash(Loc::new(3, 4)));
+    }
+
+    #[test]
+    fn test_lex_block_comment() {
+        let input = "@1 /* one */ D=A\n/* two\n three */ M=D";
+        let (tokens, trivia, errors) = lex_with_trivia(input);
+        assert!(errors.is_empty());
+        assert_eq!(tokens.len(), 8);
+        assert_eq!(
+            trivia,
+            vec![
+                Trivia::comment("/* one */", Loc::new(3, 12)),
+                Trivia::comment("/* two\n three */", Loc::new(17, 33)),
+            ]
+        );
+
+        let (tokens, errors) = lex_all("@1\n/* open\n@2\n");
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(errors, vec![LexError::unterminated_comment(Loc::new(3, 5))]);
+    }
+
+    #[test]
+    fn test_lex_whitespace() {
+        let input = "@1\r\nD=A // crlf\r\n\r\n\x0bM=D\u{3000}\u{a0};JMP\r\n";
+        let (tokens, trivia, errors) = lex_with_trivia(input);
+        assert!(errors.is_empty());
+        assert_eq!(tokens.len(), 10);
+        assert_eq!(
+            trivia,
+            vec![
+                Trivia::comment("// crlf", Loc::new(8, 15)),
+                Trivia::blank_line(Loc::new(18, 18)),
+            ]
+        );
+
+        // 空白でないUnicodeの文字は、その文字全体を指す
+        let actual = lex("@1\nD=A→M");
+        assert_eq!(actual, Err(LexError::invalid_char('→', Loc::new(6, 9))));
+    }
+
+    #[test]
+    fn test_lex_lossless() {
+        let input = "\r\n(LOOP) // top\r\n  @x /* a\r\n b */\r\n  D=D+%1 ?\r\n\t0;JMP\n/* open";
+        let (lexemes, errors) = lex_lossless(input);
+        assert_eq!(errors.len(), 2);
+        let text: String = lexemes
+            .iter()
+            .map(|l| &input[l.loc().start()..l.loc().end()])
+            .collect();
+        assert_eq!(text, input);
+        // 位置順に隙間なく並ぶ
+        for pair in lexemes.windows(2) {
+            assert_eq!(pair[0].loc().end(), pair[1].loc().start());
+        }
+        // エラーのあった行のトークンも残す
+        assert!(lexemes.contains(&Lexeme::Token(Token::plus(Loc::new(40, 41)))));
+        assert!(lexemes.contains(&Lexeme::Trivia(Trivia::skipped("%1 ?\r", Loc::new(41, 46)))));
+    }
+
+    #[test]
+    fn test_lex_with_trivia() {
+        let input = "\n@1 // one\n\n  \n// two\nD=A";
+        let (tokens, trivia, errors) = lex_with_trivia(input);
+        assert!(errors.is_empty());
+        assert_eq!(tokens.len(), 5);
+        assert_eq!(
+            trivia,
+            vec![
+                Trivia::blank_line(Loc::new(0, 0)),
+                Trivia::comment("// one", Loc::new(4, 10)),
+                Trivia::blank_line(Loc::new(11, 11)),
+                Trivia::blank_line(Loc::new(14, 14)),
+                Trivia::comment("// two", Loc::new(15, 21)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_all_recovery() {
+        let input = "@1\nD=D+%\n@2 ? @3\nM=D\n";
+        let (tokens, errors) = lex_all(input);
+        assert_eq!(
+            errors,
+            vec![
+                LexError::invalid_char('%', Loc::new(7, 8)),
+                LexError::invalid_char('?', Loc::new(12, 13)),
+            ]
+        );
+        // エラーのあった行のトークンは残らない
+        assert_eq!(
+            tokens,
+            vec![
+                Token::at(Loc::new(0, 1)),
+                Token::number(1, Loc::new(1, 2)),
+                Token::mem(MemKind::M, Loc::new(17, 18)),
+                Token::eq(Loc::new(18, 19)),
+                Token::mem(MemKind::D, Loc::new(19, 20)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lexer_next_line() {
+        let input = "@1 // a\n\nD=D+%\n/* b\n */ @2 /* c */ @3\nM=D";
+        let mut lexer = Lexer::new(input);
+        let mut line = vec![];
+        let mut errors = vec![];
+        let mut lines = vec![];
+        while lexer.next_line(&mut line, &mut errors) {
+            lines.push(line.iter().map(|t| t.value.to_string()).collect::<Vec<_>>());
+        }
+        // 空行とエラーのあった行は返さず、行をまたぐコメントは改行とみなす
+        assert_eq!(
+            lines,
+            vec![
+                vec!["@", "1"],
+                vec!["@", "2", "@", "3"],
+                vec!["M", "=", "D"]
+            ]
+        );
+        assert_eq!(errors, vec![LexError::invalid_char('%', Loc::new(13, 14))]);
+    }
+
+    #[test]
+    fn test_lex_number_overflow() {
+        let input = "@99999999999999999999999";
+        let actual = lex(input);
+        let expect = LexError::number_overflow("99999999999999999999999", Loc::new(1, 24));
+        assert_eq!(actual, Err(expect));
+    }
+
+    #[test]
+    fn test_lex_literal() {
+        let tokens = lex("0x4000 0b101 'A' ' ' 0").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::number(0x4000, Loc::new(0, 6)),
+                Token::number(0b101, Loc::new(7, 12)),
+                Token::number(65, Loc::new(13, 16)),
+                Token::number(32, Loc::new(17, 20)),
+                Token::number(0, Loc::new(21, 22)),
+            ]
+        );
+
+        let tokens = lex("SCREEN*2<<1").unwrap();
+        assert_eq!(tokens[1], Token::star(Loc::new(6, 7)));
+        assert_eq!(tokens[3], Token::shl(Loc::new(8, 10)));
+
+        let actual = lex("@0x");
+        assert_eq!(actual, Err(LexError::invalid_literal("0x", Loc::new(1, 3))));
+        let actual = lex("@0b102");
+        assert_eq!(
+            actual,
+            Err(LexError::invalid_literal("0b102", Loc::new(1, 6)))
+        );
+        let actual = lex("@'ab'");
+        assert_eq!(
+            actual,
+            Err(LexError::invalid_literal("'ab'", Loc::new(1, 5)))
+        );
+        assert!(lex("@1<2").is_err());
+    }
+
+    #[test]
+    fn test_lex_directive() {
+        let tokens = lex(".equ WIDTH 32\n.include \"lib.asm\"").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::directive(DirectiveKind::Equ, Loc::new(0, 4)),
+                Token::symbol("WIDTH", Loc::new(5, 10)),
+                Token::number(32, Loc::new(11, 13)),
+                Token::directive(DirectiveKind::Include, Loc::new(14, 22)),
+                Token::string("lib.asm", Loc::new(23, 32)),
+            ]
+        );
+        // 指令以外の`.`で始まる名前はシンボル
+        let tokens = lex(".equal").unwrap();
+        assert_eq!(tokens, vec![Token::symbol(".equal", Loc::new(0, 6))]);
+
+        let actual = lex(".include \"lib.asm\n");
+        assert_eq!(
+            actual,
+            Err(LexError::invalid_literal("\"lib.asm", Loc::new(9, 17)))
+        );
     }
 
     #[test]
@@ -0,0 +1,380 @@
+use super::common::*;
+use super::token::*;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MacroErrorKind {
+    #[error("expected a macro name after `.macro`")]
+    MissingName,
+    #[error("invalid macro parameter `{0}`")]
+    InvalidParam(TokenKind),
+    #[error("macro `{0}` is already defined")]
+    Duplicate(String),
+    #[error("`.macro` cannot be nested")]
+    Nested,
+    #[error("`.endm` without `.macro`")]
+    UnmatchedEndm,
+    #[error("macro `{0}` is not closed with `.endm`")]
+    Unterminated(String),
+    #[error("macro `{name}` takes {expected} argument(s) but {found} were given")]
+    ArgCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    #[error("macro `{0}` expands recursively too deep")]
+    Recursion(String),
+}
+
+pub type MacroError = Annot<MacroErrorKind>;
+
+impl fmt::Display for MacroError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.loc, self.value)
+    }
+}
+
+/// 展開の入れ子の上限
+const MAX_DEPTH: usize = 32;
+
+struct MacroDef {
+    name: String,
+    params: Vec<String>,
+    body: Vec<Vec<Token>>,
+    /// `.macro`の行
+    loc: Loc,
+}
+
+/// マクロ呼び出し1回分の情報 (診断用)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Expansion {
+    pub name: String,
+    /// 呼び出し箇所
+    pub call: Loc,
+    /// `.macro`の行
+    pub def: Loc,
+    /// マクロの中から呼ばれた場合、外側の展開
+    pub parent: Option<usize>,
+}
+
+/// 展開された1行
+/// トークンの位置は元の定義を指す
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExpandedLine {
+    pub tokens: Vec<Token>,
+    /// `MacroExpander::expansions`の添字 (展開されていない行は`None`)
+    pub expansion: Option<usize>,
+}
+
+/// `.macro NAME param... / .endm`を集め、呼び出しを展開する
+/// 字句解析と構文解析の間で、1行ずつ処理する
+#[derive(Default)]
+pub struct MacroExpander {
+    macros: HashMap<String, MacroDef>,
+    /// 定義中のマクロ
+    defining: Option<MacroDef>,
+    pub expansions: Vec<Expansion>,
+}
+
+impl MacroExpander {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// マクロの定義にも呼び出しにも関わらず、そのまま構文解析に渡せる行か
+    pub fn passes_through(&self, line: &[Token]) -> bool {
+        if self.defining.is_some() {
+            return false;
+        }
+        match line.first().map(|t| &t.value) {
+            Some(TokenKind::Directive(DirectiveKind::Macro))
+            | Some(TokenKind::Directive(DirectiveKind::Endm)) => false,
+            Some(TokenKind::Symbol(name)) => !self.macros.contains_key(name),
+            _ => true,
+        }
+    }
+
+    /// 1行を処理し、構文解析に渡す行を返す
+    pub fn feed(&mut self, line: Vec<Token>) -> Result<Vec<ExpandedLine>, MacroError> {
+        let head = match line.first() {
+            Some(tok) => tok,
+            None => return Ok(vec![]),
+        };
+        let line_loc = head.loc.merge(&line[line.len() - 1].loc);
+
+        if let Some(def) = self.defining.as_mut() {
+            match head.value {
+                TokenKind::Directive(DirectiveKind::Endm) => {
+                    let def = self.defining.take().unwrap();
+                    self.macros.insert(def.name.clone(), def);
+                }
+                TokenKind::Directive(DirectiveKind::Macro) => {
+                    return Err(MacroError::new(MacroErrorKind::Nested, line_loc));
+                }
+                _ => def.body.push(line),
+            }
+            return Ok(vec![]);
+        }
+
+        match &head.value {
+            TokenKind::Directive(DirectiveKind::Macro) => {
+                self.define(&line, line_loc)?;
+                Ok(vec![])
+            }
+            TokenKind::Directive(DirectiveKind::Endm) => {
+                Err(MacroError::new(MacroErrorKind::UnmatchedEndm, line_loc))
+            }
+            TokenKind::Symbol(name) if self.macros.contains_key(name) => {
+                let mut lines = vec![];
+                self.expand(&line, None, 0, &mut lines)?;
+                Ok(lines)
+            }
+            _ => Ok(vec![ExpandedLine {
+                tokens: line,
+                expansion: None,
+            }]),
+        }
+    }
+
+    /// ファイルの終わりで、閉じていないマクロを報告する
+    pub fn finish(&mut self) -> Result<(), MacroError> {
+        match self.defining.take() {
+            Some(def) => Err(MacroError::new(
+                MacroErrorKind::Unterminated(def.name),
+                def.loc,
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn define(&mut self, line: &[Token], loc: Loc) -> Result<(), MacroError> {
+        let name = match line.get(1).map(|t| &t.value) {
+            Some(TokenKind::Symbol(name)) => name.clone(),
+            _ => return Err(MacroError::new(MacroErrorKind::MissingName, loc)),
+        };
+        if self.macros.contains_key(&name) {
+            return Err(MacroError::new(MacroErrorKind::Duplicate(name), loc));
+        }
+        // 引数は空白またはカンマで区切る
+        let mut params = vec![];
+        for tok in line[2..].iter() {
+            match &tok.value {
+                TokenKind::Symbol(p) => params.push(p.clone()),
+                TokenKind::Comma => (),
+                t => {
+                    let kind = MacroErrorKind::InvalidParam(t.clone());
+                    return Err(MacroError::new(kind, tok.loc.clone()));
+                }
+            }
+        }
+        self.defining = Some(MacroDef {
+            name,
+            params,
+            body: vec![],
+            loc,
+        });
+        Ok(())
+    }
+
+    /// `line`はマクロ呼び出し (`NAME arg, ...`)
+    fn expand(
+        &mut self,
+        line: &[Token],
+        parent: Option<usize>,
+        depth: usize,
+        out: &mut Vec<ExpandedLine>,
+    ) -> Result<(), MacroError> {
+        let call = line[0].loc.merge(&line[line.len() - 1].loc);
+        let name = match &line[0].value {
+            TokenKind::Symbol(name) => name.clone(),
+            _ => unreachable!(),
+        };
+        if depth >= MAX_DEPTH {
+            return Err(MacroError::new(MacroErrorKind::Recursion(name), call));
+        }
+        let def = &self.macros[&name];
+
+        // 引数はカンマで区切る
+        let args: Vec<&[Token]> = if line.len() == 1 {
+            vec![]
+        } else {
+            line[1..].split(|t| t.value == TokenKind::Comma).collect()
+        };
+        if args.len() != def.params.len() || args.iter().any(|a| a.is_empty()) {
+            let kind = MacroErrorKind::ArgCount {
+                name,
+                expected: def.params.len(),
+                found: args.len(),
+            };
+            return Err(MacroError::new(kind, call));
+        }
+        let args: HashMap<&str, &[Token]> =
+            def.params.iter().map(|p| p.as_str()).zip(args).collect();
+
+        // 本体で定義したラベルは展開ごとに別の名前にする
+        // ローカルラベルにして、呼び出し側のスコープを切り替えないようにする
+        let id = self.expansions.len();
+        let locals: HashSet<&str> = def
+            .body
+            .iter()
+            .filter_map(|l| match (l.first(), l.get(1)) {
+                (Some(lp), Some(label)) if lp.value == TokenKind::LParen => match &label.value {
+                    TokenKind::Symbol(s) if !args.contains_key(s.as_str()) => Some(s.as_str()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+
+        let mut body = vec![];
+        for l in def.body.iter() {
+            let mut tokens = vec![];
+            for tok in l.iter() {
+                match &tok.value {
+                    // 引数のトークンは仮引数の位置に付け替え、定義の行を指させる
+                    TokenKind::Symbol(s) if args.contains_key(s.as_str()) => {
+                        let arg = args[s.as_str()].iter();
+                        tokens.extend(arg.map(|t| Token::new(t.value.clone(), tok.loc.clone())));
+                    }
+                    TokenKind::Symbol(s) if locals.contains(s.as_str()) => {
+                        let local = if s.starts_with('.') {
+                            format!("{}$m{}", s, id)
+                        } else {
+                            format!(".{}$m{}", s, id)
+                        };
+                        tokens.push(Token::symbol(&local, tok.loc.clone()));
+                    }
+                    _ => tokens.push(tok.clone()),
+                }
+            }
+            body.push(tokens);
+        }
+        self.expansions.push(Expansion {
+            name,
+            call,
+            def: def.loc.clone(),
+            parent,
+        });
+
+        for tokens in body {
+            match &tokens[0].value {
+                TokenKind::Symbol(s) if self.macros.contains_key(s) => {
+                    self.expand(&tokens, Some(id), depth + 1, out)?;
+                }
+                _ => out.push(ExpandedLine {
+                    tokens,
+                    expansion: Some(id),
+                }),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{lexer, split_lines};
+
+    fn expand_all(input: &str) -> (MacroExpander, Result<Vec<ExpandedLine>, MacroError>) {
+        let tokens = lexer::lex(input).unwrap();
+        let mut expander = MacroExpander::new();
+        let mut lines = vec![];
+        for line in split_lines(tokens, input) {
+            match expander.feed(line) {
+                Ok(ls) => lines.extend(ls),
+                Err(e) => return (expander, Err(e)),
+            }
+        }
+        let res = expander.finish().map(|_| lines);
+        (expander, res)
+    }
+
+    fn render(lines: &[ExpandedLine]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|l| {
+                let ts: Vec<String> = l.tokens.iter().map(|t| t.value.to_string()).collect();
+                ts.join(" ")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_macro_expand() {
+        let input = r###".macro PUSH value
+@value
+D=A
+.endm
+.macro WAIT
+(LOOP)
+@LOOP
+0;JMP
+.endm
+PUSH SCREEN+1
+WAIT
+WAIT
+"###;
+        let (expander, lines) = expand_all(input);
+        let lines = lines.unwrap();
+        assert_eq!(
+            render(&lines),
+            vec![
+                "@ SCREEN + 1",
+                "D = A",
+                "( .LOOP$m1 )",
+                "@ .LOOP$m1",
+                "0 ; JMP",
+                "( .LOOP$m2 )",
+                "@ .LOOP$m2",
+                "0 ; JMP",
+            ]
+        );
+        // 引数のトークンは仮引数の位置を指す
+        assert_eq!(lines[0].tokens[1].loc, Loc::new(19, 24));
+        assert_eq!(lines[0].tokens[3].loc, Loc::new(19, 24));
+        assert_eq!(expander.expansions[0].call, Loc::new(72, 85));
+        assert_eq!(expander.expansions[0].def, Loc::new(0, 17));
+    }
+
+    #[test]
+    fn test_macro_nested_call() {
+        let input =
+            ".macro INC x\n@x\nM=M+1\n.endm\n.macro INC2 x, y\nINC x\nINC y\n.endm\nINC2 i, j\n";
+        let (expander, lines) = expand_all(input);
+        let lines = lines.unwrap();
+        assert_eq!(render(&lines), vec!["@ i", "M = M + 1", "@ j", "M = M + 1"]);
+        assert_eq!(expander.expansions.len(), 3);
+        assert_eq!(expander.expansions[1].parent, Some(0));
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let (_, actual) = expand_all(".macro F a\n@a\n.endm\nF\n");
+        assert_eq!(
+            actual.unwrap_err().value,
+            MacroErrorKind::ArgCount {
+                name: "F".to_owned(),
+                expected: 1,
+                found: 0
+            }
+        );
+        let (_, actual) = expand_all(".macro F\n@1\n");
+        assert_eq!(
+            actual.unwrap_err(),
+            MacroError::new(MacroErrorKind::Unterminated("F".to_owned()), Loc::new(0, 8))
+        );
+        let (_, actual) = expand_all(".endm\n");
+        assert_eq!(actual.unwrap_err().value, MacroErrorKind::UnmatchedEndm);
+        let (_, actual) = expand_all(".macro F\n.macro G\n");
+        assert_eq!(actual.unwrap_err().value, MacroErrorKind::Nested);
+        let (_, actual) = expand_all(".macro F\nF\n.endm\nF\n");
+        assert_eq!(
+            actual.unwrap_err().value,
+            MacroErrorKind::Recursion("F".to_owned())
+        );
+    }
+}
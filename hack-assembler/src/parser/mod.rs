@@ -1,101 +1,246 @@
 pub mod command;
 pub mod common;
 pub mod lexer;
-mod token;
+pub mod macros;
+pub mod token;
 
 use command::*;
 use common::*;
+use std::fmt;
 use thiserror::Error;
 use token::*;
 
-#[derive(Error, Debug)]
-pub enum ParseError {
-    #[error("unexpected token")]
-    UnexpectedToken(Token),
-    #[error("unexpected number")]
-    UnexpectedNum(Token),
-    #[error("unexpected eof")]
-    Eof,
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ParseErrorKind {
+    #[error("unexpected token `{found}`, expected {expected}")]
+    UnexpectedToken { found: TokenKind, expected: String },
+    #[error("unexpected number `{found}`, expected {expected}")]
+    UnexpectedNum { found: u64, expected: String },
+    #[error("unexpected end of file, expected {expected}")]
+    Eof { expected: String },
+    #[error("unexpected end of line, expected {expected}")]
+    Eol { expected: String },
 }
 
-// impl ParseError
+pub type ParseError = Annot<ParseErrorKind>;
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.loc, self.value)
+    }
+}
+
+impl ParseError {
+    fn unexpected_token(tok: &Token, expected: &str) -> Self {
+        let kind = match tok.value {
+            TokenKind::Number(n) => ParseErrorKind::UnexpectedNum {
+                found: n,
+                expected: expected.to_owned(),
+            },
+            _ => ParseErrorKind::UnexpectedToken {
+                found: tok.value.clone(),
+                expected: expected.to_owned(),
+            },
+        };
+        Self::new(kind, tok.loc.clone())
+    }
+    fn eof(tokens: &[Token], expected: &str) -> Self {
+        // 最後のトークンの直後を指す
+        let end = tokens.last().map(|t| t.loc.end()).unwrap_or(0);
+        let kind = ParseErrorKind::Eof {
+            expected: expected.to_owned(),
+        };
+        Self::new(kind, Loc::new(end, end))
+    }
+}
+
+const EXPECTED_ADDRESS: &str = "a number or a symbol";
+const EXPECTED_COMP: &str = "a comp (e.g. `D+1`, `M`, `0`)";
+const EXPECTED_OPERAND: &str = "`A`, `D`, `M`, `0` or `1`";
+const EXPECTED_JUMP: &str = "a jump mnemonic (e.g. `JMP`)";
+const EXPECTED_LABEL: &str = "a label name";
+const EXPECTED_NAME: &str = "a symbol name";
+const EXPECTED_PATH: &str = "a file path (e.g. `\"lib.asm\"`)";
 
 type Commands = Vec<Command>;
 
+/// 最初のエラーで止まる
+#[cfg(test)]
 pub fn parse(tokens: Vec<Token>) -> Result<Commands, ParseError> {
     let mut pos = 0;
     let mut commands = vec![];
 
-    macro_rules! parse_a_token {
-        ($parser:expr) => {{
-            let (cmd, p) = $parser?;
-            commands.push(cmd);
-            pos = p;
-        }};
+    while pos < tokens.len() {
+        let (cmd, p) = parse_command(&tokens, pos)?;
+        commands.push(cmd);
+        pos = p;
     }
 
+    Ok(commands)
+}
+
+/// エラーがあっても次の行から構文解析を再開し、すべてのエラーを返す
+/// `src`はトークン間の改行を調べるのに使う
+pub fn parse_all(tokens: Vec<Token>, src: &str) -> (Commands, Vec<ParseError>) {
+    let mut commands = vec![];
+    let mut errors = vec![];
+    parse_into(&tokens, src, &mut commands, &mut errors);
+    (commands, errors)
+}
+
+/// `parse_all`と同じだが、結果を`commands`と`errors`に足していく
+pub fn parse_into(
+    tokens: &[Token],
+    src: &str,
+    commands: &mut Commands,
+    errors: &mut Vec<ParseError>,
+) {
+    let mut pos = 0;
     while pos < tokens.len() {
-        let tok = tokens[pos].value.clone();
-        match tok {
-            // A command
-            TokenKind::At => parse_a_token!(parse_acommand(&tokens, pos)),
-            // L command
-            TokenKind::LParen => parse_a_token!(parse_lcommand(&tokens, pos)),
-            // C command
-            _ => parse_a_token!(parse_ccommand(&tokens, pos)),
+        // 行をまたいで解析しないよう、行末までのトークンだけを渡す
+        let head = next_line_head(tokens, pos, src);
+        match parse_command(&tokens[..head], pos) {
+            Ok((cmd, p)) => {
+                commands.push(cmd);
+                pos = p;
+            }
+            Err(mut e) => {
+                // 行末で命令が途切れている
+                if let ParseErrorKind::Eof { expected } = e.value {
+                    e = ParseError::new(ParseErrorKind::Eol { expected }, e.loc);
+                }
+                errors.push(e);
+                pos = head;
+            }
         }
     }
+}
 
-    Ok(commands)
+fn parse_command(tokens: &[Token], pos: usize) -> Result<(Command, usize), ParseError> {
+    match tokens[pos].value {
+        // A command
+        TokenKind::At => parse_acommand(tokens, pos),
+        // L command
+        TokenKind::LParen => parse_lcommand(tokens, pos),
+        // directive
+        TokenKind::Directive(_) => parse_directive(tokens, pos),
+        // C command
+        _ => parse_ccommand(tokens, pos),
+    }
+}
+
+/// トークン列を行ごとに分ける
+pub fn split_lines(tokens: Vec<Token>, src: &str) -> Vec<Vec<Token>> {
+    let mut lines = vec![];
+    let mut pos = 0;
+    while pos < tokens.len() {
+        let head = next_line_head(&tokens, pos, src);
+        lines.push(tokens[pos..head].to_vec());
+        pos = head;
+    }
+    lines
+}
+
+/// `start`より後で、行頭にある最初のトークンの添字
+fn next_line_head(tokens: &[Token], start: usize, src: &str) -> usize {
+    let mut pos = start + 1;
+    while pos < tokens.len() {
+        let between = &src[tokens[pos - 1].loc.end()..tokens[pos].loc.start()];
+        if between.contains('\n') {
+            break;
+        }
+        pos += 1;
+    }
+    pos
+}
+
+/// `tokens[start..end]` の位置情報
+fn span(tokens: &[Token], start: usize, end: usize) -> Loc {
+    tokens[start].loc.merge(&tokens[end - 1].loc)
 }
 
 /// `pos`のトークンが期待するものであれば、`pos`を1進める
 fn consume_token(
-    tokens: &Vec<Token>,
+    tokens: &[Token],
     pos: usize,
     expect: TokenKind,
 ) -> Result<(TokenKind, usize), ParseError> {
-    if tokens.len() <= pos {
-        return Err(ParseError::Eof);
-    }
-    let actual = tokens[pos].value.clone();
-    if actual != expect {
-        return Err(ParseError::UnexpectedToken(tokens[pos].clone()));
+    match tokens.get(pos) {
+        Some(actual) if actual.value == expect => Ok((expect, pos + 1)),
+        // 失敗したときだけメッセージを作る
+        actual => {
+            let expected = format!("`{}`", expect);
+            match actual {
+                Some(actual) => Err(ParseError::unexpected_token(actual, &expected)),
+                None => Err(ParseError::eof(tokens, &expected)),
+            }
+        }
     }
-
-    Ok((expect, pos + 1))
 }
 
-fn check_eof(tokens: &Vec<Token>, pos: usize) -> Result<(), ParseError> {
+fn check_eof(tokens: &[Token], pos: usize, expected: &str) -> Result<(), ParseError> {
     if tokens.len() <= pos {
-        return Err(ParseError::Eof);
+        return Err(ParseError::eof(tokens, expected));
     }
     Ok(())
 }
 
-fn parse_acommand(tokens: &Vec<Token>, start: usize) -> Result<(Command, usize), ParseError> {
+fn parse_acommand(tokens: &[Token], start: usize) -> Result<(Command, usize), ParseError> {
     let (_, pos) = consume_token(tokens, start, TokenKind::At)?;
-    let _ = check_eof(tokens, pos)?;
-    let actual = tokens[pos].clone();
-    let loc = Loc::new(start, 0).merge(&actual.loc);
-    match actual.value {
-        TokenKind::Number(n) => {
-            let cmd = AddrCommand::num(n);
-            let cmd = Command::addr(cmd, loc);
-            Ok((cmd, pos + 1))
+    let (expr, pos) = parse_expr(tokens, pos)?;
+    let loc = span(tokens, start, pos);
+    let cmd = Command::addr(AddrCommand::new(expr), loc);
+    Ok((cmd, pos))
+}
+
+/// A命令の値の演算子
+fn peek_expr_op(tokens: &[Token], pos: usize) -> Option<ExprOpKind> {
+    match tokens.get(pos).map(|t| &t.value) {
+        Some(TokenKind::Plus) => Some(ExprOpKind::Add),
+        Some(TokenKind::Minus) => Some(ExprOpKind::Sub),
+        Some(TokenKind::Star) => Some(ExprOpKind::Mul),
+        Some(TokenKind::Slash) => Some(ExprOpKind::Div),
+        Some(TokenKind::Shl) => Some(ExprOpKind::Shl),
+        _ => None,
+    }
+}
+
+/// expr := term (op term)* (優先順位は`ExprOpKind::precedence`)
+fn parse_expr(tokens: &[Token], start: usize) -> Result<(Expr, usize), ParseError> {
+    parse_expr_prec(tokens, start, 0)
+}
+
+fn parse_expr_prec(tokens: &[Token], start: usize, min: u8) -> Result<(Expr, usize), ParseError> {
+    let (mut lhs, mut pos) = parse_expr_term(tokens, start)?;
+    while let Some(op) = peek_expr_op(tokens, pos) {
+        if op.precedence() < min {
+            break;
         }
-        TokenKind::Symbol(s) => {
-            let cmd = AddrCommand::symbol(&s);
-            let cmd = Command::addr(cmd, loc);
-            Ok((cmd, pos + 1))
+        let (rhs, p) = parse_expr_prec(tokens, pos + 1, op.precedence() + 1)?;
+        lhs = Expr::binop(op, lhs, rhs);
+        pos = p;
+    }
+    Ok((lhs, pos))
+}
+
+/// term := number | symbol | `(` expr `)`
+fn parse_expr_term(tokens: &[Token], pos: usize) -> Result<(Expr, usize), ParseError> {
+    check_eof(tokens, pos, EXPECTED_ADDRESS)?;
+    match &tokens[pos].value {
+        TokenKind::Number(n) => Ok((Expr::Num(*n), pos + 1)),
+        TokenKind::Symbol(s) => Ok((Expr::Symbol(s.clone()), pos + 1)),
+        TokenKind::LParen => {
+            let (expr, p) = parse_expr(tokens, pos + 1)?;
+            let (_, p) = consume_token(tokens, p, TokenKind::RParen)?;
+            Ok((expr, p))
         }
-        _ => Err(ParseError::UnexpectedToken(actual)),
+        _ => Err(ParseError::unexpected_token(&tokens[pos], EXPECTED_ADDRESS)),
     }
 }
 
-fn parse_dest(tokens: &Vec<Token>, start: usize) -> Result<(Option<MemKind>, usize), ParseError> {
+fn parse_dest(tokens: &[Token], start: usize) -> Result<(Option<MemKind>, usize), ParseError> {
     let mut pos = start;
-    let dest = match tokens[pos].value.clone() {
+    let dest = match &tokens[pos].value {
         TokenKind::Mem(m) => {
             match consume_token(tokens, pos + 1, TokenKind::Eq) {
                 Ok((_, p)) => {
@@ -111,123 +256,132 @@ fn parse_dest(tokens: &Vec<Token>, start: usize) -> Result<(Option<MemKind>, usi
         }
         _ => None,
     };
-    Ok((dest, pos))
+    Ok((dest.cloned(), pos))
 }
 
-fn parse_roperand(tokens: &Vec<Token>, start: usize) -> Result<(Operand, usize), ParseError> {
+fn parse_roperand(tokens: &[Token], start: usize) -> Result<(Operand, usize), ParseError> {
     let mut pos = start;
-    let _ = check_eof(tokens, pos)?;
-    let operand = match tokens[pos].value.clone() {
-        TokenKind::Mem(m) => Operand::mem(m),
-        TokenKind::Number(n) => match Constant::new(n) {
+    check_eof(tokens, pos, EXPECTED_OPERAND)?;
+    let operand = match &tokens[pos].value {
+        TokenKind::Mem(m) => Operand::mem(m.clone()),
+        TokenKind::Number(n) => match Constant::new(*n) {
             Some(c) => Operand::constant(c),
-            None => return Err(ParseError::UnexpectedNum(tokens[pos].clone())),
+            None => return Err(ParseError::unexpected_token(&tokens[pos], EXPECTED_OPERAND)),
         },
-        _ => return Err(ParseError::UnexpectedToken(tokens[pos].clone())),
+        _ => return Err(ParseError::unexpected_token(&tokens[pos], EXPECTED_OPERAND)),
     };
 
     pos += 1;
     Ok((operand, pos))
 }
 
-fn consume_binop(tokens: &Vec<Token>, pos: usize) -> Result<(BinOp, usize), ParseError> {
-    match (
-        consume_token(tokens, pos, TokenKind::Plus),
-        consume_token(tokens, pos, TokenKind::Minus),
-        consume_token(tokens, pos, TokenKind::And),
-        consume_token(tokens, pos, TokenKind::Or),
-    ) {
-        (Ok((_, p)), _, _, _) => {
-            let binop = BinOp::add(Loc::new(pos, p));
-            Ok((binop, p))
-        }
-        (_, Ok((_, p)), _, _) => {
-            let binop = BinOp::sub(Loc::new(pos, p));
-            Ok((binop, p))
-        }
-        (_, _, Ok((_, p)), _) => {
-            let binop = BinOp::and(Loc::new(pos, p));
-            Ok((binop, p))
-        }
-        (_, _, _, Ok((_, p))) => {
-            let binop = BinOp::or(Loc::new(pos, p));
-            Ok((binop, p))
-        }
-        _ => Err(ParseError::UnexpectedToken(tokens[pos - 1].clone())),
-    }
+fn consume_binop(tokens: &[Token], pos: usize) -> Option<(BinOp, usize)> {
+    let tok = tokens.get(pos)?;
+    let binop = match tok.value {
+        TokenKind::Plus => BinOp::add(tok.loc.clone()),
+        TokenKind::Minus => BinOp::sub(tok.loc.clone()),
+        TokenKind::And => BinOp::and(tok.loc.clone()),
+        TokenKind::Or => BinOp::or(tok.loc.clone()),
+        _ => return None,
+    };
+    Some((binop, pos + 1))
 }
 
-fn parse_comp(tokens: &Vec<Token>, start: usize) -> Result<(Comp, usize), ParseError> {
+fn parse_comp(tokens: &[Token], start: usize) -> Result<(Comp, usize), ParseError> {
     let mut pos = start;
-    let _ = check_eof(tokens, pos)?;
+    check_eof(tokens, pos, EXPECTED_COMP)?;
 
-    let comp = match tokens[pos].value.clone() {
-        // constant
+    let comp = match &tokens[pos].value {
+        // constant or BinOp
         TokenKind::Number(n) => {
-            if let Some(c) = Constant::new(n) {
-                pos += 1;
-                let loc = Loc::new(start, pos);
-                Comp::constant(c, loc)
-            } else {
-                return Err(ParseError::UnexpectedToken(tokens[pos].clone()));
+            let c = match Constant::new(*n) {
+                Some(c) => c,
+                None => return Err(ParseError::unexpected_token(&tokens[pos], EXPECTED_COMP)),
+            };
+            match consume_binop(tokens, pos + 1) {
+                Some((binop, p)) => {
+                    let (roperand, p) = parse_roperand(tokens, p)?;
+                    pos = p;
+                    let loc = span(tokens, start, pos);
+                    Comp::binop(binop, Operand::constant(c), roperand, loc)
+                }
+                _ => {
+                    pos += 1;
+                    let loc = span(tokens, start, pos);
+                    Comp::constant(c, loc)
+                }
             }
         }
         // UniOp
         TokenKind::Not => {
             let (operand, p) = parse_roperand(tokens, pos + 1)?;
             pos = p;
-            let loc = Loc::new(start, pos);
-            let uniop = UniOp::not(loc.clone());
+            let loc = span(tokens, start, pos);
+            let uniop = UniOp::not(tokens[start].loc.clone());
             Comp::uniop(uniop, operand, loc)
         }
         TokenKind::Minus => {
             let (operand, p) = parse_roperand(tokens, pos + 1)?;
             pos = p;
-            let loc = Loc::new(start, pos);
-            let uniop = UniOp::minus(loc.clone());
+            let loc = span(tokens, start, pos);
+            let uniop = UniOp::minus(tokens[start].loc.clone());
             Comp::uniop(uniop, operand, loc)
         }
-        // Mem or BinOp
+        // Mem, BinOp or Shift
         TokenKind::Mem(m) => match consume_binop(tokens, pos + 1) {
-            Ok((binop, p)) => {
+            Some((binop, p)) => {
                 pos = p;
                 let (roperand, p) = parse_roperand(tokens, pos)?;
                 pos = p;
-                let loc = Loc::new(start, pos);
-                Comp::binop(binop, m, roperand, loc)
+                let loc = span(tokens, start, pos);
+                Comp::binop(binop, Operand::mem(m.clone()), roperand, loc)
             }
             _ => {
-                pos += 1;
-                let loc = Loc::new(start, pos);
-                Comp::mem(m, loc)
+                let shift = match tokens.get(pos + 1).map(|t| &t.value) {
+                    Some(TokenKind::Shl) => Some(ShiftKind::Left),
+                    Some(TokenKind::Shr) => Some(ShiftKind::Right),
+                    _ => None,
+                };
+                match shift {
+                    Some(op) => {
+                        pos += 2;
+                        let loc = span(tokens, start, pos);
+                        Comp::shift(op, m.clone(), loc)
+                    }
+                    None => {
+                        pos += 1;
+                        let loc = span(tokens, start, pos);
+                        Comp::mem(m.clone(), loc)
+                    }
+                }
             }
         },
-        _ => return Err(ParseError::UnexpectedToken(tokens[pos].clone())),
+        _ => return Err(ParseError::unexpected_token(&tokens[pos], EXPECTED_COMP)),
     };
 
     Ok((comp, pos))
 }
 
-fn parse_ccommand(tokens: &Vec<Token>, start: usize) -> Result<(Command, usize), ParseError> {
+fn parse_ccommand(tokens: &[Token], start: usize) -> Result<(Command, usize), ParseError> {
     let pos = start;
     let (dest, pos) = parse_dest(tokens, pos)?;
 
-    let _ = check_eof(tokens, pos)?;
-    let (comp, mut pos) = parse_comp(&tokens, pos)?;
+    check_eof(tokens, pos, EXPECTED_COMP)?;
+    let (comp, mut pos) = parse_comp(tokens, pos)?;
 
-    let jump = if check_eof(tokens, pos).is_err() {
+    let jump = if pos >= tokens.len() {
         None
     } else {
         match tokens[pos].value {
             TokenKind::Semicolon => {
                 pos += 1;
-                let _ = check_eof(tokens, pos)?;
-                match tokens[pos].value.clone() {
+                check_eof(tokens, pos, EXPECTED_JUMP)?;
+                match &tokens[pos].value {
                     TokenKind::Jump(j) => {
                         pos += 1;
-                        Some(j)
+                        Some(j.clone())
                     }
-                    _ => return Err(ParseError::UnexpectedToken(tokens[pos].clone())),
+                    _ => return Err(ParseError::unexpected_token(&tokens[pos], EXPECTED_JUMP)),
                 }
             }
             _ => None,
@@ -235,28 +389,75 @@ fn parse_ccommand(tokens: &Vec<Token>, start: usize) -> Result<(Command, usize),
     };
 
     let cmd = CompCommand::new(dest, comp, jump);
-    let loc = Loc::new(start, pos);
+    let loc = span(tokens, start, pos);
     let cmd = Command::comp(cmd, loc);
     Ok((cmd, pos))
 }
 
-fn parse_lcommand(tokens: &Vec<Token>, start: usize) -> Result<(Command, usize), ParseError> {
+fn parse_lcommand(tokens: &[Token], start: usize) -> Result<(Command, usize), ParseError> {
     let (_, pos) = consume_token(tokens, start, TokenKind::LParen)?;
-    let _ = check_eof(tokens, pos)?;
-    let _ = check_eof(tokens, pos + 1)?;
-    let (actual0, actual1) = (tokens[pos].clone(), tokens[pos + 1].clone());
-    match (actual0.value, actual1.value) {
-        (TokenKind::Symbol(s), TokenKind::RParen) => {
-            let cmd = LabelCommand::new(&s);
-            let loc = Loc::new(start, 0).merge(&actual1.loc);
-            let cmd = Command::label(cmd, loc);
-            Ok((cmd, pos + 2))
-        }
-        (TokenKind::Symbol(_), _) => Err(ParseError::UnexpectedToken(tokens[pos + 1].clone())),
-        (_, _) => Err(ParseError::UnexpectedToken(tokens[pos].clone())),
+    check_eof(tokens, pos, EXPECTED_LABEL)?;
+    let label = match &tokens[pos].value {
+        TokenKind::Symbol(s) => s.clone(),
+        _ => return Err(ParseError::unexpected_token(&tokens[pos], EXPECTED_LABEL)),
+    };
+    let (_, pos) = consume_token(tokens, pos + 1, TokenKind::RParen)?;
+    let cmd = LabelCommand::new(&label);
+    let loc = span(tokens, start, pos);
+    let cmd = Command::label(cmd, loc);
+    Ok((cmd, pos))
+}
+
+fn parse_name(tokens: &[Token], pos: usize) -> Result<(String, usize), ParseError> {
+    check_eof(tokens, pos, EXPECTED_NAME)?;
+    match &tokens[pos].value {
+        TokenKind::Symbol(s) => Ok((s.clone(), pos + 1)),
+        _ => Err(ParseError::unexpected_token(&tokens[pos], EXPECTED_NAME)),
     }
 }
 
+fn parse_directive(tokens: &[Token], start: usize) -> Result<(Command, usize), ParseError> {
+    let kind = match tokens[start].value {
+        TokenKind::Directive(d) => d,
+        _ => unreachable!(),
+    };
+    let pos = start + 1;
+    let (cmd, pos) = match kind {
+        DirectiveKind::Equ => {
+            let (name, pos) = parse_name(tokens, pos)?;
+            let (value, pos) = parse_expr(tokens, pos)?;
+            (DirectiveCommand::Equ { name, value }, pos)
+        }
+        DirectiveKind::Include => {
+            check_eof(tokens, pos, EXPECTED_PATH)?;
+            let path = match &tokens[pos].value {
+                TokenKind::Str(s) => s.clone(),
+                _ => return Err(ParseError::unexpected_token(&tokens[pos], EXPECTED_PATH)),
+            };
+            (DirectiveCommand::Include { path }, pos + 1)
+        }
+        DirectiveKind::Org => {
+            let (address, pos) = parse_expr(tokens, pos)?;
+            (DirectiveCommand::Org { address }, pos)
+        }
+        DirectiveKind::Var => {
+            let (name, pos) = parse_name(tokens, pos)?;
+            let (size, pos) = parse_expr(tokens, pos)?;
+            (DirectiveCommand::Var { name, size }, pos)
+        }
+        DirectiveKind::Extern => {
+            let (name, pos) = parse_name(tokens, pos)?;
+            (DirectiveCommand::Extern { name }, pos)
+        }
+        // マクロは構文解析の前に展開済み
+        DirectiveKind::Macro | DirectiveKind::Endm => {
+            return Err(ParseError::unexpected_token(&tokens[start], EXPECTED_COMP))
+        }
+    };
+    let loc = span(tokens, start, pos);
+    Ok((Command::directive(cmd, loc), pos))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -290,6 +491,124 @@ mod tests {
         assert!(parse_acommand(&tokens, 0).is_err(), "unexpected keyword");
     }
 
+    #[test]
+    fn test_parse_acommand_expr() {
+        let tokens = lex("@SCREEN+32*2-1<<1").unwrap();
+        let (actual, pos) = parse_acommand(&tokens, 0).unwrap();
+        assert_eq!(pos, tokens.len());
+        let screen = Expr::Symbol("SCREEN".to_owned());
+        let mul = Expr::binop(ExprOpKind::Mul, Expr::Num(32), Expr::Num(2));
+        let add = Expr::binop(ExprOpKind::Add, screen, mul);
+        let sub = Expr::binop(ExprOpKind::Sub, add, Expr::Num(1));
+        let expect = Expr::binop(ExprOpKind::Shl, sub, Expr::Num(1));
+        assert_eq!(actual.value, CommandKind::A(AddrCommand::new(expect)));
+
+        let tokens = lex("@(LOOP+1)*2").unwrap();
+        let (actual, _) = parse_acommand(&tokens, 0).unwrap();
+        match actual.value {
+            CommandKind::A(cmd) => assert_eq!(cmd.value.to_string(), "(LOOP+1)*2"),
+            _ => unreachable!(),
+        }
+
+        let tokens = lex("@KBD-").unwrap();
+        assert!(parse_acommand(&tokens, 0).is_err(), "missing rhs");
+        let tokens = lex("@(1+2").unwrap();
+        assert!(parse_acommand(&tokens, 0).is_err(), "rparen not close");
+    }
+
+    #[test]
+    fn test_parse_all_line_boundary() {
+        // 次の行の`-1`を式の続きとみなさない
+        let input = "@LOOP\n-1;JMP\n@1+\nD=A\n";
+        let tokens = lex(input).unwrap();
+        let (commands, errors) = parse_all(tokens, input);
+        assert_eq!(commands.len(), 3);
+        assert_eq!(
+            commands[0].value,
+            CommandKind::A(AddrCommand::symbol("LOOP"))
+        );
+        assert_eq!(
+            errors,
+            vec![ParseError::new(
+                ParseErrorKind::Eol {
+                    expected: EXPECTED_ADDRESS.to_owned()
+                },
+                Loc::new(16, 16)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_comp_constant_lhs() {
+        let tokens = lex("1+D").unwrap();
+        let (actual, _) = parse_comp(&tokens, 0).unwrap();
+        let expect = CompKind::BinOp {
+            op: BinOp::add(Loc::new(1, 2)),
+            l: Operand::constant(Constant::One),
+            r: Operand::mem(MemKind::D),
+        };
+        assert_eq!(actual.value, expect);
+
+        let tokens = lex("2+D").unwrap();
+        assert!(parse_comp(&tokens, 0).is_err(), "unexpected number");
+    }
+
+    #[test]
+    fn test_parse_all_recovery() {
+        let input = "@\nD=M\n(LOOP\nD=D+\n@1\nM=;JMP\n0;JMP\n";
+        let tokens = lex(input).unwrap();
+        let (commands, errors) = parse_all(tokens, input);
+        assert_eq!(errors.len(), 4);
+        let actual: Vec<CommandKind> = commands.into_iter().map(|c| c.value).collect();
+        assert_eq!(actual.len(), 3, "D=M, @1, 0;JMP");
+        assert_eq!(actual[1], CommandKind::A(AddrCommand::num(1)));
+    }
+
+    #[test]
+    fn test_parse_directive() {
+        let tokens = lex(".equ ROW SCREEN+32").unwrap();
+        let (actual, _) = parse_directive(&tokens, 0).unwrap();
+        let value = Expr::binop(
+            ExprOpKind::Add,
+            Expr::Symbol("SCREEN".to_owned()),
+            Expr::Num(32),
+        );
+        let expect = DirectiveCommand::Equ {
+            name: "ROW".to_owned(),
+            value,
+        };
+        assert_eq!(actual, Command::directive(expect, Loc::new(0, 18)));
+
+        let tokens = lex(".include \"lib.asm\"").unwrap();
+        let (actual, _) = parse_directive(&tokens, 0).unwrap();
+        let expect = DirectiveCommand::Include {
+            path: "lib.asm".to_owned(),
+        };
+        assert_eq!(actual.value, CommandKind::D(expect));
+
+        let tokens = lex(".var buf 8*2").unwrap();
+        let (actual, _) = parse_directive(&tokens, 0).unwrap();
+        let expect = DirectiveCommand::Var {
+            name: "buf".to_owned(),
+            size: Expr::binop(ExprOpKind::Mul, Expr::Num(8), Expr::Num(2)),
+        };
+        assert_eq!(actual.value, CommandKind::D(expect));
+
+        let tokens = lex(".extern Sys.init").unwrap();
+        let (actual, _) = parse_directive(&tokens, 0).unwrap();
+        let expect = DirectiveCommand::Extern {
+            name: "Sys.init".to_owned(),
+        };
+        assert_eq!(actual.value, CommandKind::D(expect));
+
+        let tokens = lex(".include lib").unwrap();
+        assert!(parse_directive(&tokens, 0).is_err(), "path is not a string");
+        let tokens = lex(".var 8").unwrap();
+        assert!(parse_directive(&tokens, 0).is_err(), "missing name");
+        let tokens = lex(".org").unwrap();
+        assert!(parse_directive(&tokens, 0).is_err(), "missing address");
+    }
+
     #[test]
     fn test_parse_lcommand() {
         let tokens = lex("(LOOP)").unwrap();
@@ -3,17 +3,23 @@ use std::fmt;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TokenKind {
-    /// [0-9]+
+    /// [0-9]+, 0x.., 0b.., 'c'
     Number(u64),
     /// D Register
     Mem(MemKind),
     /// jump
     Jump(JumpKind),
     Symbol(String),
+    /// .equ, .include, .org, .var
+    Directive(DirectiveKind),
+    /// "..."
+    Str(String),
     /// @
     At,
     /// ;
     Semicolon,
+    /// ,
+    Comma,
     /// +
     Plus,
     /// -
@@ -24,6 +30,14 @@ pub enum TokenKind {
     Or,
     /// !
     Not,
+    /// *
+    Star,
+    /// /
+    Slash,
+    /// <<
+    Shl,
+    /// >>
+    Shr,
     /// =
     Eq,
     /// (
@@ -37,17 +51,24 @@ impl fmt::Display for TokenKind {
         use self::TokenKind::*;
         match self {
             Number(n) => n.fmt(f),
-            Mem(m) => m.fmt(f),
+            Mem(m) => write!(f, "{}", m.mnemonic()),
             Jump(j) => j.fmt(f),
             Symbol(s) => s.fmt(f),
+            Directive(d) => d.fmt(f),
+            Str(s) => write!(f, "\"{}\"", s),
             Plus => write!(f, "+"),
             Minus => write!(f, "-"),
             And => write!(f, "&"),
             Or => write!(f, "|"),
             Not => write!(f, "!"),
+            Star => write!(f, "*"),
+            Slash => write!(f, "/"),
+            Shl => write!(f, "<<"),
+            Shr => write!(f, ">>"),
             Eq => write!(f, "="),
             At => write!(f, "@"),
             Semicolon => write!(f, ";"),
+            Comma => write!(f, ","),
             LParen => write!(f, "("),
             RParen => write!(f, ")"),
         }
@@ -66,6 +87,12 @@ impl Token {
     pub fn symbol(s: &str, loc: Loc) -> Self {
         Self::new(TokenKind::Symbol(s.to_string()), loc)
     }
+    pub fn directive(d: DirectiveKind, loc: Loc) -> Self {
+        Self::new(TokenKind::Directive(d), loc)
+    }
+    pub fn string(s: &str, loc: Loc) -> Self {
+        Self::new(TokenKind::Str(s.to_string()), loc)
+    }
     pub fn jump(j: JumpKind, loc: Loc) -> Self {
         Self::new(TokenKind::Jump(j), loc)
     }
@@ -75,6 +102,9 @@ impl Token {
     pub fn semicolon(loc: Loc) -> Self {
         Self::new(TokenKind::Semicolon, loc)
     }
+    pub fn comma(loc: Loc) -> Self {
+        Self::new(TokenKind::Comma, loc)
+    }
     pub fn plus(loc: Loc) -> Self {
         Self::new(TokenKind::Plus, loc)
     }
@@ -90,6 +120,18 @@ impl Token {
     pub fn not(loc: Loc) -> Self {
         Self::new(TokenKind::Not, loc)
     }
+    pub fn star(loc: Loc) -> Self {
+        Self::new(TokenKind::Star, loc)
+    }
+    pub fn slash(loc: Loc) -> Self {
+        Self::new(TokenKind::Slash, loc)
+    }
+    pub fn shl(loc: Loc) -> Self {
+        Self::new(TokenKind::Shl, loc)
+    }
+    pub fn shr(loc: Loc) -> Self {
+        Self::new(TokenKind::Shr, loc)
+    }
     pub fn eq(loc: Loc) -> Self {
         Self::new(TokenKind::Eq, loc)
     }
@@ -0,0 +1,307 @@
+use crate::code::AssembleErrorKind;
+use crate::diagnostic::Snippet;
+use crate::parser;
+use crate::parser::command::*;
+use crate::parser::common::*;
+use crate::parser::lexer::Lexer;
+use crate::parser::macros::{ExpandedLine, Expansion, MacroExpander};
+use crate::parser::token::Token;
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum IncludeErrorKind {
+    #[error("cannot read `{path}`: {reason}")]
+    NotFound { path: String, reason: String },
+    #[error("`{0}` includes itself")]
+    Cycle(String),
+}
+
+pub type IncludeError = Annot<IncludeErrorKind>;
+
+impl fmt::Display for IncludeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.loc, self.value)
+    }
+}
+
+impl IncludeError {
+    fn not_found(path: &Path, reason: &str, loc: Loc) -> Self {
+        let kind = IncludeErrorKind::NotFound {
+            path: path.display().to_string(),
+            reason: reason.to_owned(),
+        };
+        Self::new(kind, loc)
+    }
+    fn cycle(path: &Path, loc: Loc) -> Self {
+        Self::new(IncludeErrorKind::Cycle(path.display().to_string()), loc)
+    }
+}
+
+/// 読み込んだファイルひとつ
+pub struct SourceFile {
+    pub path: Option<PathBuf>,
+    /// `SourceMap::buf`での開始位置
+    pub base: usize,
+    pub text: String,
+    /// マクロ展開で作った行なら、その展開
+    pub expansion: Option<usize>,
+}
+
+/// マクロ展開で作ったトークンの位置と、元の位置
+struct Origin {
+    expanded: Loc,
+    original: Loc,
+}
+
+/// `.include`したファイルやマクロの展開結果を含む、すべてのソース
+/// 各ファイルを1つのバッファにつなげ、`Loc`はそのバッファ上の位置で表す
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+    buf: String,
+    macros: MacroExpander,
+    /// `expanded`の開始位置の順
+    origins: Vec<Origin>,
+}
+
+impl SourceMap {
+    pub fn new(src: &str, path: Option<&Path>) -> Self {
+        let mut map = Self {
+            files: vec![],
+            buf: String::new(),
+            macros: MacroExpander::new(),
+            origins: vec![],
+        };
+        map.add(src, path, None);
+        map
+    }
+
+    fn add(&mut self, text: &str, path: Option<&Path>, expansion: Option<usize>) -> usize {
+        let base = self.buf.len();
+        self.buf.push_str(text);
+        // 前のファイルの最終行とつながらないように区切る
+        self.buf.push('\n');
+        self.files.push(SourceFile {
+            path: path.map(|p| p.to_path_buf()),
+            base,
+            text: text.to_owned(),
+            expansion,
+        });
+        self.files.len() - 1
+    }
+
+    /// マクロ展開で作った行をバッファに書き出し、その上の位置に付け替える
+    fn add_expanded(&mut self, line: ExpandedLine) -> Vec<Token> {
+        let base = self.buf.len();
+        let mut text = String::new();
+        let mut tokens = vec![];
+        for tok in line.tokens {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            let start = base + text.len();
+            text.push_str(&tok.value.to_string());
+            let expanded = Loc::new(start, base + text.len());
+            self.origins.push(Origin {
+                expanded: expanded.clone(),
+                original: tok.loc,
+            });
+            tokens.push(Token::new(tok.value, expanded));
+        }
+        self.add(&text, None, line.expansion);
+        tokens
+    }
+
+    /// すべてのファイルをつなげたもの
+    pub fn buf(&self) -> &str {
+        &self.buf
+    }
+
+    /// `pos`を含むファイル
+    pub fn locate(&self, pos: usize) -> &SourceFile {
+        let i = self.files.partition_point(|f| f.base <= pos);
+        &self.files[i.saturating_sub(1)]
+    }
+
+    /// マクロ展開で作った位置を元の位置に戻す
+    /// 展開されたものであれば、その展開も返す
+    pub fn original(&self, loc: &Loc) -> (Loc, Option<&Expansion>) {
+        let expansion = match self.locate(loc.start()).expansion {
+            Some(id) => &self.macros.expansions[id],
+            None => return (loc.clone(), None),
+        };
+        let find = |pos: usize| {
+            let i = self.origins.partition_point(|o| o.expanded.start() <= pos);
+            &self.origins[i.saturating_sub(1)].original
+        };
+        let start = find(loc.start());
+        let end = find(loc.end().saturating_sub(1).max(loc.start()));
+        let same_file = self.locate(start.start()).base == self.locate(end.start()).base;
+        let original = if same_file && start.start() <= end.end() {
+            start.merge(end)
+        } else {
+            start.clone()
+        };
+        (original, Some(expansion))
+    }
+
+    /// マクロの外側の展開
+    pub fn parent(&self, expansion: &Expansion) -> Option<&Expansion> {
+        expansion.parent.map(|id| &self.macros.expansions[id])
+    }
+
+    /// 元のソース上の位置のパスと抜粋
+    pub fn snippet(&self, loc: &Loc) -> (Option<PathBuf>, Snippet) {
+        let file = self.locate(loc.start());
+        let snippet = Snippet::new(&file.text, loc.start() - file.base, loc.end() - file.base);
+        (file.path.clone(), snippet)
+    }
+
+    /// エラーを並べる順序 (展開されたものは一番外側の呼び出し箇所)
+    pub fn sort_key(&self, loc: &Loc) -> usize {
+        match self.original(loc) {
+            (loc, None) => loc.start(),
+            (_, Some(mut expansion)) => {
+                while let Some(parent) = self.parent(expansion) {
+                    expansion = parent;
+                }
+                expansion.call.start()
+            }
+        }
+    }
+
+    /// 最初のファイルを解析し、`.include`とマクロを展開したコマンド列を返す
+    pub fn parse(&mut self) -> (Vec<Command>, Vec<AssembleErrorKind>) {
+        let mut stack = vec![];
+        if let Some(path) = &self.files[0].path {
+            stack.push(canonical(path));
+        }
+        self.parse_file(0, &mut stack)
+    }
+
+    fn parse_file(
+        &mut self,
+        file: usize,
+        stack: &mut Vec<PathBuf>,
+    ) -> (Vec<Command>, Vec<AssembleErrorKind>) {
+        let base = self.files[file].base;
+        // 字句解析器が借りている間も`self`を書き換えられるよう、一時的に取り出す
+        let text = std::mem::take(&mut self.files[file].text);
+        let mut lexer = Lexer::new(&text);
+        let mut commands = vec![];
+        let mut errors: Vec<AssembleErrorKind> = vec![];
+        // 行ごとに使い回す
+        let mut line = vec![];
+        let mut lex_errors = vec![];
+        let mut parsed = vec![];
+        let mut parse_errors = vec![];
+        loop {
+            let more = lexer.next_line(&mut line, &mut lex_errors);
+            errors.extend(lex_errors.drain(..).map(|mut e| {
+                e.loc = shift(&e.loc, base);
+                AssembleErrorKind::from(e)
+            }));
+            if !more {
+                break;
+            }
+            for tok in line.iter_mut() {
+                tok.loc = shift(&tok.loc, base);
+            }
+
+            if self.macros.passes_through(&line) {
+                parser::parse_into(&line, &self.buf, &mut parsed, &mut parse_errors);
+            } else {
+                let lines = match self.macros.feed(std::mem::take(&mut line)) {
+                    Ok(lines) => lines,
+                    Err(e) => {
+                        errors.push(e.into());
+                        continue;
+                    }
+                };
+                for line in lines {
+                    let tokens = match line.expansion {
+                        Some(_) => self.add_expanded(line),
+                        None => line.tokens,
+                    };
+                    parser::parse_into(&tokens, &self.buf, &mut parsed, &mut parse_errors);
+                }
+            }
+            errors.extend(parse_errors.drain(..).map(AssembleErrorKind::from));
+            for cmd in parsed.drain(..) {
+                match &cmd.value {
+                    CommandKind::D(DirectiveCommand::Include { path }) => {
+                        let path = path.clone();
+                        let (cmds, errs) = self.include(file, &path, cmd.loc, stack);
+                        commands.extend(cmds);
+                        errors.extend(errs);
+                    }
+                    _ => commands.push(cmd),
+                }
+            }
+        }
+        self.files[file].text = text;
+        // マクロの定義はファイルをまたげない
+        if let Err(e) = self.macros.finish() {
+            errors.push(e.into());
+        }
+        (commands, errors)
+    }
+}
+
+impl SourceMap {
+    fn include(
+        &mut self,
+        file: usize,
+        path: &str,
+        loc: Loc,
+        stack: &mut Vec<PathBuf>,
+    ) -> (Vec<Command>, Vec<AssembleErrorKind>) {
+        // 取り込む側のファイルからの相対パス
+        let path = match &self.files[file].path {
+            Some(p) => p.parent().unwrap_or_else(|| Path::new("")).join(path),
+            None => PathBuf::from(path),
+        };
+        if stack.contains(&canonical(&path)) {
+            return (vec![], vec![IncludeError::cycle(&path, loc).into()]);
+        }
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) => {
+                let e = IncludeError::not_found(&path, &e.to_string(), loc);
+                return (vec![], vec![e.into()]);
+            }
+        };
+        let included = self.add(&text, Some(&path), None);
+        stack.push(canonical(&path));
+        let res = self.parse_file(included, stack);
+        stack.pop();
+        res
+    }
+}
+
+fn shift(loc: &Loc, base: usize) -> Loc {
+    Loc::new(loc.start() + base, loc.end() + base)
+}
+
+/// 同じファイルを別の書き方で参照しても同一とみなす
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_map_locate() {
+        let mut map = SourceMap::new("@0\n", None);
+        map.add("D=M\n", Some(Path::new("lib.asm")), None);
+        assert_eq!(map.buf(), "@0\n\nD=M\n\n");
+        assert_eq!(map.locate(1).base, 0);
+        assert_eq!(map.locate(4).base, 4);
+        assert_eq!(map.locate(4).path, Some(PathBuf::from("lib.asm")));
+    }
+}
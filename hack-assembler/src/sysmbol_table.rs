@@ -1,26 +1,194 @@
 use super::types::Address;
+use crate::instruction::Statement;
+use crate::layout::{RamLayout, RamRange};
 use crate::parser::command::*;
 use crate::parser::common::*;
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
 use thiserror::Error;
 
-#[derive(Error, Debug)]
-pub enum SymTableError {
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SymTableErrorKind {
     #[error("available address reach the upper limit.")]
     AddressLimit,
+    #[error("program does not fit in ROM ({} words)", ROM_SIZE)]
+    RomLimit,
+    #[error("label `{0}` is already defined")]
+    DuplicateLabel(String),
+    #[error("label `{0}` conflicts with a predefined symbol")]
+    PredefinedLabel(String),
+    #[error("symbol `{0}` is already defined")]
+    DuplicateSymbol(String),
+    #[error("symbol `{0}` must be defined before it is used in a directive")]
+    UndefinedSymbol(String),
+    #[error("value {0} is out of range (0..=32767)")]
+    ValueOutOfRange(i64),
+    #[error("value overflows or divides by zero during evaluation")]
+    ValueOverflow,
+    #[error("`.var` block size must be at least 1, but got {0}")]
+    InvalidBlockSize(i64),
+    #[error("RAM block `{0}` overlaps another block")]
+    BlockOverlap(String),
+    #[error("RAM block `{0}` overlaps a reserved range or a fixed symbol")]
+    ReservedOverlap(String),
+    #[error("external label `{0}` is not defined")]
+    UndefinedExtern(String),
+    #[error("label `{0}` names both a global label and a local label")]
+    AmbiguousLabel(String),
+    #[error("local label `{label}` is not defined in {}", scope_name(.scope))]
+    UndefinedLocalLabel { label: String, scope: String },
+    #[error("local label `{label}` is used in {} but defined in {}", scope_name(.scope), scope_name(.defined))]
+    LocalOutOfScope {
+        label: String,
+        scope: String,
+        defined: String,
+    },
 }
 
-const AVAILABLE_ADDRESS_END: Address = 0x4000;
+fn scope_name(scope: &str) -> String {
+    if scope.is_empty() {
+        "the top level".to_owned()
+    } else {
+        format!("the scope of `{}`", scope)
+    }
+}
+
+pub type SymTableError = Annot<SymTableErrorKind>;
+
+impl fmt::Display for SymTableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.loc, self.value)
+    }
+}
+
+impl SymTableError {
+    fn address_limit(loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::AddressLimit, loc)
+    }
+    fn rom_limit(loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::RomLimit, loc)
+    }
+    fn duplicate_label(label: &str, loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::DuplicateLabel(label.to_owned()), loc)
+    }
+    fn predefined_label(label: &str, loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::PredefinedLabel(label.to_owned()), loc)
+    }
+    fn duplicate_symbol(name: &str, loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::DuplicateSymbol(name.to_owned()), loc)
+    }
+    fn undefined_symbol(name: &str, loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::UndefinedSymbol(name.to_owned()), loc)
+    }
+    fn value_out_of_range(value: i64, loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::ValueOutOfRange(value), loc)
+    }
+    fn value_overflow(loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::ValueOverflow, loc)
+    }
+    fn invalid_block_size(size: i64, loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::InvalidBlockSize(size), loc)
+    }
+    fn block_overlap(name: &str, loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::BlockOverlap(name.to_owned()), loc)
+    }
+    fn reserved_overlap(name: &str, loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::ReservedOverlap(name.to_owned()), loc)
+    }
+    fn undefined_extern(name: &str, loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::UndefinedExtern(name.to_owned()), loc)
+    }
+    fn ambiguous_label(label: &str, loc: Loc) -> Self {
+        Self::new(SymTableErrorKind::AmbiguousLabel(label.to_owned()), loc)
+    }
+    fn undefined_local_label(label: &str, scope: &str, loc: Loc) -> Self {
+        let kind = SymTableErrorKind::UndefinedLocalLabel {
+            label: label.to_owned(),
+            scope: scope.to_owned(),
+        };
+        Self::new(kind, loc)
+    }
+    fn local_out_of_scope(label: &str, scope: &str, defined: &str, loc: Loc) -> Self {
+        let kind = SymTableErrorKind::LocalOutOfScope {
+            label: label.to_owned(),
+            scope: scope.to_owned(),
+            defined: defined.to_owned(),
+        };
+        Self::new(kind, loc)
+    }
+}
+
+/// 変数の割り当て開始アドレス (既定値)
+pub(crate) const VARIABLE_BASE: Address = 16;
+/// 変数に使えるRAMの終端 (SCREENの手前まで)
+pub(crate) const AVAILABLE_ADDRESS_END: Address = 0x4000;
+/// 指令で定義できる値の上限 (A命令と同じ15bit)
+pub(crate) const MAX_VALUE: i64 = 0x7fff;
+/// ROMのワード数
+pub const ROM_SIZE: usize = 0x8000;
+
+/// `.`で始まるラベルは、直前のグローバルラベルに属するローカルラベル
+pub fn is_local(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+/// ローカルラベルをスコープで修飾した名前 (`Main.loop` + `.end` → `Main.loop.end`)
+pub fn qualify(name: &str, scope: &str) -> String {
+    if is_local(name) {
+        format!("{}{}", scope, name)
+    } else {
+        name.to_owned()
+    }
+}
+
+/// 各コマンドが属するスコープ (直前のグローバルラベル、なければ空文字列)
+pub fn scopes(commands: &[Command]) -> Vec<&str> {
+    let mut scope = "";
+    commands
+        .iter()
+        .map(|c| {
+            if let CommandKind::L(LabelCommand { label }) = &c.value {
+                if !is_local(label) {
+                    scope = label;
+                }
+            }
+            scope
+        })
+        .collect()
+}
 
 pub struct SymbolTable {
-    table: HashMap<String, Address>,
+    /// 定義済みシンボル
+    predefined: HashMap<String, Address>,
+    /// ラベル (ROMアドレス)
+    labels: HashMap<String, Address>,
+    /// 変数 (RAMアドレス)
+    variables: HashMap<String, Address>,
+    /// `.equ`で定義した定数
+    constants: HashMap<String, Address>,
+    /// ローカルラベルの修飾した名前と、(スコープ, 元の名前)
+    locals: HashMap<String, (String, String)>,
+    /// `.var`で確保したRAMの区間 [start, end)
+    blocks: Vec<(Address, Address)>,
+    /// 予約した区間と固定したアドレス
+    reserved: Vec<RamRange>,
+    /// 次の`.var`を置くアドレス
+    block_cursor: Address,
+    /// `.org`で`.var`の位置を指定したか (指定がなければ予約した区間を避ける)
+    org_given: bool,
     /// 利用可能なアドレス
     vacant: Address,
 }
 
 impl SymbolTable {
     pub fn new() -> Self {
+        Self::with_layout(&RamLayout::default())
+    }
+
+    /// 変数の配置を指定して作る (`layout`は`RamLayout::validate`で検査済みとする)
+    pub fn with_layout(layout: &RamLayout) -> Self {
         let mut mp = HashMap::<String, Address>::new();
 
         // predefineds
@@ -37,74 +205,447 @@ impl SymbolTable {
             mp.insert(s, i);
         }
 
+        let variables = layout
+            .fixed
+            .iter()
+            .map(|f| (f.name.clone(), f.address))
+            .collect();
         Self {
-            table: mp,
-            vacant: 16,
+            predefined: mp,
+            labels: HashMap::new(),
+            variables,
+            constants: HashMap::new(),
+            locals: HashMap::new(),
+            blocks: vec![],
+            reserved: layout.avoided(),
+            block_cursor: layout.variable_base,
+            org_given: false,
+            vacant: layout.variable_base,
         }
     }
 
     pub fn get_address(&self, symbol: &str) -> Option<&Address> {
-        self.table.get(&symbol.to_string())
+        self.predefined
+            .get(symbol)
+            .or_else(|| self.labels.get(symbol))
+            .or_else(|| self.constants.get(symbol))
+            .or_else(|| self.variables.get(symbol))
+    }
+
+    /// `scope`で使われたシンボルのアドレス (グローバルな名前なら文字列を作らずに引く)
+    fn get_scoped(&self, symbol: &str, scope: &str) -> Option<&Address> {
+        if is_local(symbol) {
+            self.get_address(&qualify(symbol, scope))
+        } else {
+            self.get_address(symbol)
+        }
+    }
+
+    /// 定義済みシンボル (`SP`, `R0`..`R15`, `SCREEN`, `KBD`など)
+    pub fn predefined(&self) -> &HashMap<String, Address> {
+        &self.predefined
+    }
+
+    /// ラベルとそのROMアドレス
+    pub fn labels(&self) -> &HashMap<String, Address> {
+        &self.labels
+    }
+
+    /// 変数とそのRAMアドレス (`.var`のブロックは先頭アドレス)
+    pub fn variables(&self) -> &HashMap<String, Address> {
+        &self.variables
     }
 
-    pub fn resolve(&mut self, commands: &Vec<Command>) -> Result<(), SymTableError> {
-        let mut line_num: Address = 0;
+    /// `.var`で確保したRAMの区間 [start, end)
+    pub fn blocks(&self) -> &[(Address, Address)] {
+        &self.blocks
+    }
+
+    /// ローカルラベルを除いたラベル
+    pub fn global_labels(&self) -> impl Iterator<Item = (&String, &Address)> {
+        self.labels
+            .iter()
+            .filter(move |(label, _)| !self.locals.contains_key(label.as_str()))
+    }
+
+    /// `.equ`で定義した定数
+    pub fn constants(&self) -> &HashMap<String, Address> {
+        &self.constants
+    }
+
+    /// エラーがあっても最後まで解決し、すべてのエラーを返す
+    pub fn resolve(&mut self, commands: &[Command]) -> Result<(), Vec<SymTableError>> {
+        let mut errors = self.resolve_labels(commands).err().unwrap_or_default();
         for c in commands.iter() {
+            if let Annot {
+                value: CommandKind::D(DirectiveCommand::Extern { name }),
+                loc,
+            } = c
+            {
+                // 1つのプログラムとしてアセンブルするなら、同じプログラムにあるはず
+                if !self.labels.contains_key(name) {
+                    errors.push(SymTableError::undefined_extern(name, loc.clone()));
+                }
+            }
+        }
+        // 最後に、未定義のシンボルを変数にする
+        for c in commands.iter() {
+            if let Annot {
+                value: CommandKind::A(AddrCommand { value }),
+                loc,
+            } = c
+            {
+                for symbol in value.symbols().into_iter().filter(|s| !is_local(s)) {
+                    if let Err(e) = self.add_symbol(symbol, loc) {
+                        errors.push(e);
+                    }
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// ラベルと指令だけを解決し、変数は割り当てない
+    /// ローカルラベルの参照もここで検査する
+    pub fn resolve_labels(&mut self, commands: &[Command]) -> Result<(), Vec<SymTableError>> {
+        let mut errors = vec![];
+        let scopes = scopes(commands);
+        let mut line_num: usize = 0;
+        for (c, scope) in commands.iter().zip(&scopes) {
             match c {
                 Annot {
                     value: CommandKind::L(LabelCommand { label, .. }),
-                    ..
+                    loc,
                 } => {
-                    self.add_label(&label, line_num)?;
+                    if let Err(e) = self.add_scoped_label(label, scope, line_num, loc) {
+                        errors.push(e);
+                    }
+                }
+                Annot {
+                    value: CommandKind::D(_),
+                    ..
+                } => (),
+                Annot { loc, .. } => {
+                    // ROMからはみ出た最初の命令だけ報告する
+                    if line_num == ROM_SIZE {
+                        errors.push(SymTableError::rom_limit(loc.clone()));
+                    }
+                    line_num += 1;
+                }
+            }
+        }
+        // 指令はラベルを集め終えてから、書かれた順に解決する
+        for (c, scope) in commands.iter().zip(&scopes) {
+            if let Annot {
+                value: CommandKind::D(directive),
+                loc,
+            } = c
+            {
+                if let Err(e) = self.add_directive(directive, scope, loc) {
+                    errors.push(e);
+                }
+            }
+        }
+        for (c, scope) in commands.iter().zip(&scopes) {
+            if let Annot {
+                value: CommandKind::A(AddrCommand { value }),
+                loc,
+            } = c
+            {
+                for symbol in value.symbols().into_iter().filter(|s| is_local(s)) {
+                    if let Err(e) = self.check_local(symbol, scope, loc) {
+                        errors.push(e);
+                    }
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// `resolve`の`Statement`版
+    /// テキストを持たないので、エラー位置には文の番号を入れる
+    pub fn resolve_statements(&mut self, program: &[Statement]) -> Result<(), Vec<SymTableError>> {
+        let mut errors = vec![];
+        let mut line_num: usize = 0;
+        let mut scope = "";
+        for (i, stmt) in program.iter().enumerate() {
+            let loc = Loc::new(i, i + 1);
+            match stmt {
+                Statement::Label(label) => {
+                    if !is_local(label) {
+                        scope = label;
+                    }
+                    if let Err(e) = self.add_scoped_label(label, scope, line_num, &loc) {
+                        errors.push(e);
+                    }
                 }
                 _ => {
+                    if line_num == ROM_SIZE {
+                        errors.push(SymTableError::rom_limit(loc));
+                    }
                     line_num += 1;
                 }
             }
         }
-        for c in commands.iter() {
-            match c {
-                Annot {
-                    value:
-                        CommandKind::A(AddrCommand {
-                            value: NumOrSymbol::Symbol(symbol),
-                            ..
-                        }),
-                    ..
-                } => {
-                    self.add_symbol(&symbol)?;
+        let mut scope = "";
+        for (i, stmt) in program.iter().enumerate() {
+            match stmt {
+                Statement::Label(label) if !is_local(label) => scope = label,
+                Statement::Symbol(symbol) => {
+                    let loc = Loc::new(i, i + 1);
+                    let res = if is_local(symbol) {
+                        self.check_local(symbol, scope, &loc)
+                    } else {
+                        self.add_symbol(symbol, &loc)
+                    };
+                    if let Err(e) = res {
+                        errors.push(e);
+                    }
                 }
                 _ => (),
             }
         }
 
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// A命令の値を評価する
+    /// 解決に失敗したシンボルはエラー報告済みなので0とみなす
+    /// 途中で`i64`に収まらなくなるか、0で割れば`None`
+    /// ローカルラベルは`scope`で修飾して引く
+    pub fn eval(&self, expr: &Expr, scope: &str) -> Option<i64> {
+        match expr {
+            Expr::Num(n) => i64::try_from(*n).ok(),
+            Expr::Symbol(s) => {
+                let address = self.get_scoped(s, scope).copied();
+                Some(address.unwrap_or(0) as i64)
+            }
+            Expr::BinOp { op, l, r } => {
+                let l = self.eval(l, scope)?;
+                let r = self.eval(r, scope)?;
+                match op {
+                    ExprOpKind::Add => l.checked_add(r),
+                    ExprOpKind::Sub => l.checked_sub(r),
+                    ExprOpKind::Mul => l.checked_mul(r),
+                    // 0への丸め、0で割れば`None`
+                    ExprOpKind::Div => l.checked_div(r),
+                    ExprOpKind::Shl => {
+                        let shift = u32::try_from(r).ok().filter(|s| *s < 63)?;
+                        l.checked_mul(1 << shift)
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_directive(
+        &mut self,
+        directive: &DirectiveCommand,
+        scope: &str,
+        loc: &Loc,
+    ) -> Result<(), SymTableError> {
+        match directive {
+            DirectiveCommand::Equ { name, value } => {
+                self.check_new_symbol(name, loc)?;
+                let value = self.eval_directive(value, scope, loc)?;
+                if !(0..=MAX_VALUE).contains(&value) {
+                    return Err(SymTableError::value_out_of_range(value, loc.clone()));
+                }
+                self.constants.insert(name.clone(), value as Address);
+            }
+            DirectiveCommand::Org { address } => {
+                let address = self.eval_directive(address, scope, loc)?;
+                if !(0..AVAILABLE_ADDRESS_END as i64).contains(&address) {
+                    return Err(SymTableError::address_limit(loc.clone()));
+                }
+                self.block_cursor = address as Address;
+                self.org_given = true;
+            }
+            DirectiveCommand::Var { name, size } => {
+                self.check_new_symbol(name, loc)?;
+                let size = self.eval_directive(size, scope, loc)?;
+                if size < 1 {
+                    return Err(SymTableError::invalid_block_size(size, loc.clone()));
+                }
+                let mut start = self.block_cursor;
+                let end = loop {
+                    let end = start as i64 + size;
+                    if end > AVAILABLE_ADDRESS_END as i64 {
+                        return Err(SymTableError::address_limit(loc.clone()));
+                    }
+                    let end = end as Address;
+                    match self.reserved.iter().find(|r| r.overlaps(start, end)) {
+                        // 位置を指定していなければ、予約した区間の後ろにずらす
+                        Some(r) if !self.org_given => start = r.end,
+                        Some(_) => return Err(SymTableError::reserved_overlap(name, loc.clone())),
+                        None => break end,
+                    }
+                };
+                if self.blocks.iter().any(|(s, e)| start < *e && *s < end) {
+                    return Err(SymTableError::block_overlap(name, loc.clone()));
+                }
+                self.blocks.push((start, end));
+                self.variables.insert(name.clone(), start);
+                self.block_cursor = end;
+            }
+            // 構文解析の後で展開済み
+            DirectiveCommand::Include { .. } => (),
+            // リンク時に解決する
+            DirectiveCommand::Extern { .. } => (),
+        }
         Ok(())
     }
 
-    fn check_address_limit(&self) -> Result<(), SymTableError> {
+    fn check_new_symbol(&self, name: &str, loc: &Loc) -> Result<(), SymTableError> {
+        if self.get_address(name).is_some() {
+            return Err(SymTableError::duplicate_symbol(name, loc.clone()));
+        }
+        Ok(())
+    }
+
+    /// 指令の値には、それまでに定義されたシンボルしか使えない
+    fn eval_directive(&self, expr: &Expr, scope: &str, loc: &Loc) -> Result<i64, SymTableError> {
+        if let Some(s) = expr
+            .symbols()
+            .into_iter()
+            .find(|s| self.get_scoped(s, scope).is_none())
+        {
+            return Err(SymTableError::undefined_symbol(s, loc.clone()));
+        }
+        self.eval(expr, scope)
+            .ok_or_else(|| SymTableError::value_overflow(loc.clone()))
+    }
+
+    /// `.var`のブロックと予約した区間を避けた、次の空きアドレス
+    fn skip_blocks(&mut self) {
+        loop {
+            let vacant = self.vacant;
+            let block = self
+                .blocks
+                .iter()
+                .find(|(start, end)| (*start..*end).contains(&vacant))
+                .map(|(_, end)| *end);
+            let reserved = || {
+                self.reserved
+                    .iter()
+                    .find(|r| r.contains(vacant))
+                    .map(|r| r.end)
+            };
+            match block.or_else(reserved) {
+                Some(end) => self.vacant = end,
+                None => break,
+            }
+        }
+    }
+
+    fn check_address_limit(&self, loc: &Loc) -> Result<(), SymTableError> {
         if self.vacant >= AVAILABLE_ADDRESS_END {
-            Err(SymTableError::AddressLimit)
+            Err(SymTableError::address_limit(loc.clone()))
         } else {
             Ok(())
         }
     }
 
-    fn add_symbol(&mut self, symbol: &str) -> Result<(), SymTableError> {
-        self.check_address_limit()?;
+    /// 未定義のシンボルは変数としてRAMを割り当てる
+    pub(crate) fn add_symbol(&mut self, symbol: &str, loc: &Loc) -> Result<(), SymTableError> {
+        if self.get_address(symbol).is_some() {
+            return Ok(());
+        }
+        self.skip_blocks();
+        self.check_address_limit(loc)?;
+
+        self.variables.insert(symbol.to_string(), self.vacant);
+        self.vacant += 1;
+        Ok(())
+    }
+
+    /// ローカルラベルは使われたスコープで定義されていなければならない
+    fn check_local(&self, label: &str, scope: &str, loc: &Loc) -> Result<(), SymTableError> {
+        if self.labels.contains_key(&qualify(label, scope)) {
+            return Ok(());
+        }
+        // 別のスコープにあれば、最初に定義されたものを示す
+        let defined = self
+            .locals
+            .iter()
+            .filter(|(_, (_, name))| name == label)
+            .min_by_key(|(key, _)| self.labels[key.as_str()]);
+        match defined {
+            Some((_, (defined, _))) => Err(SymTableError::local_out_of_scope(
+                label,
+                scope,
+                defined,
+                loc.clone(),
+            )),
+            None => Err(SymTableError::undefined_local_label(
+                label,
+                scope,
+                loc.clone(),
+            )),
+        }
+    }
 
-        let vacant = &mut self.vacant;
-        self.table.entry(symbol.to_string()).or_insert_with(|| {
-            let address = vacant.clone();
-            *vacant += 1;
-            address
-        });
+    /// ローカルラベルは修飾した名前で登録する
+    /// 修飾した名前がグローバルラベルと重なれば、どちらを指すか決められない
+    fn add_scoped_label(
+        &mut self,
+        label: &str,
+        scope: &str,
+        address: usize,
+        loc: &Loc,
+    ) -> Result<(), SymTableError> {
+        if !is_local(label) {
+            if self.locals.contains_key(label) {
+                return Err(SymTableError::ambiguous_label(label, loc.clone()));
+            }
+            return self.add_label(label, address, loc);
+        }
+        let key = qualify(label, scope);
+        if self.labels.contains_key(&key) && !self.locals.contains_key(&key) {
+            return Err(SymTableError::ambiguous_label(&key, loc.clone()));
+        }
+        self.add_label(&key, address, loc)?;
+        self.locals
+            .insert(key, (scope.to_owned(), label.to_owned()));
         Ok(())
     }
 
-    fn add_label(&mut self, label: &str, address: Address) -> Result<(), SymTableError> {
-        self.check_address_limit()?;
+    pub(crate) fn add_label(
+        &mut self,
+        label: &str,
+        address: usize,
+        loc: &Loc,
+    ) -> Result<(), SymTableError> {
+        // プログラム末尾を指すラベルもROMに収まっていなければならない
+        if address >= ROM_SIZE {
+            return Err(SymTableError::rom_limit(loc.clone()));
+        }
+        if self.predefined.contains_key(label) {
+            return Err(SymTableError::predefined_label(label, loc.clone()));
+        }
+        if self.labels.contains_key(label) {
+            return Err(SymTableError::duplicate_label(label, loc.clone()));
+        }
+        // アドレスを固定した変数
+        if self.variables.contains_key(label) {
+            return Err(SymTableError::duplicate_symbol(label, loc.clone()));
+        }
 
-        self.table.entry(label.to_string()).or_insert(address);
+        self.labels.insert(label.to_string(), address as Address);
         Ok(())
     }
 }
@@ -118,11 +659,245 @@ mod tests {
         let mut table = SymbolTable::new();
         for i in 16..AVAILABLE_ADDRESS_END {
             let s = format!("a{}", i);
-            let actual = table.add_symbol(&s);
+            let actual = table.add_symbol(&s, &Loc::new(0, 0));
             assert!(actual.is_ok());
         }
 
-        let actual = table.add_symbol("hoge");
+        let actual = table.add_symbol("hoge", &Loc::new(0, 0));
         assert!(actual.is_err(), "available address reached the upper limit");
+        // 割り当て済みの変数は参照できる
+        let actual = table.add_symbol("a16", &Loc::new(0, 0));
+        assert!(actual.is_ok());
+    }
+
+    #[test]
+    fn test_add_label_rom_space() {
+        let mut table = SymbolTable::new();
+        // ラベルはRAMの上限とは無関係
+        let actual = table.add_label("HIGH", 0x7fff, &Loc::new(0, 0));
+        assert!(actual.is_ok());
+        assert_eq!(table.get_address("HIGH"), Some(&0x7fff));
+
+        let actual = table.add_label("OVER", ROM_SIZE, &Loc::new(0, 0));
+        assert_eq!(actual, Err(SymTableError::rom_limit(Loc::new(0, 0))));
+    }
+
+    #[test]
+    fn test_eval_expr() {
+        let mut table = SymbolTable::new();
+        table.add_label("LOOP", 10, &Loc::new(0, 0)).unwrap();
+        let sym = |s: &str| Expr::Symbol(s.to_owned());
+
+        let expr = Expr::binop(ExprOpKind::Add, sym("SCREEN"), Expr::Num(32));
+        assert_eq!(table.eval(&expr, ""), Some(0x4020));
+        let expr = Expr::binop(ExprOpKind::Sub, sym("KBD"), Expr::Num(1));
+        assert_eq!(table.eval(&expr, ""), Some(0x5fff));
+        let expr = Expr::binop(ExprOpKind::Shl, sym("LOOP"), Expr::Num(2));
+        assert_eq!(table.eval(&expr, ""), Some(40));
+        let expr = Expr::binop(ExprOpKind::Sub, Expr::Num(1), sym("LOOP"));
+        assert_eq!(table.eval(&expr, ""), Some(-9));
+
+        let expr = Expr::binop(ExprOpKind::Shl, Expr::Num(1), Expr::Num(64));
+        assert_eq!(table.eval(&expr, ""), None);
+        let expr = Expr::binop(ExprOpKind::Mul, Expr::Num(u64::MAX), Expr::Num(2));
+        assert_eq!(table.eval(&expr, ""), None);
+    }
+
+    #[test]
+    fn test_resolve_directives() {
+        use crate::parser::{lexer, parse_all};
+
+        let input = r###"
+.equ WIDTH 32
+.equ ROW SCREEN+WIDTH
+.var buf 4
+@i
+.org 100
+.var table WIDTH*2
+@buf
+@j
+@ROW
+"###;
+        let (tokens, _) = lexer::lex_all(input);
+        let (commands, errors) = parse_all(tokens, input);
+        assert!(errors.is_empty());
+        let mut table = SymbolTable::new();
+        table.resolve(&commands).unwrap();
+
+        // 定数はRAMを消費しない
+        assert_eq!(table.get_address("WIDTH"), Some(&32));
+        assert_eq!(table.get_address("ROW"), Some(&0x4020));
+        assert_eq!(table.get_address("buf"), Some(&16));
+        assert_eq!(table.get_address("table"), Some(&100));
+        // 変数はブロックを避けて割り当てる
+        assert_eq!(table.get_address("i"), Some(&20));
+        assert_eq!(table.get_address("j"), Some(&21));
+        assert_eq!(table.variables().len(), 4);
+        assert_eq!(table.constants().len(), 2);
+    }
+
+    #[test]
+    fn test_resolve_directive_errors() {
+        use crate::parser::{lexer, parse_all};
+
+        let input = ".equ A1 X\n.equ R0 1\n.equ BIG 0x8000\n.org 20\n.var a 4\n.org 18\n.var b 4\n.var c 0\n.extern F\n";
+        let (tokens, _) = lexer::lex_all(input);
+        let (commands, _) = parse_all(tokens, input);
+        let mut table = SymbolTable::new();
+        let actual: Vec<SymTableErrorKind> = table
+            .resolve(&commands)
+            .unwrap_err()
+            .into_iter()
+            .map(|e| e.value)
+            .collect();
+        assert_eq!(
+            actual,
+            vec![
+                SymTableErrorKind::UndefinedSymbol("X".to_owned()),
+                SymTableErrorKind::DuplicateSymbol("R0".to_owned()),
+                SymTableErrorKind::ValueOutOfRange(0x8000),
+                SymTableErrorKind::BlockOverlap("b".to_owned()),
+                SymTableErrorKind::InvalidBlockSize(0),
+                SymTableErrorKind::UndefinedExtern("F".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_with_layout() {
+        use crate::parser::{lexer, parse_all};
+
+        let input = "@a\n.var buf 4\n@b\n@sys\n.org 0x30\n.var c 1\n(sys2)\n";
+        let (tokens, _) = lexer::lex_all(input);
+        let (commands, _) = parse_all(tokens, input);
+        let layout = RamLayout {
+            variable_base: 0x20,
+            reserved: vec!["0x22..0x28".parse().unwrap(), "0x30..0x40".parse().unwrap()],
+            fixed: vec!["sys=0x29".parse().unwrap(), "sys2=0x100".parse().unwrap()],
+        };
+        let mut table = SymbolTable::with_layout(&layout);
+        let actual: Vec<SymTableErrorKind> = table
+            .resolve(&commands)
+            .unwrap_err()
+            .into_iter()
+            .map(|e| e.value)
+            .collect();
+        assert_eq!(
+            actual,
+            vec![
+                SymTableErrorKind::DuplicateSymbol("sys2".to_owned()),
+                SymTableErrorKind::ReservedOverlap("c".to_owned()),
+            ]
+        );
+        // `.var`は予約した区間の後ろに、変数は予約した区間と固定したアドレスを避けて置く
+        assert_eq!(table.get_address("buf"), Some(&0x2a));
+        assert_eq!(table.get_address("a"), Some(&0x20));
+        assert_eq!(table.get_address("b"), Some(&0x21));
+        assert_eq!(table.get_address("sys"), Some(&0x29));
+    }
+
+    #[test]
+    fn test_resolve_local_labels() {
+        use crate::parser::{lexer, parse_all};
+
+        let input = r###"
+(.init)
+@.init
+(Main.main)
+(.loop)
+@.loop
+0;JMP
+(Main.sub)
+(.loop)
+@Main.main.loop
+0;JMP
+"###;
+        let (tokens, _) = lexer::lex_all(input);
+        let (commands, errors) = parse_all(tokens, input);
+        assert!(errors.is_empty());
+        let mut table = SymbolTable::new();
+        table.resolve(&commands).unwrap();
+
+        assert_eq!(table.get_address(".init"), Some(&0));
+        assert_eq!(table.get_address("Main.main.loop"), Some(&1));
+        assert_eq!(table.get_address("Main.sub.loop"), Some(&3));
+        // ローカルラベルは変数にならない
+        assert!(table.variables().is_empty());
+        let expr = Expr::Symbol(".loop".to_owned());
+        assert_eq!(table.eval(&expr, "Main.sub"), Some(3));
+    }
+
+    #[test]
+    fn test_resolve_local_label_errors() {
+        use crate::parser::{lexer, parse_all};
+
+        let input = "(Foo)\n(.x)\n(.x)\n(Bar)\n@.x\n@.y\n(Foo.x)\n";
+        let (tokens, _) = lexer::lex_all(input);
+        let (commands, _) = parse_all(tokens, input);
+        let mut table = SymbolTable::new();
+        let actual: Vec<SymTableErrorKind> = table
+            .resolve(&commands)
+            .unwrap_err()
+            .into_iter()
+            .map(|e| e.value)
+            .collect();
+        assert_eq!(
+            actual,
+            vec![
+                SymTableErrorKind::DuplicateLabel("Foo.x".to_owned()),
+                SymTableErrorKind::AmbiguousLabel("Foo.x".to_owned()),
+                SymTableErrorKind::LocalOutOfScope {
+                    label: ".x".to_owned(),
+                    scope: "Bar".to_owned(),
+                    defined: "Foo".to_owned(),
+                },
+                SymTableErrorKind::UndefinedLocalLabel {
+                    label: ".y".to_owned(),
+                    scope: "Bar".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(
+            actual[2].to_string(),
+            "local label `.x` is used in the scope of `Bar` but defined in the scope of `Foo`"
+        );
+    }
+
+    #[test]
+    fn test_resolve_statements() {
+        let program = vec![
+            Statement::Symbol("i".to_owned()),
+            Statement::Label("LOOP".to_owned()),
+            Statement::Symbol("LOOP".to_owned()),
+            Statement::Symbol("j".to_owned()),
+            Statement::Label("LOOP".to_owned()),
+        ];
+        let mut table = SymbolTable::new();
+        let actual = table.resolve_statements(&program);
+        assert_eq!(
+            actual,
+            Err(vec![SymTableError::duplicate_label("LOOP", Loc::new(4, 5))])
+        );
+        assert_eq!(table.get_address("LOOP"), Some(&1));
+        assert_eq!(table.get_address("i"), Some(&16));
+        assert_eq!(table.get_address("j"), Some(&17));
+    }
+
+    #[test]
+    fn test_add_label_duplicate() {
+        let mut table = SymbolTable::new();
+        assert!(table.add_label("LOOP", 0, &Loc::new(0, 6)).is_ok());
+        let actual = table.add_label("LOOP", 4, &Loc::new(10, 16));
+        assert_eq!(
+            actual,
+            Err(SymTableError::duplicate_label("LOOP", Loc::new(10, 16)))
+        );
+        assert_eq!(table.get_address("LOOP"), Some(&0));
+
+        let actual = table.add_label("R1", 4, &Loc::new(20, 24));
+        assert_eq!(
+            actual,
+            Err(SymTableError::predefined_label("R1", Loc::new(20, 24)))
+        );
     }
 }
@@ -0,0 +1,97 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Clap;
+use hack_assembler::parse_address;
+use hack_emulator::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clap, Debug)]
+#[clap(name = env!("CARGO_BIN_NAME"), version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"))]
+struct Opts {
+    /// .hackか.asmファイル、または.tstスクリプト
+    #[clap(name = "FILE")]
+    path: PathBuf,
+    /// 実行する最大サイクル数
+    #[clap(short = 'n', long = "cycles", default_value = "1000000")]
+    cycles: u64,
+    /// 実行前にRAMへ書き込む値 (`ADDRESS=VALUE`)
+    #[clap(long = "set", number_of_values = 1, parse(try_from_str = parse_poke))]
+    set: Vec<Poke>,
+    /// 実行後に表示するRAMのアドレス
+    #[clap(long = "ram", number_of_values = 1, parse(try_from_str = parse_address))]
+    ram: Vec<u16>,
+}
+
+#[derive(Debug)]
+struct Poke {
+    address: u16,
+    value: u16,
+}
+
+/// 値は負の数も書ける (16bitの2の補数)
+fn parse_poke(s: &str) -> Result<Poke> {
+    let (address, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("invalid `{}` (expected ADDRESS=VALUE)", s))?;
+    let address = parse_address(address.trim())?;
+    let value = value.trim();
+    let value = match value.parse::<i16>() {
+        Ok(n) => n as u16,
+        Err(_) => parse_address(value)?,
+    };
+    Ok(Poke { address, value })
+}
+
+/// .outを書き出し、.cmpと食い違った行をすべて示す
+fn run_test(path: &Path) -> Result<()> {
+    let report = run_script(path).with_context(|| format!("{}", path.display()))?;
+    if let Some(out) = report.output_file.as_ref() {
+        fs::write(out, &report.output).with_context(|| format!("cannot write {:?}", out))?;
+    }
+    let cmp = match report.compare_file.as_ref() {
+        Some(cmp) => cmp,
+        None => {
+            println!("{}: finished", path.display());
+            return Ok(());
+        }
+    };
+    if report.passed() {
+        println!("{}: comparison ended successfully", path.display());
+        return Ok(());
+    }
+    for mismatch in report.mismatches.iter() {
+        eprintln!("{}", mismatch);
+    }
+    bail!(
+        "{}: {} line(s) differ from {}",
+        path.display(),
+        report.mismatches.len(),
+        cmp.display()
+    )
+}
+
+fn main() -> Result<()> {
+    let opts = Opts::parse();
+    if opts.path.extension().and_then(|ext| ext.to_str()) == Some("tst") {
+        return run_test(&opts.path);
+    }
+    let mut computer = Computer::from_file(&opts.path)?;
+    for Poke { address, value } in opts.set.iter() {
+        computer.write(*address, *value);
+    }
+
+    match computer.run(opts.cycles) {
+        Stop::Halted => println!("Halted after {} cycles", computer.cycles()),
+        Stop::CycleLimit => println!("Stopped after {} cycles (limit)", computer.cycles()),
+    }
+    println!(
+        "PC={} A={} D={}",
+        computer.pc(),
+        computer.a() as i16,
+        computer.d() as i16
+    );
+    for address in opts.ram.iter() {
+        println!("RAM[{}] = {}", address, computer.read(*address) as i16);
+    }
+    Ok(())
+}
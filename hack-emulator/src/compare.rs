@@ -0,0 +1,244 @@
+use std::fmt;
+
+/// `output-list`の1列 (`RAM[0]%D2.6.2`なら`D`で、左2・幅6・右2の空白)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Column {
+    pub name: String,
+    pub format: Format,
+    pub left: usize,
+    pub width: usize,
+    pub right: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// 2進数 (下位`width`bit)
+    Binary,
+    /// 符号付き10進数
+    Decimal,
+    /// 16進数 (下位`width`桁)
+    Hex,
+    /// 文字列 (左寄せ)
+    String,
+}
+
+/// 出力する値
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Value {
+    Word(u16),
+    /// `tick`のあとは`3+`のように`+`がつく
+    Time {
+        time: u64,
+        tick: bool,
+    },
+}
+
+impl Column {
+    /// `name%FMT.L.W.R`を読む (書式を省略すれば`%B1.16.1`)
+    pub fn parse(s: &str) -> Option<Self> {
+        let (name, fmt) = match s.split_once('%') {
+            Some((name, fmt)) => (name, fmt),
+            None => (s, "B1.16.1"),
+        };
+        let mut chars = fmt.chars();
+        let format = match chars.next()? {
+            'B' => Format::Binary,
+            'D' => Format::Decimal,
+            'X' => Format::Hex,
+            'S' => Format::String,
+            _ => return None,
+        };
+        let mut widths = chars.as_str().split('.').map(|n| n.parse::<usize>());
+        let (left, width, right) = match (widths.next(), widths.next(), widths.next()) {
+            (Some(Ok(l)), Some(Ok(w)), Some(Ok(r))) => (l, w, r),
+            _ => return None,
+        };
+        if name.is_empty() || widths.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            name: name.to_string(),
+            format,
+            left,
+            width,
+            right,
+        })
+    }
+
+    /// 見出しは列の幅に収まるよう中央寄せ (長ければ切り詰める)
+    pub fn header(&self) -> String {
+        let total = self.left + self.width + self.right;
+        let name: String = self.name.chars().take(total).collect();
+        let pad = total - name.chars().count();
+        format!(
+            "{}{}{}",
+            " ".repeat(pad / 2),
+            name,
+            " ".repeat(pad - pad / 2)
+        )
+    }
+
+    pub fn cell(&self, value: Value) -> String {
+        let text = match (self.format, value) {
+            (Format::Binary, Value::Word(w)) => low_digits(format!("{:016b}", w), self.width),
+            (Format::Hex, Value::Word(w)) => low_digits(format!("{:04X}", w), self.width),
+            (_, Value::Word(w)) => (w as i16).to_string(),
+            (_, Value::Time { time, tick }) => format!("{}{}", time, if tick { "+" } else { "" }),
+        };
+        let text = if self.format == Format::String {
+            format!("{:<1$}", text, self.width)
+        } else {
+            format!("{:>1$}", text, self.width)
+        };
+        format!(
+            "{}{}{}",
+            " ".repeat(self.left),
+            text,
+            " ".repeat(self.right)
+        )
+    }
+}
+
+/// 下位`width`桁 (足りなければ0で埋める)
+fn low_digits(digits: String, width: usize) -> String {
+    if digits.len() >= width {
+        digits[digits.len() - width..].to_string()
+    } else {
+        format!("{:0>1$}", digits, width)
+    }
+}
+
+/// `|`で区切った1行
+pub fn table_row(cells: impl Iterator<Item = String>) -> String {
+    let mut row = String::from("|");
+    for cell in cells {
+        row.push_str(&cell);
+        row.push('|');
+    }
+    row
+}
+
+/// .outと.cmpの食い違った行
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Mismatch {
+    /// 1始まりの行番号
+    pub line: usize,
+    /// .cmpの行 (なければ.outの方が長い)
+    pub expected: Option<String>,
+    /// .outの行 (なければ.cmpの方が長い)
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.expected, &self.actual) {
+            (Some(expected), Some(actual)) => write!(
+                f,
+                "line {}:\n  expected: {}\n  actual:   {}",
+                self.line, expected, actual
+            ),
+            (Some(expected), None) => write!(
+                f,
+                "line {}: missing output\n  expected: {}",
+                self.line, expected
+            ),
+            (None, Some(actual)) => write!(
+                f,
+                "line {}: unexpected output\n  actual:   {}",
+                self.line, actual
+            ),
+            (None, None) => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// .cmpの`*`は任意の1文字に一致する (行末の空白と改行コードは無視する)
+pub fn lines_match(expected: &str, actual: &str) -> bool {
+    let expected = expected.trim_end();
+    let actual = actual.trim_end();
+    expected.chars().count() == actual.chars().count()
+        && expected
+            .chars()
+            .zip(actual.chars())
+            .all(|(e, a)| e == '*' || e == a)
+}
+
+/// 行ごとに比べて、食い違った行をすべて返す
+pub fn compare(expected: &str, actual: &str) -> Vec<Mismatch> {
+    let mut expected = expected.lines();
+    let mut actual = actual.lines();
+    let mut mismatches = vec![];
+    for line in 1.. {
+        match (expected.next(), actual.next()) {
+            (None, None) => break,
+            (Some(e), Some(a)) if lines_match(e, a) => {}
+            (e, a) => mismatches.push(Mismatch {
+                line,
+                expected: e.map(|e| e.trim_end().to_string()),
+                actual: a.map(|a| a.trim_end().to_string()),
+            }),
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_column() {
+        let column = Column::parse("RAM[0]%D2.6.2").unwrap();
+        assert_eq!(column.header(), "  RAM[0]  ");
+        assert_eq!(column.cell(Value::Word(0xffff)), "      -1  ");
+
+        let column = Column::parse("addressM%D0.5.0").unwrap();
+        assert_eq!(column.header(), "addre");
+        let column = Column::parse("instruction%B0.16.0").unwrap();
+        assert_eq!(column.header(), "  instruction   ");
+        assert_eq!(column.cell(Value::Word(12345)), "0011000000111001");
+        let column = Column::parse("reset%B2.1.2").unwrap();
+        assert_eq!(column.cell(Value::Word(1)), "  1  ");
+        let column = Column::parse("time%S1.4.1").unwrap();
+        assert_eq!(
+            column.cell(Value::Time {
+                time: 15,
+                tick: true
+            }),
+            " 15+  "
+        );
+        let column = Column::parse("KBD%X1.2.1").unwrap();
+        assert_eq!(column.cell(Value::Word(0x8041)), " 41 ");
+
+        assert_eq!(Column::parse("pc").unwrap().format, Format::Binary);
+        assert_eq!(Column::parse("pc%Q1.2.3"), None);
+        assert_eq!(Column::parse("pc%D1.2"), None);
+    }
+
+    #[test]
+    fn test_compare() {
+        let expected = "|a|  b  |\n|0|*****|\n|1|    2|\n|2|    3|\n";
+        assert!(compare(expected, "|a|  b  |\r\n|0|   -1|\n|1|    2|\n|2|    3|").is_empty());
+
+        let mismatches = compare(expected, "|a|  b  |\n|0|   -1|\n|1|    5|\n");
+        assert_eq!(
+            mismatches,
+            vec![
+                Mismatch {
+                    line: 3,
+                    expected: Some("|1|    2|".to_string()),
+                    actual: Some("|1|    5|".to_string()),
+                },
+                Mismatch {
+                    line: 4,
+                    expected: Some("|2|    3|".to_string()),
+                    actual: None,
+                },
+            ]
+        );
+        assert_eq!(
+            mismatches[0].to_string(),
+            "line 3:\n  expected: |1|    2|\n  actual:   |1|    5|"
+        );
+    }
+}
@@ -0,0 +1,354 @@
+use crate::rom::{load_program, LoadError, ROM_SIZE};
+
+use std::path::Path;
+
+/// RAMの大きさ (16K)
+pub const RAM_SIZE: usize = 0x4000;
+/// スクリーンの先頭アドレス (512x256ピクセル、1ワード16ピクセル)
+pub const SCREEN: u16 = 0x4000;
+pub const SCREEN_SIZE: usize = 0x2000;
+/// キーボードのアドレス (押されているキーのコード、なければ0)
+pub const KBD: u16 = 0x6000;
+
+/// `Computer::run`が止まった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stop {
+    /// 何も書き込まずに自分自身へ跳び続けている
+    Halted,
+    /// 指定したサイクル数を実行した
+    CycleLimit,
+}
+
+/// Hackコンピュータ (CPU、32K ROM、RAM・スクリーン・キーボードのデータメモリ)
+pub struct Computer {
+    rom: Vec<u16>,
+    /// RAM・スクリーン・キーボード (0..=KBD)
+    memory: Vec<u16>,
+    a: u16,
+    d: u16,
+    pc: u16,
+    cycles: u64,
+}
+
+impl Default for Computer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Computer {
+    /// ROMもRAMも0で埋まった状態
+    pub fn new() -> Self {
+        Self {
+            rom: vec![0; ROM_SIZE],
+            memory: vec![0; KBD as usize + 1],
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+        }
+    }
+
+    pub fn from_program(program: &[u16]) -> Result<Self, LoadError> {
+        let mut computer = Self::new();
+        computer.load(program)?;
+        Ok(computer)
+    }
+
+    /// .hackまたは.asmファイルを読み込む
+    pub fn from_file(path: &Path) -> Result<Self, LoadError> {
+        Self::from_program(&load_program(path)?)
+    }
+
+    /// ROMを書き換える (残りは0で埋め、レジスタとRAMはそのまま)
+    pub fn load(&mut self, program: &[u16]) -> Result<(), LoadError> {
+        if program.len() > ROM_SIZE {
+            return Err(LoadError::TooLarge(program.len()));
+        }
+        self.rom[..program.len()].copy_from_slice(program);
+        self.rom[program.len()..].iter_mut().for_each(|w| *w = 0);
+        Ok(())
+    }
+
+    pub fn rom(&self) -> &[u16] {
+        &self.rom
+    }
+
+    pub fn ram(&self) -> &[u16] {
+        &self.memory[..RAM_SIZE]
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u16] {
+        &mut self.memory[..RAM_SIZE]
+    }
+
+    pub fn screen(&self) -> &[u16] {
+        &self.memory[SCREEN as usize..KBD as usize]
+    }
+
+    pub fn keyboard(&self) -> u16 {
+        self.memory[KBD as usize]
+    }
+
+    pub fn set_keyboard(&mut self, key: u16) {
+        self.memory[KBD as usize] = key;
+    }
+
+    /// データメモリを読む (キーボードより後ろは0)
+    pub fn read(&self, address: u16) -> u16 {
+        self.memory.get(address as usize).copied().unwrap_or(0)
+    }
+
+    /// CPUからの書き込みと同じく、キーボードとそれより後ろへの書き込みは無視する
+    pub fn write(&mut self, address: u16, value: u16) {
+        if address < KBD {
+            self.memory[address as usize] = value;
+        }
+    }
+
+    pub fn a(&self) -> u16 {
+        self.a
+    }
+
+    pub fn d(&self) -> u16 {
+        self.d
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_a(&mut self, value: u16) {
+        self.a = value;
+    }
+
+    pub fn set_d(&mut self, value: u16) {
+        self.d = value;
+    }
+
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
+    }
+
+    /// これまでに実行したサイクル数
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// PCだけを0に戻す (CPUの`reset`と同じ)
+    pub fn reset(&mut self) {
+        self.pc = 0;
+    }
+
+    /// ROMのアドレスは下位15bit
+    fn fetch(&self, address: u16) -> u16 {
+        self.rom[address as usize % ROM_SIZE]
+    }
+
+    /// 1命令 (1サイクル) 実行する
+    pub fn step(&mut self) {
+        let instr = self.fetch(self.pc);
+        let next = self.pc.wrapping_add(1);
+        if instr & 0x8000 == 0 {
+            self.a = instr;
+            self.pc = next;
+        } else {
+            // 書き込み先とジャンプ先は実行前のA
+            let address = self.a;
+            let out = self.compute(instr, address);
+            if instr & 0x08 != 0 {
+                self.write(address, out);
+            }
+            if instr & 0x20 != 0 {
+                self.a = out;
+            }
+            if instr & 0x10 != 0 {
+                self.d = out;
+            }
+            self.pc = if jumps(instr, out) { address } else { next };
+        }
+        self.cycles += 1;
+    }
+
+    /// 止まったとみなせるまで、最大`max_cycles`サイクル実行する
+    pub fn run(&mut self, max_cycles: u64) -> Stop {
+        for _ in 0..max_cycles {
+            if self.is_halted() {
+                return Stop::Halted;
+            }
+            self.step();
+        }
+        if self.is_halted() {
+            Stop::Halted
+        } else {
+            Stop::CycleLimit
+        }
+    }
+
+    /// 何も書き込まずに自分自身へ跳び続ける状態 (`(END) @END 0;JMP`など) にあるか
+    pub fn is_halted(&self) -> bool {
+        let instr = self.fetch(self.pc);
+        if instr & 0x8000 == 0 {
+            // `@pc`の次の命令で`pc`へ跳ぶ
+            instr == self.pc && self.loops_back(self.fetch(self.pc.wrapping_add(1)), instr)
+        } else {
+            self.a == self.pc && self.loops_back(instr, self.a)
+        }
+    }
+
+    /// Aが`a`のとき、`instr`が何も書き込まずに`a`へ跳ぶか
+    fn loops_back(&self, instr: u16, a: u16) -> bool {
+        if instr & 0x8000 == 0 || instr & 0x38 != 0 {
+            return false;
+        }
+        // キーボードを待っているなら、キーが押されれば抜ける
+        if instr & 0x1000 != 0 && a == KBD {
+            return false;
+        }
+        jumps(instr, self.compute(instr, a))
+    }
+
+    /// C命令のcompを、Aが`a`のときの値で計算する
+    fn compute(&self, instr: u16, a: u16) -> u16 {
+        let y = if instr & 0x1000 != 0 { self.read(a) } else { a };
+        alu(instr, self.d, y)
+    }
+}
+
+/// c1..c6 (zx nx zy ny f no) に従って計算する
+pub(crate) fn alu(instr: u16, x: u16, y: u16) -> u16 {
+    let bit = |n: u16| instr & (1 << n) != 0;
+    let x = if bit(11) { 0 } else { x };
+    let x = if bit(10) { !x } else { x };
+    let y = if bit(9) { 0 } else { y };
+    let y = if bit(8) { !y } else { y };
+    let out = if bit(7) { x.wrapping_add(y) } else { x & y };
+    if bit(6) {
+        !out
+    } else {
+        out
+    }
+}
+
+/// j1 (負) j2 (0) j3 (正) のいずれかが成り立てば跳ぶ
+pub(crate) fn jumps(instr: u16, out: u16) -> bool {
+    let out = out as i16;
+    (instr & 0b100 != 0 && out < 0)
+        || (instr & 0b010 != 0 && out == 0)
+        || (instr & 0b001 != 0 && out > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::assemble;
+
+    #[test]
+    fn test_run_max() {
+        let path = Path::new("../05-computer-achitecture/Max.hack");
+        let mut computer = Computer::from_file(path).unwrap();
+        computer.ram_mut()[0] = 3;
+        computer.ram_mut()[1] = 5;
+        assert_eq!(computer.run(1000), Stop::Halted);
+        assert_eq!(computer.ram()[0], 3);
+        assert_eq!(computer.ram()[2], 5);
+        assert_eq!(computer.pc(), 14);
+        assert_eq!(computer.cycles(), 12);
+
+        computer.reset();
+        computer.ram_mut()[0] = 23456;
+        computer.ram_mut()[1] = 12345;
+        assert_eq!(computer.run(1000), Stop::Halted);
+        assert_eq!(computer.ram()[2], 23456);
+        assert_eq!(computer.cycles(), 22);
+    }
+
+    #[test]
+    fn test_alu_and_memory_map() {
+        let program = assemble(
+            r###"
+    @7
+    D=-A        // -7
+    @R0
+    M=D
+    M=M+1       // -6
+    D=!D        // 6
+    @R1
+    M=D|A
+    AM=M-1      // A = RAM[1] = 6
+    D=D&A
+    @R2
+    M=D
+    @SCREEN
+    M=-1
+    @KBD
+    D=M
+    @R3
+    M=D
+    @KBD
+    M=1         // 無視される
+(END)
+    @END
+    0;JMP
+"###,
+            None,
+        )
+        .unwrap();
+        let mut computer = Computer::from_program(&program).unwrap();
+        computer.set_keyboard(75);
+        assert_eq!(computer.run(100), Stop::Halted);
+        assert_eq!(computer.ram()[0] as i16, -6);
+        assert_eq!(computer.ram()[1], 6);
+        assert_eq!(computer.ram()[2], 6);
+        assert_eq!(computer.ram()[3], 75);
+        assert_eq!(computer.screen()[0], 0xffff);
+        assert_eq!(computer.keyboard(), 75);
+        assert_eq!(computer.pc(), program.len() as u16 - 2);
+    }
+
+    #[test]
+    fn test_run_cycle_limit() {
+        // キーが押されるまで待つ
+        let program = assemble("(LOOP)\n@KBD\nD=M\n@LOOP\nD;JEQ\n@LOOP\n0;JMP\n", None).unwrap();
+        let mut computer = Computer::from_program(&program).unwrap();
+        assert_eq!(computer.run(1000), Stop::CycleLimit);
+        assert_eq!(computer.cycles(), 1000);
+
+        // `A`がすでに自分を指していれば、`0;JMP`だけでも止まる
+        let computer = Computer::from_program(&[0xea87]).unwrap();
+        assert!(computer.is_halted());
+
+        assert!(matches!(
+            Computer::from_program(&vec![0; ROM_SIZE + 1]),
+            Err(LoadError::TooLarge(32769))
+        ));
+    }
+
+    #[test]
+    fn test_optimize_keeps_behavior() {
+        use hack_assembler::{AssembleOptions, Assembler};
+        use std::fs;
+
+        // RectLは数値のジャンプ先を使うので、-Oでも命令を削ってはいけない
+        for name in ["rect/RectL.asm", "rect/Rect.asm"].iter() {
+            let path = Path::new("../06-assembler").join(name);
+            let src = fs::read_to_string(&path).unwrap();
+            let run = |optimize: bool| {
+                let opts = AssembleOptions {
+                    optimize,
+                    ..AssembleOptions::default()
+                };
+                let out = Assembler::run_with_options(&src, Some(&path), &opts).unwrap();
+                let mut computer = Computer::from_program(&out.words).unwrap();
+                computer.ram_mut()[0] = 4;
+                assert_eq!(computer.run(10_000), Stop::Halted, "{}", name);
+                computer.memory
+            };
+            let expected = run(false);
+            for row in 0..4 {
+                assert_eq!(expected[SCREEN as usize + row * 32], 0xffff, "{}", name);
+            }
+            assert!(expected == run(true), "{} behaves differently with -O", name);
+        }
+    }
+}
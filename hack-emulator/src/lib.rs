@@ -0,0 +1,8 @@
+mod compare;
+pub use compare::*;
+mod computer;
+pub use computer::*;
+mod rom;
+pub use rom::*;
+mod script;
+pub use script::*;
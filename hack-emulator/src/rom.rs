@@ -0,0 +1,77 @@
+use hack_assembler::{AssembleErrors, AssembleOptions, Assembler, DisasmError, Disassembler};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// ROMに読み込む機械語は32Kワードまで
+pub use hack_assembler::ROM_SIZE;
+
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error("cannot read {path:?}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("{0}")]
+    Hack(#[from] DisasmError),
+    #[error("{0}")]
+    Asm(#[from] AssembleErrors),
+    #[error("{0:?} is neither a .hack nor an .asm file")]
+    UnknownExtension(PathBuf),
+    #[error(
+        "the program has {0} words, which exceeds the ROM ({} words)",
+        ROM_SIZE
+    )]
+    TooLarge(usize),
+}
+
+/// .hack形式のテキストを機械語列として読む
+pub fn parse_hack(text: &str) -> Result<Vec<u16>, LoadError> {
+    Ok(Disassembler::read_words(text)?)
+}
+
+/// hack-assemblerでアセンブルする (`path`は`.include`の基点とエラーメッセージに使う)
+pub fn assemble(text: &str, path: Option<&Path>) -> Result<Vec<u16>, LoadError> {
+    let out = Assembler::run_with_options(text, path, &AssembleOptions::default())?;
+    Ok(out.words)
+}
+
+/// 拡張子 (.hack / .asm) で読み方を決める
+pub fn load_program(path: &Path) -> Result<Vec<u16>, LoadError> {
+    let read = || {
+        fs::read_to_string(path).map_err(|source| LoadError::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    };
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("hack") => parse_hack(&read()?),
+        Some("asm") => assemble(&read()?, Some(path)),
+        _ => Err(LoadError::UnknownExtension(path.to_path_buf())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_program() {
+        let hack = load_program(Path::new("../05-computer-achitecture/Max.hack")).unwrap();
+        let asm = load_program(Path::new("../06-assembler/max/Max.asm")).unwrap();
+        assert_eq!(hack.len(), 16);
+        assert_eq!(hack, asm);
+
+        assert!(matches!(
+            parse_hack("0000000000000000\n0101\n"),
+            Err(LoadError::Hack(DisasmError::InvalidFormat {
+                address: 1,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            load_program(Path::new("Max.txt")),
+            Err(LoadError::UnknownExtension(_))
+        ));
+    }
+}
@@ -0,0 +1,1003 @@
+use crate::compare::{compare, table_row, Column, Mismatch, Value};
+use crate::computer::{alu, jumps, Computer, KBD, RAM_SIZE};
+use crate::rom::{load_program, LoadError};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ScriptErrorKind {
+    #[error("cannot read {path:?}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("{0}")]
+    Load(#[from] LoadError),
+    #[error("unterminated string")]
+    UnterminatedString,
+    #[error("unterminated comment")]
+    UnterminatedComment,
+    #[error("unexpected `{0}`")]
+    Unexpected(String),
+    #[error("unexpected end of script")]
+    UnexpectedEof,
+    #[error("unknown command `{0}`")]
+    UnknownCommand(String),
+    #[error("invalid value `{0}`")]
+    InvalidValue(String),
+    #[error("invalid output format `{0}` (expected NAME%FMT.LEFT.WIDTH.RIGHT)")]
+    InvalidFormat(String),
+    #[error("unknown variable `{0}` for {1}")]
+    UnknownVariable(String, &'static str),
+    #[error("`{0}` is not supported by {1}")]
+    Unsupported(String, &'static str),
+    #[error("unsupported chip `{0}` (only CPU.hdl and Computer.hdl are built in)")]
+    UnsupportedChip(String),
+}
+
+/// スクリプトの行番号つきのエラー
+#[derive(Error, Debug)]
+#[error("line {line}: {kind}")]
+pub struct ScriptError {
+    pub line: usize,
+    pub kind: ScriptErrorKind,
+}
+
+impl ScriptError {
+    fn new(line: usize, kind: impl Into<ScriptErrorKind>) -> Self {
+        Self {
+            line,
+            kind: kind.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Tok {
+    Word(String),
+    Str(String),
+    LBrace,
+    RBrace,
+    /// `,` (コマンドの区切り)
+    Comma,
+    /// `;` (シミュレーションの1ステップの終わり)
+    Semi,
+    Op(CmpOp),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl CmpOp {
+    fn test(self, x: i16, y: i16) -> bool {
+        match self {
+            CmpOp::Eq => x == y,
+            CmpOp::Ne => x != y,
+            CmpOp::Lt => x < y,
+            CmpOp::Gt => x > y,
+            CmpOp::Le => x <= y,
+            CmpOp::Ge => x >= y,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    tok: Tok,
+    line: usize,
+}
+
+impl Tok {
+    fn text(&self) -> String {
+        match self {
+            Tok::Word(w) => w.clone(),
+            Tok::Str(s) => format!("\"{}\"", s),
+            Tok::LBrace => "{".to_string(),
+            Tok::RBrace => "}".to_string(),
+            Tok::Comma => ",".to_string(),
+            Tok::Semi => ";".to_string(),
+            Tok::Op(op) => match op {
+                CmpOp::Eq => "=",
+                CmpOp::Ne => "<>",
+                CmpOp::Lt => "<",
+                CmpOp::Gt => ">",
+                CmpOp::Le => "<=",
+                CmpOp::Ge => ">=",
+            }
+            .to_string(),
+        }
+    }
+}
+
+fn lex(src: &str) -> Result<Vec<Token>, ScriptError> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut pos = 0;
+    let starts_comment =
+        |pos: usize| chars[pos] == '/' && matches!(chars.get(pos + 1), Some('/') | Some('*'));
+    while pos < chars.len() {
+        let c = chars[pos];
+        let tok = match c {
+            '\n' => {
+                line += 1;
+                pos += 1;
+                continue;
+            }
+            c if c.is_whitespace() => {
+                pos += 1;
+                continue;
+            }
+            '/' if chars.get(pos + 1) == Some(&'/') => {
+                while pos < chars.len() && chars[pos] != '\n' {
+                    pos += 1;
+                }
+                continue;
+            }
+            '/' if chars.get(pos + 1) == Some(&'*') => {
+                let start = line;
+                pos += 2;
+                loop {
+                    match chars.get(pos) {
+                        None => {
+                            return Err(ScriptError::new(
+                                start,
+                                ScriptErrorKind::UnterminatedComment,
+                            ))
+                        }
+                        Some('*') if chars.get(pos + 1) == Some(&'/') => break,
+                        Some('\n') => line += 1,
+                        _ => {}
+                    }
+                    pos += 1;
+                }
+                pos += 2;
+                continue;
+            }
+            '"' => {
+                let end = chars[pos + 1..]
+                    .iter()
+                    .position(|&c| c == '"' || c == '\n')
+                    .map(|n| pos + 1 + n)
+                    .filter(|&end| chars[end] == '"')
+                    .ok_or_else(|| ScriptError::new(line, ScriptErrorKind::UnterminatedString))?;
+                let s = chars[pos + 1..end].iter().collect();
+                pos = end + 1;
+                Tok::Str(s)
+            }
+            '{' | '}' | ',' | ';' => {
+                pos += 1;
+                match c {
+                    '{' => Tok::LBrace,
+                    '}' => Tok::RBrace,
+                    ',' => Tok::Comma,
+                    _ => Tok::Semi,
+                }
+            }
+            '<' | '>' | '=' => {
+                let next = chars.get(pos + 1).copied();
+                let (op, len) = match (c, next) {
+                    ('<', Some('>')) => (CmpOp::Ne, 2),
+                    ('<', Some('=')) => (CmpOp::Le, 2),
+                    ('>', Some('=')) => (CmpOp::Ge, 2),
+                    ('<', _) => (CmpOp::Lt, 1),
+                    ('>', _) => (CmpOp::Gt, 1),
+                    _ => (CmpOp::Eq, 1),
+                };
+                pos += len;
+                Tok::Op(op)
+            }
+            _ => {
+                let start = pos;
+                while pos < chars.len()
+                    && !chars[pos].is_whitespace()
+                    && !"{},;\"<>=".contains(chars[pos])
+                    && !starts_comment(pos)
+                {
+                    pos += 1;
+                }
+                Tok::Word(chars[start..pos].iter().collect())
+            }
+        };
+        tokens.push(Token { tok, line });
+    }
+    Ok(tokens)
+}
+
+/// 変数 (`RAM[16384]`なら名前`RAM`と添字16384)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Var {
+    pub name: String,
+    pub index: Option<u16>,
+}
+
+impl Var {
+    fn parse(s: &str) -> Option<Self> {
+        match s.split_once('[') {
+            Some((name, rest)) => {
+                let index = rest.strip_suffix(']')?.parse().ok()?;
+                Some(Self {
+                    name: name.to_string(),
+                    index: Some(index),
+                })
+            }
+            None => Some(Self {
+                name: s.to_string(),
+                index: None,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `load Prog.hack` (CPUエミュレータ) または`load Computer.hdl` (組み込みのチップ)
+    Load(String),
+    /// `ROM32K load Prog.hack`
+    RomLoad(String),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<Column>),
+    Set(Var, u16),
+    /// 回数がなければ、止まるまで (上限は`Runner::repeat_limit`)
+    Repeat(Option<u64>, Vec<Statement>),
+    While(Var, CmpOp, u16, Vec<Statement>),
+    Tick,
+    Tock,
+    TickTock,
+    Output,
+    Echo(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub command: Command,
+    pub line: usize,
+}
+
+/// .tstファイルを読んだもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Script {
+    pub statements: Vec<Statement>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or_else(|| self.tokens.last())
+            .map_or(1, |t| t.line)
+    }
+
+    fn error(&self, kind: ScriptErrorKind) -> ScriptError {
+        ScriptError::new(self.line(), kind)
+    }
+
+    fn unexpected(&self) -> ScriptError {
+        match self.tokens.get(self.pos) {
+            Some(t) => self.error(ScriptErrorKind::Unexpected(t.tok.text())),
+            None => self.error(ScriptErrorKind::UnexpectedEof),
+        }
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos).map(|t| &t.tok)
+    }
+
+    fn word(&mut self) -> Result<String, ScriptError> {
+        match self.peek() {
+            Some(Tok::Word(w)) => {
+                let w = w.clone();
+                self.pos += 1;
+                Ok(w)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn expect(&mut self, tok: Tok) -> Result<(), ScriptError> {
+        if self.peek() == Some(&tok) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn var(&mut self) -> Result<Var, ScriptError> {
+        let w = self.word()?;
+        Var::parse(&w).ok_or_else(|| {
+            ScriptError::new(
+                self.line(),
+                ScriptErrorKind::UnknownVariable(w, "this script"),
+            )
+        })
+    }
+
+    fn value(&mut self) -> Result<u16, ScriptError> {
+        let w = self.word()?;
+        parse_value(&w)
+            .ok_or_else(|| ScriptError::new(self.line(), ScriptErrorKind::InvalidValue(w)))
+    }
+
+    /// `}`か終わりまで
+    fn statements(&mut self) -> Result<Vec<Statement>, ScriptError> {
+        let mut statements = vec![];
+        while !matches!(self.peek(), None | Some(Tok::RBrace)) {
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, ScriptError> {
+        self.expect(Tok::LBrace)?;
+        let body = self.statements()?;
+        self.expect(Tok::RBrace)?;
+        Ok(body)
+    }
+
+    fn statement(&mut self) -> Result<Statement, ScriptError> {
+        let line = self.line();
+        let command = match self.peek() {
+            Some(Tok::Word(w)) => w.clone(),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        let command = match command.as_str() {
+            "repeat" => {
+                let count = match self.peek() {
+                    Some(Tok::Word(_)) => {
+                        let w = self.word()?;
+                        let n = w.parse().map_err(|_| {
+                            ScriptError::new(line, ScriptErrorKind::InvalidValue(w))
+                        })?;
+                        Some(n)
+                    }
+                    _ => None,
+                };
+                let body = self.block()?;
+                return Ok(Statement {
+                    command: Command::Repeat(count, body),
+                    line,
+                });
+            }
+            "while" => {
+                let var = self.var()?;
+                let op = match self.peek() {
+                    Some(Tok::Op(op)) => *op,
+                    _ => return Err(self.unexpected()),
+                };
+                self.pos += 1;
+                let value = self.value()?;
+                let body = self.block()?;
+                return Ok(Statement {
+                    command: Command::While(var, op, value, body),
+                    line,
+                });
+            }
+            "load" => Command::Load(self.word()?),
+            "ROM32K" => {
+                if self.word()? != "load" {
+                    self.pos -= 1;
+                    return Err(self.unexpected());
+                }
+                Command::RomLoad(self.word()?)
+            }
+            "output-file" => Command::OutputFile(self.word()?),
+            "compare-to" => Command::CompareTo(self.word()?),
+            "output-list" => {
+                let mut columns = vec![];
+                while let Some(Tok::Word(w)) = self.peek() {
+                    let column = Column::parse(w)
+                        .ok_or_else(|| self.error(ScriptErrorKind::InvalidFormat(w.clone())))?;
+                    columns.push(column);
+                    self.pos += 1;
+                }
+                Command::OutputList(columns)
+            }
+            "set" => {
+                let var = self.var()?;
+                Command::Set(var, self.value()?)
+            }
+            "tick" => Command::Tick,
+            "tock" => Command::Tock,
+            "ticktock" => Command::TickTock,
+            "output" => Command::Output,
+            "echo" => match self.peek() {
+                Some(Tok::Str(s)) => {
+                    let s = s.clone();
+                    self.pos += 1;
+                    Command::Echo(s)
+                }
+                _ => return Err(self.unexpected()),
+            },
+            _ => {
+                self.pos -= 1;
+                return Err(self.error(ScriptErrorKind::UnknownCommand(command)));
+            }
+        };
+        // 区切りは`}`と終わりの前なら省略できる
+        match self.peek() {
+            Some(Tok::Comma) | Some(Tok::Semi) => self.pos += 1,
+            None | Some(Tok::RBrace) => {}
+            _ => return Err(self.unexpected()),
+        }
+        Ok(Statement { command, line })
+    }
+}
+
+/// `%B0101`、`%X7fff`、`%D-1`、`-1`のような値 (16bitに収まらなければNone)
+fn parse_value(s: &str) -> Option<u16> {
+    let (radix, digits) = match s.strip_prefix('%') {
+        Some(rest) => {
+            let radix = match rest.chars().next()? {
+                'B' => 2,
+                'X' => 16,
+                'D' => 10,
+                _ => return None,
+            };
+            (radix, &rest[1..])
+        }
+        None => (10, s),
+    };
+    let n = i32::from_str_radix(digits, radix).ok()?;
+    if (i16::MIN as i32..=u16::MAX as i32).contains(&n) {
+        Some(n as u16)
+    } else {
+        None
+    }
+}
+
+impl Script {
+    pub fn parse(src: &str) -> Result<Self, ScriptError> {
+        let mut parser = Parser {
+            tokens: lex(src)?,
+            pos: 0,
+        };
+        let statements = parser.statements()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(parser.unexpected());
+        }
+        Ok(Self { statements })
+    }
+}
+
+/// CPU.hdlと同じ入出力を持つCPU
+#[derive(Debug, Default)]
+struct CpuChip {
+    a: u16,
+    d: u16,
+    pc: u16,
+    in_m: u16,
+    instruction: u16,
+    reset: bool,
+    /// `tick`で計算し、`tock`で反映するレジスタ (A, D, PC)
+    next: Option<(u16, u16, u16)>,
+}
+
+impl CpuChip {
+    fn out_m(&self) -> u16 {
+        let y = if self.instruction & 0x1000 != 0 {
+            self.in_m
+        } else {
+            self.a
+        };
+        alu(self.instruction, self.d, y)
+    }
+
+    fn write_m(&self) -> bool {
+        self.instruction & 0x8000 != 0 && self.instruction & 0x08 != 0
+    }
+
+    fn next(&self) -> (u16, u16, u16) {
+        let instr = self.instruction;
+        let (mut a, mut d, mut pc) = (self.a, self.d, self.pc.wrapping_add(1) & 0x7fff);
+        if instr & 0x8000 == 0 {
+            a = instr;
+        } else {
+            let out = self.out_m();
+            if instr & 0x20 != 0 {
+                a = out;
+            }
+            if instr & 0x10 != 0 {
+                d = out;
+            }
+            if jumps(instr, out) {
+                pc = self.a & 0x7fff;
+            }
+        }
+        if self.reset {
+            pc = 0;
+        }
+        (a, d, pc)
+    }
+}
+
+/// `load`した対象
+enum Target {
+    None,
+    /// CPUエミュレータ (`load Prog.hack`)
+    Program(Computer),
+    /// Computer.hdl
+    Computer {
+        computer: Computer,
+        reset: bool,
+    },
+    /// CPU.hdl
+    Cpu(CpuChip),
+}
+
+impl Target {
+    fn name(&self) -> &'static str {
+        match self {
+            Target::None => "no target (use `load` first)",
+            Target::Program(_) => "the CPU emulator",
+            Target::Computer { .. } => "Computer.hdl",
+            Target::Cpu(_) => "CPU.hdl",
+        }
+    }
+}
+
+/// スクリプトを実行した結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// `output-file`で指定した.outのパスと、書き出す内容
+    pub output_file: Option<PathBuf>,
+    pub output: String,
+    /// `compare-to`で指定した.cmpのパス
+    pub compare_file: Option<PathBuf>,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// .tstスクリプトを実行する
+pub struct Runner {
+    /// ファイル名はスクリプトのディレクトリからの相対パス
+    dir: PathBuf,
+    repeat_limit: u64,
+    target: Target,
+    time: u64,
+    tick: bool,
+    columns: Vec<Column>,
+    output_file: Option<PathBuf>,
+    output: String,
+    compare_file: Option<PathBuf>,
+    expected: Option<String>,
+}
+
+impl Runner {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            repeat_limit: 1_000_000,
+            target: Target::None,
+            time: 0,
+            tick: false,
+            columns: vec![],
+            output_file: None,
+            output: String::new(),
+            compare_file: None,
+            expected: None,
+        }
+    }
+
+    /// 回数のない`repeat`で、止まらないプログラムを実行するサイクル数の上限
+    pub fn repeat_limit(mut self, cycles: u64) -> Self {
+        self.repeat_limit = cycles;
+        self
+    }
+
+    /// `load`したプログラムまたはチップのコンピュータ
+    pub fn computer(&self) -> Option<&Computer> {
+        match &self.target {
+            Target::Program(computer) | Target::Computer { computer, .. } => Some(computer),
+            _ => None,
+        }
+    }
+
+    pub fn run(mut self, script: &Script) -> Result<Report, ScriptError> {
+        self.run_mut(script)
+    }
+
+    /// 実行後も`computer`で状態を調べられる
+    pub fn run_mut(&mut self, script: &Script) -> Result<Report, ScriptError> {
+        self.exec_all(&script.statements)?;
+        let mismatches = match &self.expected {
+            Some(expected) => compare(expected, &self.output),
+            None => vec![],
+        };
+        Ok(Report {
+            output_file: self.output_file.clone(),
+            output: self.output.clone(),
+            compare_file: self.compare_file.clone(),
+            mismatches,
+        })
+    }
+
+    fn exec_all(&mut self, statements: &[Statement]) -> Result<(), ScriptError> {
+        statements.iter().try_for_each(|s| self.exec(s))
+    }
+
+    fn exec(&mut self, statement: &Statement) -> Result<(), ScriptError> {
+        let line = statement.line;
+        let unsupported = |command: &str, target: &Target| {
+            ScriptError::new(
+                line,
+                ScriptErrorKind::Unsupported(command.to_string(), target.name()),
+            )
+        };
+        match &statement.command {
+            Command::Load(name) => {
+                self.target = self
+                    .load(name)
+                    .map_err(|kind| ScriptError::new(line, kind))?;
+                self.time = 0;
+                self.tick = false;
+            }
+            Command::RomLoad(name) => match &mut self.target {
+                Target::Computer { computer, .. } => {
+                    let program = load_program(&resolve(&self.dir, name))
+                        .map_err(|e| ScriptError::new(line, e))?;
+                    computer
+                        .load(&program)
+                        .map_err(|e| ScriptError::new(line, e))?;
+                }
+                target => return Err(unsupported("ROM32K load", target)),
+            },
+            Command::OutputFile(name) => self.output_file = Some(self.dir.join(name)),
+            Command::CompareTo(name) => {
+                let path = self.dir.join(name);
+                let expected = fs::read_to_string(&path).map_err(|source| {
+                    ScriptError::new(
+                        line,
+                        ScriptErrorKind::Io {
+                            path: path.clone(),
+                            source,
+                        },
+                    )
+                })?;
+                self.expected = Some(expected);
+                self.compare_file = Some(path);
+            }
+            Command::OutputList(columns) => {
+                self.columns = columns.clone();
+                let header = table_row(self.columns.iter().map(|c| c.header()));
+                self.output.push_str(&header);
+                self.output.push('\n');
+            }
+            Command::Set(var, value) => self.set(var, *value, line)?,
+            Command::Repeat(Some(count), body) => {
+                for _ in 0..*count {
+                    self.exec_all(body)?;
+                }
+            }
+            Command::Repeat(None, body) => {
+                for _ in 0..self.repeat_limit {
+                    if matches!(&self.target, Target::Program(c) if c.is_halted()) {
+                        break;
+                    }
+                    self.exec_all(body)?;
+                }
+            }
+            Command::While(var, op, value, body) => {
+                while op.test(self.get(var, line)?.word() as i16, *value as i16) {
+                    self.exec_all(body)?;
+                }
+            }
+            Command::Tick => match &mut self.target {
+                Target::Computer { .. } => self.tick = true,
+                Target::Cpu(cpu) => {
+                    cpu.next = Some(cpu.next());
+                    self.tick = true;
+                }
+                target => return Err(unsupported("tick", target)),
+            },
+            Command::Tock => match &mut self.target {
+                Target::Computer { computer, reset } => {
+                    computer.step();
+                    // 命令は実行され、PCだけが0になる
+                    if *reset {
+                        computer.reset();
+                    }
+                    self.clock();
+                }
+                Target::Cpu(cpu) => {
+                    let (a, d, pc) = cpu.next.take().unwrap_or_else(|| cpu.next());
+                    cpu.a = a;
+                    cpu.d = d;
+                    cpu.pc = pc;
+                    self.clock();
+                }
+                target => return Err(unsupported("tock", target)),
+            },
+            Command::TickTock => match &mut self.target {
+                Target::Program(computer) => {
+                    computer.step();
+                    self.clock();
+                }
+                Target::None => return Err(unsupported("ticktock", &self.target)),
+                _ => {
+                    for command in [Command::Tick, Command::Tock].iter() {
+                        let command = command.clone();
+                        self.exec(&Statement { command, line })?;
+                    }
+                }
+            },
+            Command::Output => {
+                let mut cells = vec![];
+                for column in self.columns.iter() {
+                    let var = Var::parse(&column.name).ok_or_else(|| {
+                        ScriptError::new(
+                            line,
+                            ScriptErrorKind::UnknownVariable(
+                                column.name.clone(),
+                                self.target.name(),
+                            ),
+                        )
+                    })?;
+                    cells.push(column.cell(self.get(&var, line)?));
+                }
+                self.output.push_str(&table_row(cells.into_iter()));
+                self.output.push('\n');
+            }
+            Command::Echo(_) => {}
+        }
+        Ok(())
+    }
+
+    fn clock(&mut self) {
+        self.time += 1;
+        self.tick = false;
+    }
+
+    fn load(&self, name: &str) -> Result<Target, ScriptErrorKind> {
+        let path = Path::new(name);
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("hdl") => match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some("Computer") => Ok(Target::Computer {
+                    computer: Computer::new(),
+                    reset: false,
+                }),
+                Some("CPU") => Ok(Target::Cpu(CpuChip::default())),
+                _ => Err(ScriptErrorKind::UnsupportedChip(name.to_string())),
+            },
+            _ => Ok(Target::Program(Computer::from_file(&resolve(
+                &self.dir, name,
+            ))?)),
+        }
+    }
+
+    fn get(&self, var: &Var, line: usize) -> Result<Value, ScriptError> {
+        let unknown = || {
+            ScriptError::new(
+                line,
+                ScriptErrorKind::UnknownVariable(var_name(var), self.target.name()),
+            )
+        };
+        if var.name == "time" && var.index.is_none() {
+            return Ok(Value::Time {
+                time: self.time,
+                tick: self.tick,
+            });
+        }
+        let word = match (&self.target, var.name.as_str(), var.index) {
+            (Target::Program(c), "RAM", Some(i)) if i <= KBD => c.read(i),
+            (Target::Program(c), "A", None) => c.a(),
+            (Target::Program(c), "D", None) => c.d(),
+            (Target::Program(c), "PC", None) => c.pc(),
+            (Target::Computer { computer, .. }, "RAM16K", Some(i)) if (i as usize) < RAM_SIZE => {
+                computer.read(i)
+            }
+            (Target::Computer { reset, .. }, "reset", None) => *reset as u16,
+            (Target::Computer { computer, .. }, "PC", None) => computer.pc(),
+            (Target::Computer { computer, .. }, "ARegister", None) => computer.a(),
+            (Target::Computer { computer, .. }, "DRegister", None) => computer.d(),
+            (Target::Cpu(cpu), "inM", None) => cpu.in_m,
+            (Target::Cpu(cpu), "instruction", None) => cpu.instruction,
+            (Target::Cpu(cpu), "reset", None) => cpu.reset as u16,
+            (Target::Cpu(cpu), "outM", None) => cpu.out_m(),
+            (Target::Cpu(cpu), "writeM", None) => cpu.write_m() as u16,
+            (Target::Cpu(cpu), "addressM", None) => cpu.a & 0x7fff,
+            (Target::Cpu(cpu), "pc", None) => cpu.pc,
+            (Target::Cpu(cpu), "ARegister", None) => cpu.a,
+            (Target::Cpu(cpu), "DRegister", None) => cpu.d,
+            _ => return Err(unknown()),
+        };
+        Ok(Value::Word(word))
+    }
+
+    fn set(&mut self, var: &Var, value: u16, line: usize) -> Result<(), ScriptError> {
+        let name = self.target.name();
+        match (&mut self.target, var.name.as_str(), var.index) {
+            // キーボードへはスクリプトからなら書き込める
+            (Target::Program(c), "RAM", Some(KBD)) => c.set_keyboard(value),
+            (Target::Program(c), "RAM", Some(i)) if i < KBD => c.write(i, value),
+            (Target::Program(c), "A", None) => c.set_a(value),
+            (Target::Program(c), "D", None) => c.set_d(value),
+            (Target::Program(c), "PC", None) => c.set_pc(value),
+            (Target::Computer { computer, .. }, "RAM16K", Some(i)) if (i as usize) < RAM_SIZE => {
+                computer.write(i, value)
+            }
+            (Target::Computer { reset, .. }, "reset", None) => *reset = value != 0,
+            (Target::Cpu(cpu), "inM", None) => cpu.in_m = value,
+            (Target::Cpu(cpu), "instruction", None) => cpu.instruction = value,
+            (Target::Cpu(cpu), "reset", None) => cpu.reset = value != 0,
+            _ => {
+                return Err(ScriptError::new(
+                    line,
+                    ScriptErrorKind::UnknownVariable(var_name(var), name),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Value {
+    fn word(self) -> u16 {
+        match self {
+            Value::Word(w) => w,
+            Value::Time { time, .. } => time as u16,
+        }
+    }
+}
+
+fn var_name(var: &Var) -> String {
+    match var.index {
+        Some(i) => format!("{}[{}]", var.name, i),
+        None => var.name.clone(),
+    }
+}
+
+/// Windowsで書かれたスクリプトのため、見つからなければ大文字小文字を区別せずに探し、
+/// `.hack`がなければ同名の`.asm`を使う
+fn resolve(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if path.exists() {
+        return path;
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) => (stem, ext),
+        None => return path,
+    };
+    let entries = match fs::read_dir(path.parent().unwrap_or(dir)) {
+        Ok(entries) => entries,
+        Err(_) => return path,
+    };
+    let mut candidates: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            let file_stem = p.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+            let file_ext = p.extension().and_then(|s| s.to_str()).unwrap_or("");
+            file_stem.eq_ignore_ascii_case(stem)
+                && (file_ext.eq_ignore_ascii_case(ext)
+                    || (ext.eq_ignore_ascii_case("hack") && file_ext == "asm"))
+        })
+        .collect();
+    // .hackを.asmより優先する
+    candidates.sort_by_key(|p| p.extension().and_then(|s| s.to_str()) == Some("asm"));
+    candidates.into_iter().next().unwrap_or(path)
+}
+
+/// .tstファイルを読んで実行する
+pub fn run_script(path: &Path) -> Result<Report, ScriptError> {
+    let src = fs::read_to_string(path).map_err(|source| {
+        ScriptError::new(
+            0,
+            ScriptErrorKind::Io {
+                path: path.to_path_buf(),
+                source,
+            },
+        )
+    })?;
+    let script = Script::parse(&src)?;
+    Runner::new(path.parent().unwrap_or_else(|| Path::new("."))).run(&script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_passes(path: &str) -> Report {
+        let report = run_script(Path::new(path)).unwrap();
+        let mismatches: Vec<_> = report.mismatches.iter().map(|m| m.to_string()).collect();
+        assert!(report.passed(), "{}:\n{}", path, mismatches.join("\n"));
+        report
+    }
+
+    #[test]
+    fn test_parse() {
+        let script = Script::parse(
+            "load Max.hack, /* comment\n */ output-list RAM[0]%D2.6.2 time%S1.4.1;\n\
+             while RAM[0] <> 0 { set RAM[0] %B11, ticktock; }\nrepeat { ticktock }\necho \"done\";",
+        )
+        .unwrap();
+        let lines: Vec<usize> = script.statements.iter().map(|s| s.line).collect();
+        assert_eq!(lines, vec![1, 2, 3, 4, 5]);
+        match &script.statements[2].command {
+            Command::While(var, CmpOp::Ne, 0, body) => {
+                assert_eq!(var_name(var), "RAM[0]");
+                assert_eq!(body[0].command, Command::Set(var.clone(), 3));
+            }
+            command => panic!("{:?}", command),
+        }
+
+        assert_eq!(parse_value("-1"), Some(0xffff));
+        assert_eq!(parse_value("%X7fff"), Some(0x7fff));
+        assert_eq!(parse_value("65536"), None);
+
+        let err = Script::parse("load Max.hack,\nrun;").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: unknown command `run`");
+        let err = Script::parse("repeat 3 {\n  ticktock;\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: unexpected end of script");
+        let err = Script::parse("set RAM[0] 1 output;").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: unexpected `output`");
+    }
+
+    #[test]
+    fn test_machine_language() {
+        let report = assert_passes("../04-machine-language/mult/Mult.tst");
+        assert_eq!(report.output.lines().count(), 7);
+        assert!(report.output_file.unwrap().ends_with("Mult.out"));
+        assert_passes("../04-machine-language/fill/FillAutomatic.tst");
+
+        // 対話用のスクリプトは、回数のないrepeatを上限まで回して終わる
+        let path = Path::new("../04-machine-language/fill/Fill.tst");
+        let script = Script::parse(&fs::read_to_string(path).unwrap()).unwrap();
+        let mut runner = Runner::new(path.parent().unwrap()).repeat_limit(10_000);
+        assert!(runner.run_mut(&script).unwrap().passed());
+        assert_eq!(runner.computer().unwrap().cycles(), 10_000);
+    }
+
+    #[test]
+    fn test_computer_architecture() {
+        assert_passes("../05-computer-achitecture/CPU-external.tst");
+        assert_passes("../05-computer-achitecture/ComputerAdd-external.tst");
+        assert_passes("../05-computer-achitecture/ComputerMax-external.tst");
+        assert_passes("../05-computer-achitecture/ComputerRect-external.tst");
+    }
+
+    #[test]
+    fn test_mismatch_report() {
+        let dir = Path::new("../05-computer-achitecture");
+        let src = fs::read_to_string(dir.join("ComputerMax-external.tst")).unwrap();
+        // 2回目の実行を1サイクル早く打ち切ると、最後の行が足りない
+        let script = Script::parse(&src.replace("repeat 10", "repeat 9")).unwrap();
+        let mut runner = Runner::new(dir);
+        let report = runner.run_mut(&script).unwrap();
+        assert_eq!(
+            report.mismatches,
+            vec![Mismatch {
+                line: 28,
+                expected: Some("| 25   |  0  |   23456 |   12345 |   23456 |".to_string()),
+                actual: None,
+            }]
+        );
+        assert_eq!(runner.computer().unwrap().ram()[2], 5);
+
+        let err = Runner::new(dir)
+            .run(&Script::parse("load Computer.hdl,\nset RAM[0] 1;").unwrap())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: unknown variable `RAM[0]` for Computer.hdl"
+        );
+    }
+}